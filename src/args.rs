@@ -1,3 +1,19 @@
+use crate::{
+    countdown,
+    device_cache::Filter,
+    groups::{Member, SceneMember},
+    report::Version,
+    scheduler::{Action, Trigger},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -23,70 +39,784 @@ pub enum Command {
 
     /// fetch device infos
     Info {
-        #[structopt(long, short = "id")]
-        device_id: String,
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
     },
 
     /// set wifi credentials in the sonoff device
     Wifi {
-        #[structopt(long, short = "id")]
-        device_id: String,
+        #[structopt(flatten)]
+        targets: Targets,
 
         #[structopt(long)]
         essid: String,
 
+        // the password source
+        #[structopt(flatten)]
+        pwd: PasswordSource,
+
+        #[structopt(long)]
+        /// wait until the device shows up in the new network
+        wait: bool,
+
+        #[structopt(long, default_value = "120")]
+        /// max. seconds to wait for the device in the new network
+        wait_timeout: u64,
+
         #[structopt(long)]
-        pwd: String,
+        /// discard the progress from an unfinished migration of multiple devices
+        restart: bool,
     },
 
     /// switch on / off
     Switch {
-        #[structopt(long, short = "id")]
-        device_id: String,
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
 
         #[structopt(subcommand)]
-        state: SwitchState,
+        cmd: SwitchCommand,
+    },
+
+    /// set the relay state after a power loss
+    Startup {
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+
+        #[structopt(subcommand)]
+        state: StartupState,
+    },
+
+    /// switch the relay off after a given time (inching)
+    Pulse {
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+
+        #[structopt(subcommand)]
+        state: PulseState,
     },
 
     /// unlock the sonoff device to flash it ota
     Unlock {
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+
+    /// tag devices to address them with '--tag'
+    Tag {
         #[structopt(long, short = "id")]
         device_id: String,
+
+        #[structopt(long)]
+        /// remove the given tags
+        remove: bool,
+
+        #[structopt(required = true)]
+        tags: Vec<String>,
+    },
+
+    /// manage device keys and wifi passwords in the encrypted key store
+    Keys {
+        #[structopt(subcommand)]
+        cmd: KeysCommand,
+    },
+
+    /// switch devices on a schedule - cron expressions or solar events per device or outlet
+    Schedule {
+        #[structopt(subcommand)]
+        cmd: ScheduleCommand,
+    },
+
+    /// save the settings of the devices (startup, pulse, switch, ssid, firmware) in a backup file
+    Backup {
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(long, short, parse(from_os_str))]
+        /// file or directory - default: a timestamped file in 'sonoff-diy-backups' in the local data dir
+        output: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+
+    /// re-apply the startup state and the pulse from a backup
+    Restore {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        #[structopt(long, short = "id")]
+        /// only this device from the backup - all devices if not set
+        device_id: Option<String>,
+
+        #[structopt(long, value_name = "DEVICE_ID", requires = "device-id")]
+        /// restore to a replacement device
+        to: Option<String>,
+
+        #[structopt(long)]
+        /// also restore the switch state
+        switch: bool,
+    },
+
+    /// compare a backup with the live settings
+    Diff {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        #[structopt(long, short = "id")]
+        /// only this device from the backup - all devices if not set
+        device_id: Option<String>,
+
+        #[structopt(long, value_name = "DEVICE_ID", requires = "device-id")]
+        /// compare with a replacement device
+        to: Option<String>,
+    },
+
+    /// sample the wifi signal strength of the devices - writes csv rows and prints min / avg / max
+    Survey {
+        #[structopt(flatten)]
+        targets: Targets,
+
+        #[structopt(long, default_value = "10")]
+        /// seconds between the samples
+        interval: u64,
+
+        #[structopt(
+            long,
+            value_name = "DURATION",
+            default_value = "10m",
+            parse(try_from_str = countdown::parse_duration)
+        )]
+        /// e.g. '30m' or '2h'
+        duration: Duration,
+
+        #[structopt(long, short, parse(from_os_str))]
+        /// csv file for the samples
+        output: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// also record the rssi from the mdns announcements
+        txt: bool,
+
+        #[structopt(long, default_value = "_ewelink._tcp")]
+        service_name: String,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+
+    /// show the state changing requests from the audit log
+    Audit {
+        #[structopt(long, short = "id")]
+        device_id: Option<String>,
+
+        #[structopt(long)]
+        /// local time like '2020-03-02' or '2020-03-02 07:15' - or a duration before now like '2h'
+        since: Option<String>,
+
+        #[structopt(long)]
+        /// local time like '2020-03-02 18:00' - or a duration before now
+        until: Option<String>,
+
+        #[structopt(long)]
+        /// print the json lines
+        json: bool,
+    },
+
+    /// reports over all cached devices
+    Report {
+        #[structopt(subcommand)]
+        cmd: ReportCommand,
+    },
+
+    /// named groups of devices or outlets - switched with one command
+    Group {
+        #[structopt(subcommand)]
+        cmd: GroupCommand,
+    },
+
+    /// named scenes with a desired state per device or outlet
+    Scene {
+        #[structopt(subcommand)]
+        cmd: SceneCommand,
+    },
+
+    /// keep a live device registry and serve a local http / json api
+    Daemon {
+        #[structopt(long, default_value = "127.0.0.1:8990")]
+        /// address for the http api - a non-loopback address requires '--token-env'
+        listen: SocketAddr,
+
+        #[structopt(long, value_name = "VAR")]
+        /// read the api token from the environment variable - requests must send 'Authorization: Bearer <token>'
+        token_env: Option<String>,
+
+        #[structopt(long, value_name = "DIR")]
+        /// directory with the binaries for the flash endpoint - flashing is disabled without it
+        flash_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "_ewelink._tcp")]
+        service_name: String,
+
+        #[structopt(long, default_value = "30")]
+        /// seconds between the discovery rounds
+        scan_interval: u64,
+
+        #[structopt(long, default_value = "300")]
+        /// max. seconds for a flash which was triggered per api
+        flash_timeout: u64,
+    },
+
+    /// publish the device states to a mqtt broker and forward commands to the devices
+    Mqtt {
+        #[structopt(long, default_value = "localhost")]
+        host: String,
+
+        #[structopt(long, default_value = "1883")]
+        port: u16,
+
+        #[structopt(long, default_value = "sonoff-diy-bridge")]
+        client_id: String,
+
+        #[structopt(long)]
+        username: Option<String>,
+
+        #[structopt(long, value_name = "VAR", requires = "username")]
+        /// read the password from the environment variable
+        password_env: Option<String>,
+
+        #[structopt(long, default_value = "sonoff-diy")]
+        /// topic prefix
+        prefix: String,
+
+        #[structopt(long, default_value = "_ewelink._tcp")]
+        service_name: String,
+
+        #[structopt(long, default_value = "30")]
+        /// seconds between the discovery / polling rounds
+        interval: u64,
+
+        #[structopt(long, default_value = "180")]
+        /// seconds without an announcement until a device is offline
+        offline_after: u64,
+
+        #[structopt(long)]
+        /// publish home assistant mqtt discovery configs
+        homeassistant: bool,
+
+        #[structopt(long, default_value = "homeassistant")]
+        /// home assistant discovery prefix
+        discovery_prefix: String,
+    },
+
+    /// run automation rules on device state changes, e.g. 'when A turns on, switch B on'
+    Rules {
+        #[structopt(long, parse(from_os_str))]
+        /// rules config file - default: '.sonoff-diy-rules.json' in the local data dir
+        config: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// only log the actions
+        dry_run: bool,
+
+        #[structopt(long, default_value = "_ewelink._tcp")]
+        service_name: String,
+
+        #[structopt(long, value_name = "SECONDS")]
+        /// also poll the device infos in this interval
+        poll_interval: Option<u64>,
     },
 
     /// flash the given firmware (--bin) ota
     Flash {
-        #[structopt(long, short = "id")]
-        device_id: String,
+        #[structopt(flatten)]
+        targets: Targets,
 
         #[structopt(
             long,
-            conflicts_with = "external_httpd_url",
-            conflicts_with = "bin_sha256sum"
+            conflicts_with_all = &["external-httpd-url", "bin-sha256sum"]
         )]
         /// firmware binary to flash
         bin: Option<String>,
 
+        #[structopt(long, conflicts_with_all = &["external-httpd-url", "bin-sha256sum"])]
+        /// port for the embedded web-server (default: 8989, 0: pick a free port)
+        httpd_port: Option<u16>,
+
         #[structopt(
             long,
-            default_value = "8989",
-            conflicts_with = "external_httpd_url",
-            conflicts_with = "bin_sha256sum"
+            conflicts_with_all = &["external-httpd-url", "bin-sha256sum"]
         )]
-        httpd_port: u16,
+        /// ip for the embedded web-server (default: host ip in the network of the device)
+        httpd_ip: Option<IpAddr>,
 
-        #[structopt(long, conflicts_with = "bin", conflicts_with = "httpd_port")]
+        #[structopt(
+            long,
+            conflicts_with_all = &["bin", "httpd-port", "httpd-ip"]
+        )]
         /// use external web-server with the given url (http://<IP>:<PORT>/path/sonoff.bin)
         external_httpd_url: Option<String>,
 
-        #[structopt(long, conflicts_with = "bin", conflicts_with = "httpd_port")]
+        #[structopt(
+            long,
+            conflicts_with_all = &["bin", "httpd-port", "httpd-ip"]
+        )]
         /// when using the extenal web-server, we need the sha256 sum of the binary on the server
         bin_sha256sum: Option<String>,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+
+        #[structopt(long, default_value = "300")]
+        /// seconds to wait for a device to finish the flash process
+        flash_timeout: u64,
+
+        #[structopt(long)]
+        /// verify that the new firmware comes up after the flash process
+        verify: bool,
+
+        #[structopt(long, default_value = "180")]
+        /// seconds to wait for the new firmware
+        verify_timeout: u64,
+    },
+
+    /// scan, unlock, flash and verify a device in one run
+    Provision {
+        #[structopt(long, short = "id", conflicts_with = "ip")]
+        /// scan for the device with the given id (default: the first found device)
+        device_id: Option<String>,
+
+        #[structopt(long)]
+        /// ip of the device - skips the scan
+        ip: Option<IpAddr>,
+
+        #[structopt(long, default_value = "8081")]
+        /// port of the device - used with '--ip'
+        port: u16,
+
+        #[structopt(long)]
+        /// firmware binary to flash
+        bin: String,
+
+        #[structopt(long, default_value = "8989")]
+        /// port for the embedded web-server (0: pick a free port)
+        httpd_port: u16,
+
+        #[structopt(long)]
+        /// ip for the embedded web-server (default: host ip in the network of the device)
+        httpd_ip: Option<IpAddr>,
+
+        #[structopt(long, default_value = "60")]
+        /// seconds to scan for the device
+        scan_timeout: u64,
+
+        #[structopt(long, default_value = "300")]
+        /// seconds to wait for the device to finish the flash process
+        flash_timeout: u64,
+
+        #[structopt(long, default_value = "180")]
+        /// seconds to wait for the new firmware
+        verify_timeout: u64,
+
+        #[structopt(long)]
+        /// start from scratch - ignore the progress from a previous run
+        restart: bool,
     },
 }
 
+// selects the devices for a command
+#[derive(StructOpt, Debug)]
+pub struct Targets {
+    #[structopt(
+        long,
+        short = "id",
+        use_delimiter = true,
+        require_delimiter = true,
+        required_unless_one = &["all", "tag", "filter"],
+        conflicts_with_all = &["all", "tag", "filter"]
+    )]
+    /// device id - use a comma separated list for multiple devices
+    pub device_id: Vec<String>,
+
+    #[structopt(long, conflicts_with_all = &["tag", "filter"])]
+    /// all devices from the cache
+    pub all: bool,
+
+    #[structopt(long, conflicts_with = "filter")]
+    /// all devices with the given tag
+    pub tag: Option<String>,
+
+    #[structopt(long)]
+    /// all devices which match the expression, e.g. 'type=diy_plug,tag!=outdoor,name=hall*'
+    pub filter: Option<Filter>,
+}
+
+impl Targets {
+    /// more than one device is (potentially) selected
+    pub fn is_batch(&self) -> bool {
+        self.all || self.tag.is_some() || self.filter.is_some() || self.device_id.len() > 1
+    }
+}
+
+// the number of devices which are requested at the same time
+#[derive(StructOpt, Debug, Clone, Copy)]
+pub struct Parallel {
+    #[structopt(long, default_value = "8")]
+    /// number of devices which are requested at the same time
+    pub parallel: usize,
+}
+
+// the source for the wifi password
+//
+// without any option, the password is looked up in the key store
+// or asked for on the terminal.
 #[derive(StructOpt, Debug)]
+pub struct PasswordSource {
+    #[structopt(long, conflicts_with_all = &["pwd-stdin", "pwd-env", "pwd-file"])]
+    /// the wifi password - visible in the shell history, prefer the other options
+    pub pwd: Option<String>,
+
+    #[structopt(long, conflicts_with_all = &["pwd-env", "pwd-file"])]
+    /// read the wifi password from stdin
+    pub pwd_stdin: bool,
+
+    #[structopt(long, value_name = "VAR", conflicts_with = "pwd-file")]
+    /// read the wifi password from the environment variable
+    pub pwd_env: Option<String>,
+
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    /// read the wifi password from the first line of the file
+    pub pwd_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SwitchCommand {
+    On(Countdown),
+    Off(Countdown),
+}
+
+impl SwitchCommand {
+    pub fn state(&self) -> SwitchState {
+        match self {
+            SwitchCommand::On(_) => SwitchState::On,
+            SwitchCommand::Off(_) => SwitchState::Off,
+        }
+    }
+
+    pub fn countdown(&self) -> &Countdown {
+        match self {
+            SwitchCommand::On(countdown) | SwitchCommand::Off(countdown) => countdown,
+        }
+    }
+}
+
+// switch back after a duration
+#[derive(StructOpt, Debug)]
+pub struct Countdown {
+    #[structopt(
+        long = "for",
+        value_name = "DURATION",
+        parse(try_from_str = countdown::parse_duration)
+    )]
+    /// switch back after the duration, e.g. '90s', '15m' or '1h30m'
+    pub duration: Option<Duration>,
+
+    #[structopt(long, requires = "duration")]
+    /// only record the switch back - it is sent by 'schedule run'
+    pub no_wait: bool,
+}
+
+#[derive(StructOpt, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SwitchState {
     On,
     Off,
 }
+
+impl SwitchState {
+    pub fn opposite(self) -> Self {
+        match self {
+            SwitchState::On => SwitchState::Off,
+            SwitchState::Off => SwitchState::On,
+        }
+    }
+}
+
+impl fmt::Display for SwitchState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SwitchState::On => write!(f, "on"),
+            SwitchState::Off => write!(f, "off"),
+        }
+    }
+}
+
+impl FromStr for SwitchState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(SwitchState::On),
+            "off" => Ok(SwitchState::Off),
+            _ => Err(invalid_state(s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone, Copy)]
+pub enum StartupState {
+    On,
+    Off,
+    /// the state before the power loss
+    Stay,
+}
+
+impl FromStr for StartupState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(StartupState::On),
+            "off" => Ok(StartupState::Off),
+            "stay" => Ok(StartupState::Stay),
+            _ => Err(invalid_state(s)),
+        }
+    }
+}
+
+fn invalid_state(s: &str) -> Error {
+    Error::InvalidRequest {
+        msg: format!("invalid state: '{}'", s),
+    }
+}
+
+#[derive(StructOpt, Debug, Clone, Copy)]
+pub enum PulseState {
+    On {
+        #[structopt(long, default_value = "500")]
+        /// pulse width in ms - a multiple of 500
+        width: u32,
+    },
+    Off,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ScheduleCommand {
+    /// list the jobs
+    List,
+
+    /// add a job, e.g. 'schedule add --device-id 1000xxxxxx --trigger "@sunset+30m" on'
+    Add {
+        #[structopt(long, short = "id")]
+        device_id: String,
+
+        #[structopt(long)]
+        /// the outlet of a multi channel device
+        outlet: Option<u8>,
+
+        #[structopt(long, alias = "cron")]
+        /// 'minute hour day-of-month month day-of-week' (@hourly, @daily, ...) or a solar event
+        /// with an optional offset: @sunrise, @sunset, @dawn, @dusk, e.g. '@sunset+30m'
+        trigger: Trigger,
+
+        #[structopt(subcommand)]
+        action: Action,
+    },
+
+    /// remove a job
+    Remove { id: u32 },
+
+    /// set the location for the solar events
+    Location {
+        #[structopt(long, allow_hyphen_values = true)]
+        /// in degrees - north is positive
+        latitude: f64,
+
+        #[structopt(long, allow_hyphen_values = true)]
+        /// in degrees - east is positive
+        longitude: f64,
+    },
+
+    /// print the next runs of the jobs - or of the given trigger
+    Preview {
+        /// only the job with the id
+        id: Option<u32>,
+
+        #[structopt(long, conflicts_with = "id")]
+        trigger: Option<Trigger>,
+
+        #[structopt(long, short = "n", default_value = "10")]
+        /// number of runs per job
+        count: usize,
+    },
+
+    /// run the jobs - missed jobs from the last 24 hours are caught up once
+    Run,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ReportCommand {
+    /// firmware versions and ota locks - outdated devices from a minimum version policy
+    Firmware {
+        #[structopt(long, parse(from_os_str))]
+        /// json file with the minimum versions, e.g. '{"minVersion": "3.6.0", "types": {"mini": "3.7.0"}}'
+        policy: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// minimum version for all devices - overrides 'minVersion' from the policy
+        min_version: Option<Version>,
+
+        #[structopt(long)]
+        /// print json instead of a table
+        json: bool,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum GroupCommand {
+    /// list the groups
+    List,
+
+    /// create or replace a group, e.g. 'group set workshop 1000xxxxxx 1000yyyyyy:0'
+    Set {
+        name: String,
+
+        #[structopt(required = true)]
+        /// 'device-id' or 'device-id:outlet'
+        members: Vec<Member>,
+    },
+
+    /// remove a group
+    Remove { name: String },
+
+    /// switch all members of the group
+    Switch {
+        name: String,
+
+        #[structopt(possible_values = &["on", "off"])]
+        state: SwitchState,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SceneCommand {
+    /// list the scenes
+    List,
+
+    /// create or replace a scene, e.g. 'scene set night 1000xxxxxx=off 1000yyyyyy:1=on'
+    Set {
+        name: String,
+
+        #[structopt(required = true)]
+        /// 'device-id=on|off' or 'device-id:outlet=on|off'
+        members: Vec<SceneMember>,
+    },
+
+    /// remove a scene
+    Remove { name: String },
+
+    /// switch the members to the states of the scene
+    Apply {
+        name: String,
+
+        #[structopt(flatten)]
+        parallel: Parallel,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum KeysCommand {
+    /// add a device key or a wifi password - the secret is read from the terminal
+    Add {
+        #[structopt(flatten)]
+        entry: KeyEntry,
+    },
+
+    /// list the device ids and essids in the key store
+    List,
+
+    /// remove a device key or a wifi password
+    Remove {
+        #[structopt(flatten)]
+        entry: KeyEntry,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub struct KeyEntry {
+    #[structopt(
+        long,
+        short = "id",
+        required_unless = "essid",
+        conflicts_with = "essid"
+    )]
+    /// the device key (api key) for an encrypted device
+    pub device_id: Option<String>,
+
+    #[structopt(long)]
+    /// the wifi password for the essid
+    pub essid: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::ErrorKind;
+
+    fn flash(args: &[&str]) -> Result<Args, structopt::clap::Error> {
+        let mut argv = vec!["sonoff-diy", "flash", "--all"];
+        argv.extend_from_slice(args);
+        Args::from_iter_safe(argv)
+    }
+
+    #[test]
+    fn flash_with_the_embedded_or_an_external_httpd() {
+        assert!(flash(&["--bin", "sonoff.bin", "--httpd-ip", "127.0.0.1"]).is_ok());
+        assert!(flash(&[
+            "--external-httpd-url",
+            "http://127.0.0.1/sonoff.bin",
+            "--bin-sha256sum",
+            "-"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn embedded_and_external_httpd_conflict() {
+        for args in &[
+            &[
+                "--httpd-ip",
+                "127.0.0.1",
+                "--external-httpd-url",
+                "http://x",
+            ][..],
+            &["--httpd-port", "0", "--bin-sha256sum", "-"][..],
+            &["--bin", "sonoff.bin", "--external-httpd-url", "http://x"][..],
+        ] {
+            match flash(args) {
+                Err(err) => assert_eq!(err.kind, ErrorKind::ArgumentConflict),
+                Ok(args) => panic!("accepted {:?}", args),
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::*;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use lazy_static::lazy_static;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// payload fields which are not written to the log
+const SECRET_FIELDS: &[&str] = &["password", "pwd", "key", "apikey"];
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+lazy_static! {
+    /// the log file - no log if not set
+    static ref AUDIT_LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// a state changing request to a device - one json line in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// utc, e.g. '2020-03-02T07:15:00Z'
+    pub time: String,
+    pub user: String,
+    pub host: String,
+    pub device_id: String,
+    pub endpoint: String,
+    /// with redacted secrets
+    pub payload: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn default_path() -> PathBuf {
+    let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+    path.push(".sonoff-diy-audit.jsonl");
+    path
+}
+
+/// records the state changing requests of all devices in the given file
+pub fn enable<P: Into<PathBuf>>(path: P) {
+    *AUDIT_LOG.lock().unwrap() = Some(path.into());
+}
+
+/// called by `Device` - errors are only reported, the request is already done
+pub fn record(device_id: &str, endpoint: &str, payload: &Value, res: &Result<Value>) {
+    let log = AUDIT_LOG.lock().unwrap();
+    let path = match *log {
+        Some(ref path) => path,
+        None => return,
+    };
+    let record = Record {
+        time: format_time(countdown::now()),
+        user: env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".into()),
+        host: hostname(),
+        device_id: device_id.to_string(),
+        endpoint: endpoint.to_string(),
+        payload: redact(payload),
+        response: res.as_ref().ok().cloned(),
+        error: res.as_ref().err().map(|err| err.to_string()),
+    };
+    if let Err(err) = append(path, &record) {
+        eprintln!(
+            "warning: unable to write the audit log {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+fn append(path: &Path, record: &Record) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    // one write per line - appends from other processes are not interleaved
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// replaces the values of secret fields
+fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| {
+                    let v = if SECRET_FIELDS.contains(&k.to_lowercase().as_str()) {
+                        Value::String("***".into())
+                    } else {
+                        redact(v)
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(redact).collect()),
+        v => v.clone(),
+    }
+}
+
+/// seconds since the epoch as utc, e.g. '2020-03-02T07:15:00Z'
+pub fn format_time(ts: i64) -> String {
+    DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .format(TIME_FORMAT)
+        .to_string()
+}
+
+/// a local time like '2020-03-02' or '2020-03-02 07:15' - or a duration before now like '2h'
+pub fn parse_time(s: &str, now: i64) -> Result<i64> {
+    if let Ok(duration) = countdown::parse_duration(s) {
+        return Ok(now - duration.as_secs() as i64);
+    }
+    let invalid = || Error::ParserError {
+        msg: format!(
+            "invalid time '{}' - expected e.g. '2020-03-02', '2020-03-02 07:15' or '2h'",
+            s
+        ),
+    };
+    let tm = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|day| day.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(invalid)?;
+    // the parsed time is local - the earlier one if the clock was turned back
+    Local
+        .from_local_datetime(&tm)
+        .earliest()
+        .map(|tm| tm.timestamp())
+        .ok_or_else(invalid)
+}
+
+/// selects records from the log
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub device_id: Option<String>,
+    /// seconds since the epoch
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl Query {
+    pub fn matches(&self, record: &Record) -> bool {
+        // the utc times in the fixed format compare like the timestamps
+        self.device_id
+            .as_ref()
+            .map(|id| *id == record.device_id)
+            .unwrap_or(true)
+            && self
+                .since
+                .map(|ts| record.time >= format_time(ts))
+                .unwrap_or(true)
+            && self
+                .until
+                .map(|ts| record.time < format_time(ts))
+                .unwrap_or(true)
+    }
+
+    /// the matching records from the log - an empty list if the log does not exist
+    pub fn run(&self, path: &Path) -> Result<Vec<Record>> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        debug!("read audit log from {}", path.display());
+        let mut records = Vec::new();
+        for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) if self.matches(&record) => records.push(record),
+                Ok(_) => (),
+                Err(err) => eprintln!("warning: skip line {} in the audit log: {}", idx + 1, err),
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redact_secrets() {
+        let payload = json!({
+            "deviceid": "1000000001",
+            "data": { "ssid": "iot", "password": "secret" },
+        });
+        assert_eq!(
+            redact(&payload),
+            json!({
+                "deviceid": "1000000001",
+                "data": { "ssid": "iot", "password": "***" },
+            })
+        );
+    }
+
+    #[test]
+    fn query_by_time() {
+        // 2020-03-02 00:00 utc
+        let monday = 1_583_107_200;
+        assert_eq!(format_time(monday + 3600), "2020-03-02T01:00:00Z");
+        assert_eq!(parse_time("2h", monday).unwrap(), monday - 7200);
+        assert!(parse_time("yesterday", monday).is_err());
+
+        let record = |device_id: &str, ts: i64| Record {
+            time: format_time(ts),
+            user: "user".into(),
+            host: "host".into(),
+            device_id: device_id.into(),
+            endpoint: "switch".into(),
+            payload: json!({}),
+            response: None,
+            error: None,
+        };
+        let query = Query {
+            device_id: Some("a".into()),
+            since: Some(monday),
+            until: Some(monday + 86_400),
+        };
+        assert!(query.matches(&record("a", monday)));
+        assert!(!query.matches(&record("b", monday)));
+        assert!(!query.matches(&record("a", monday - 1)));
+        assert!(!query.matches(&record("a", monday + 86_400)));
+    }
+}
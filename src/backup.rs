@@ -0,0 +1,250 @@
+use crate::*;
+use chrono::{DateTime, Local};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+/// the settings of a device from '/zeroconf/info'
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub startup: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pulse: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pulse_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switch: Option<String>,
+    /// the outlets of multi channel devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switches: Option<Vec<OutletState>>,
+    /// not restored - the password is not part of the info
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// not restored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fw_version: Option<String>,
+}
+
+impl From<DeviceInfo> for Settings {
+    fn from(info: DeviceInfo) -> Self {
+        Settings {
+            startup: info.startup,
+            pulse: info.pulse,
+            pulse_width: info.pulse_width,
+            switch: info.switch,
+            switches: info.switches,
+            ssid: info.ssid,
+            fw_version: info.fw_version,
+        }
+    }
+}
+
+impl Settings {
+    /// the settings which differ - (name, own value, other value)
+    pub fn diff(&self, other: &Settings) -> Vec<(&'static str, String, String)> {
+        fn show<T: ToString>(v: &Option<T>) -> String {
+            v.as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".into())
+        }
+        let outlets = |s: &Settings| {
+            s.switches.as_ref().map(|switches| {
+                switches
+                    .iter()
+                    .map(|s| format!("{}:{}", s.outlet, s.switch))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        };
+
+        let fields = vec![
+            ("startup", show(&self.startup), show(&other.startup)),
+            ("pulse", show(&self.pulse), show(&other.pulse)),
+            (
+                "pulseWidth",
+                show(&self.pulse_width),
+                show(&other.pulse_width),
+            ),
+            ("switch", show(&self.switch), show(&other.switch)),
+            ("switches", show(&outlets(self)), show(&outlets(other))),
+            ("ssid", show(&self.ssid), show(&other.ssid)),
+            ("fwVersion", show(&self.fw_version), show(&other.fw_version)),
+        ];
+        fields.into_iter().filter(|(_, a, b)| a != b).collect()
+    }
+
+    /// re-applies the startup state, the pulse and (optional) the switch state.
+    ///
+    /// returns the result per setting - the ssid and the firmware version are not restored.
+    pub fn restore(
+        &self,
+        device: &Device,
+        with_switch: bool,
+    ) -> Vec<(&'static str, Result<String>)> {
+        let mut results = Vec::new();
+        if let Some(ref startup) = self.startup {
+            let res = match startup.as_str() {
+                "on" => device.startup(StartupState::On),
+                "off" => device.startup(StartupState::Off),
+                "stay" => device.startup(StartupState::Stay),
+                _ => Err(invalid("startup", startup)),
+            };
+            results.push(("startup", res));
+        }
+        if let Some(ref pulse) = self.pulse {
+            let res = match (pulse.as_str(), self.pulse_width) {
+                ("on", Some(width)) => device.pulse(PulseState::On { width }),
+                ("off", _) => device.pulse(PulseState::Off),
+                _ => Err(invalid("pulse", pulse)),
+            };
+            results.push(("pulse", res));
+        }
+        if with_switch {
+            match (&self.switches, &self.switch) {
+                (Some(switches), _) => {
+                    let outlets = switches
+                        .iter()
+                        .map(|s| Ok((s.outlet, s.switch.parse()?)))
+                        .collect::<Result<Vec<_>>>();
+                    results.push(("switches", outlets.and_then(|o| device.switches(&o))));
+                }
+                (None, Some(switch)) => {
+                    results.push(("switch", switch.parse().and_then(|s| device.switch(s))))
+                }
+                (None, None) => (),
+            }
+        }
+        results
+    }
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidRequest {
+        msg: format!("invalid {} '{}' in the backup", name, value),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceBackup {
+    pub device_id: String,
+    pub name: String,
+    pub settings: Settings,
+}
+
+/// a snapshot of the device settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    /// seconds since the epoch
+    pub created: i64,
+    pub devices: Vec<DeviceBackup>,
+}
+
+impl Backup {
+    /// 'sonoff-diy-backups' in the local data dir
+    pub fn default_dir() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push("sonoff-diy-backups");
+        path
+    }
+
+    /// the file name with the local time, e.g. 'backup-20200302-071500.json'
+    pub fn file_name(&self) -> String {
+        let ts = DateTime::from_timestamp(self.created, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local);
+        format!("backup-{}.json", ts.format("%Y%m%d-%H%M%S"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        debug!("load backup from {}", path.display());
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// saves the backup in the given file - or with the timestamped name in the directory
+    pub fn save(&self, path: &Path) -> Result<PathBuf> {
+        let path = if path.is_dir() {
+            path.join(self.file_name())
+        } else {
+            path.to_path_buf()
+        };
+        debug!("save backup to: {}", path.display());
+        let file = File::create(&path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(path)
+    }
+
+    /// the backup of the device
+    pub fn device(&self, device_id: &str) -> Result<&DeviceBackup> {
+        self.devices
+            .iter()
+            .find(|d| d.device_id == device_id)
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.to_string(),
+            })
+    }
+}
+
+/// reads the settings of the devices in parallel.
+///
+/// the backup contains the reachable devices - the failures are returned per device.
+pub fn backup(
+    devices: Vec<Device>,
+    parallel: usize,
+    now: i64,
+) -> (Backup, Vec<(Device, Result<DeviceBackup>)>) {
+    let results = fanout::fan_out(devices, parallel, |device| {
+        Ok(DeviceBackup {
+            device_id: device.id.clone(),
+            name: device.name.clone(),
+            settings: device.device_info()?.into(),
+        })
+    });
+    let backup = Backup {
+        created: now,
+        devices: results
+            .iter()
+            .filter_map(|(_, res)| res.as_ref().ok().cloned())
+            .collect(),
+    };
+    (backup, results)
+}
+
+/// creates the directory for the backups
+pub fn create_default_dir() -> Result<PathBuf> {
+    let dir = Backup::default_dir();
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_settings() {
+        let backup = Settings {
+            startup: Some("stay".into()),
+            pulse: Some("on".into()),
+            pulse_width: Some(1500),
+            switch: Some("on".into()),
+            ..Settings::default()
+        };
+        let live = Settings {
+            pulse: Some("off".into()),
+            ..backup.clone()
+        };
+        assert!(backup.diff(&backup).is_empty());
+        assert_eq!(
+            backup.diff(&live),
+            vec![("pulse", "on".to_string(), "off".to_string())]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use sonoff_diy::*;
+use std::net::IpAddr;
+use structopt::StructOpt;
+
+/// emulates a sonoff device in diy mode
+#[derive(StructOpt, Debug)]
+struct Args {
+    #[structopt(short, long)]
+    /// enable debug logging
+    debug: bool,
+
+    #[structopt(long, short = "id", default_value = "1000000001")]
+    device_id: String,
+
+    #[structopt(long, default_value = "0.0.0.0")]
+    ip: IpAddr,
+
+    #[structopt(long, default_value = "8081")]
+    port: u16,
+
+    #[structopt(long)]
+    /// don't announce the device per mdns
+    no_mdns: bool,
+
+    #[structopt(long)]
+    /// inject the faults from the given scenario file
+    scenario: Option<String>,
+
+    #[structopt(long)]
+    /// emulate an encrypted device with the given device key
+    key: Option<String>,
+}
+
+#[paw::main]
+fn main(args: Args) {
+    let level = if args.debug { "debug" } else { "info" };
+    env_logger::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        err.print_backtrace();
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let mut emulator = Emulator::new(&args.ip, args.port, &args.device_id)?;
+    if let Some(scenario) = args.scenario {
+        emulator = emulator.scenario(scenario::Scenario::load(scenario)?);
+    }
+    if let Some(key) = args.key {
+        emulator = emulator.key(&key);
+    }
+    if !args.no_mdns {
+        emulator.advertise()?;
+    }
+    println!(
+        "emulated device {} listens at {}",
+        args.device_id,
+        emulator.local_addr()
+    );
+    emulator.start().join().unwrap();
+    Ok(())
+}
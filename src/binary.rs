@@ -2,6 +2,12 @@ use crate::*;
 use std::fmt;
 use std::path::PathBuf;
 
+/// the diy ota update accepts only binaries up to 508KB
+pub const MAX_BINARY_SIZE: usize = 508 * 1024;
+
+/// first byte of an esp8266 firmware image
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+
 #[derive(Debug, Clone)]
 pub struct Binary(PathBuf);
 
@@ -35,6 +41,27 @@ impl Binary {
         std::fs::read(&self.0).map_err(|e| e.into())
     }
 
+    /// checks if the binary looks like a flashable esp8266 firmware
+    pub fn validate(&self) -> Result<()> {
+        let content = self.slurp()?;
+        if content.first() != Some(&ESP_IMAGE_MAGIC) {
+            return Err(Error::InvalidBinary {
+                msg: "not an esp8266 firmware image".into(),
+            });
+        }
+
+        if content.len() > MAX_BINARY_SIZE {
+            return Err(Error::InvalidBinary {
+                msg: format!(
+                    "binary too large: {} bytes - max: {} bytes",
+                    content.len(),
+                    MAX_BINARY_SIZE
+                ),
+            });
+        }
+        Ok(())
+    }
+
     pub fn sha256sum(&self) -> String {
         let content = self.slurp().unwrap();
         hmac_sha256::Hash::hash(&content)
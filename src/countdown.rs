@@ -0,0 +1,291 @@
+use crate::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// wait time before a failed revert is sent again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// a pending switch back after 'switch on / off --for <DURATION>'
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revert {
+    pub device_id: String,
+    /// the state to switch to
+    pub state: SwitchState,
+    /// seconds since the epoch
+    pub due: i64,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl Revert {
+    pub fn new(device_id: &str, state: SwitchState, duration: Duration) -> Self {
+        Revert {
+            device_id: device_id.to_string(),
+            state,
+            due: now() + duration.as_secs() as i64,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// the pending reverts - persisted, so they survive a restart of the tool
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Countdowns {
+    reverts: Vec<Revert>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Countdowns {
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-countdowns.json");
+        path
+    }
+
+    /// loads the pending reverts - none if the file does not exist
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let mut countdowns = if path.exists() {
+            debug!("load countdowns from {}", path.display());
+            let file = File::open(&path)?;
+            serde_json::from_reader::<_, Countdowns>(BufReader::new(file))?
+        } else {
+            Countdowns::default()
+        };
+        countdowns.path = path;
+        Ok(countdowns)
+    }
+
+    /// replaces the file - a concurrent `load` never sees a partial file
+    pub fn save(&self) -> Result<()> {
+        debug!("save countdowns to: {}", self.path.display());
+        fsutils::write_atomic(&self.path, &serde_json::to_vec_pretty(self)?, false)
+    }
+
+    pub fn reverts(&self) -> &[Revert] {
+        &self.reverts
+    }
+
+    /// replaces a pending revert of the device
+    pub fn add(&mut self, revert: Revert) {
+        self.cancel(&revert.device_id);
+        self.reverts.push(revert);
+    }
+
+    /// returns the cancelled revert
+    pub fn cancel(&mut self, device_id: &str) -> Option<Revert> {
+        let idx = self.reverts.iter().position(|r| r.device_id == device_id)?;
+        Some(self.reverts.remove(idx))
+    }
+
+    pub fn overdue(&self, now: i64) -> Vec<Revert> {
+        self.reverts
+            .iter()
+            .filter(|r| r.due <= now)
+            .cloned()
+            .collect()
+    }
+}
+
+/// sends the due reverts of the given devices from the countdown file.
+///
+/// delivered reverts are removed - failed reverts stay in the file with the error
+/// and are retried with the next call.
+pub fn deliver_due(
+    path: &Path,
+    devices: &[Device],
+    now: i64,
+) -> Result<Vec<(Revert, Result<String>)>> {
+    let results = Countdowns::load(path)?
+        .overdue(now)
+        .into_iter()
+        .filter_map(|revert| {
+            let device = devices.iter().find(|d| d.id == revert.device_id)?;
+            let res = device.switch(revert.state);
+            Some((revert, res))
+        })
+        .collect::<Vec<_>>();
+    if results.is_empty() {
+        return Ok(results);
+    }
+
+    // reload - a revert could have been added or cancelled in the meantime.
+    // another process could have recorded an attempt - only the device and due time identify it
+    let mut countdowns = Countdowns::load(path)?;
+    for (revert, res) in &results {
+        match countdowns
+            .reverts
+            .iter()
+            .position(|r| r.device_id == revert.device_id && r.due == revert.due)
+        {
+            Some(idx) if res.is_ok() => {
+                countdowns.reverts.remove(idx);
+            }
+            Some(idx) => {
+                let pending = &mut countdowns.reverts[idx];
+                pending.attempts += 1;
+                pending.last_error = res.as_ref().err().map(|err| err.to_string());
+            }
+            None => (),
+        }
+    }
+    countdowns.save()?;
+    Ok(results)
+}
+
+/// waits until the reverts are due and sends them - retries until they are delivered.
+///
+/// skips reverts which were cancelled or sent by another process (e.g. 'schedule run').
+pub fn await_reverts(path: &Path, devices: &[Device], reverts: &[Revert]) -> Result<()> {
+    let due = reverts.iter().map(|r| r.due).max().unwrap_or(0);
+    thread::sleep(Duration::from_secs((due - now()).max(0) as u64));
+    loop {
+        let countdowns = Countdowns::load(path)?;
+        let pending = devices
+            .iter()
+            .filter(|d| {
+                countdowns.reverts().iter().any(|r| {
+                    r.device_id == d.id
+                        && reverts
+                            .iter()
+                            .any(|revert| revert.device_id == d.id && revert.due == r.due)
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut failed = 0;
+        for (r, res) in deliver_due(path, &pending, now())? {
+            match res {
+                Ok(_) => println!("switched {} {}", r.device_id, r.state),
+                Err(err) => {
+                    failed += 1;
+                    eprintln!(
+                        "warning: unable to switch {} {} (attempt {}): {} - retry in {}s",
+                        r.device_id,
+                        r.state,
+                        r.attempts + 1,
+                        err,
+                        RETRY_INTERVAL.as_secs()
+                    )
+                }
+            }
+        }
+        if failed == 0 {
+            return Ok(());
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// parses a duration like '90s', '15m', '2h' or '1h30m'
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidRequest {
+        msg: format!(
+            "invalid duration '{}' - expected e.g. '90s', '15m' or '1h30m'",
+            s
+        ),
+    };
+
+    let (mut secs, mut number) = (0, String::new());
+    for c in s.trim().chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            's' | 'm' | 'h' if !number.is_empty() => {
+                let unit = match c {
+                    's' => 1,
+                    'm' => 60,
+                    _ => 3600,
+                };
+                secs += number.parse::<u64>().map_err(|_| invalid())? * unit;
+                number.clear();
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if !number.is_empty() || secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// seconds since the epoch
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        for s in &["", "15", "m", "0m", "1d", "-5m"] {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn failed_reverts_are_kept() {
+        let path =
+            std::env::temp_dir().join(format!("sonoff-diy-countdowns-{}.json", std::process::id()));
+        let mut countdowns = Countdowns::load(&path).unwrap();
+        countdowns.add(Revert::new(
+            "1000000001",
+            SwitchState::Off,
+            Duration::from_secs(60),
+        ));
+        countdowns.add(Revert::new(
+            "1000000002",
+            SwitchState::Off,
+            Duration::from_secs(900),
+        ));
+        // replaces the first revert
+        countdowns.add(Revert::new(
+            "1000000001",
+            SwitchState::On,
+            Duration::from_secs(120),
+        ));
+        countdowns.save().unwrap();
+        assert_eq!(countdowns.reverts().len(), 2);
+
+        // nothing listens at the port
+        let devices = [
+            Device::new("a", "1000000001", &[], "127.0.0.1".parse().unwrap(), 1),
+            Device::new("b", "1000000002", &[], "127.0.0.1".parse().unwrap(), 1),
+        ];
+        assert!(deliver_due(&path, &devices, now()).unwrap().is_empty());
+        let results = deliver_due(&path, &devices, now() + 300).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.state, SwitchState::On);
+
+        let countdowns = Countdowns::load(&path).unwrap();
+        assert_eq!(countdowns.reverts()[1].attempts, 1);
+        assert!(countdowns.reverts()[1].last_error.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
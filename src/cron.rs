@@ -0,0 +1,202 @@
+use crate::*;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// a cron expression: 'minute hour day-of-month month day-of-week'
+///
+/// the fields support '*', lists ('1,15'), ranges ('1-5') and steps ('*/15', '8-18/2').
+/// the day of the week is 0 - 7 (sunday is 0 and 7). '@hourly', '@daily',
+/// '@weekly', '@monthly' and '@yearly' are shortcuts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// day-of-month and day-of-week are or-ed if both are restricted (like in cron)
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let expanded = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(invalid(expr, "expected 5 fields"));
+        }
+
+        let weekdays = parse_field(expr, fields[4], 0, 7)?;
+        Ok(Cron {
+            expr: expr.trim().to_string(),
+            minutes: parse_field(expr, fields[0], 0, 59)?,
+            hours: parse_field(expr, fields[1], 0, 23)?,
+            days: parse_field(expr, fields[2], 1, 31)?,
+            months: parse_field(expr, fields[3], 1, 12)?,
+            // sunday is 0 and 7
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// the expression matches the minute of the given (local) time
+    pub fn matches(&self, tm: &NaiveDateTime) -> bool {
+        self.matches_day(tm) && bit(self.hours, tm.hour()) && bit(self.minutes, tm.minute())
+    }
+
+    fn matches_day(&self, tm: &NaiveDateTime) -> bool {
+        let day = bit(self.days, tm.day());
+        let weekday = bit(self.weekdays, tm.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day && bit(self.months, tm.month())
+    }
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Cron::parse(s)
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+impl Serialize for Cron {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.expr)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cron {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let expr = String::deserialize(deserializer)?;
+        Cron::parse(&expr).map_err(de::Error::custom)
+    }
+}
+
+fn bit(mask: u64, value: u32) -> bool {
+    (0..64).contains(&value) && mask & (1 << value) != 0
+}
+
+/// the allowed values of a field as bit mask
+fn parse_field(expr: &str, field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let mut iter = part.splitn(2, '/');
+        let (range, step) = (iter.next().unwrap_or(""), iter.next());
+        let step = match step {
+            Some(step) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => step,
+                _ => return Err(invalid(expr, &format!("invalid step '{}'", step))),
+            },
+            None => 1,
+        };
+
+        let number = |s: &str| match s.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(invalid(
+                expr,
+                &format!("'{}' is not in {} - {}", s, min, max),
+            )),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range if range.contains('-') => {
+                let mut iter = range.splitn(2, '-');
+                let from = number(iter.next().unwrap_or(""))?;
+                let to = number(iter.next().unwrap_or(""))?;
+                if from > to {
+                    return Err(invalid(expr, &format!("invalid range '{}'", range)));
+                }
+                (from, to)
+            }
+            // '5/15' starts at 5 and runs to the end
+            value if step > 1 => (number(value)?, max),
+            value => {
+                let n = number(value)?;
+                (n, n)
+            }
+        };
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn invalid(expr: &str, msg: &str) -> Error {
+    Error::InvalidRequest {
+        msg: format!("invalid cron expression '{}': {}", expr, msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// 2020-03-02 (monday) at the given time
+    fn monday(hour: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2020, 3, 2)
+            .and_then(|day| day.and_hms_opt(hour, min, 0))
+            .unwrap()
+    }
+
+    /// 2020-03-01 (sunday) at the given time
+    fn sunday(hour: u32, min: u32) -> NaiveDateTime {
+        monday(hour, min) - chrono::Duration::days(1)
+    }
+
+    #[test]
+    fn parse_and_match() {
+        let cron = Cron::parse("30 7 * * 1-5").unwrap();
+        assert!(cron.matches(&monday(7, 30)));
+        assert!(!cron.matches(&monday(7, 31)));
+        assert!(!cron.matches(&sunday(7, 30)));
+
+        let cron = Cron::parse("*/15 8-18/2 * * *").unwrap();
+        assert!(cron.matches(&monday(8, 45)));
+        assert!(!cron.matches(&monday(9, 45)));
+
+        // sunday as 7
+        assert!(Cron::parse("0 0 * * 7").unwrap().matches(&sunday(0, 0)));
+
+        // day-of-month or day-of-week
+        let cron = Cron::parse("0 12 15 * 1").unwrap();
+        assert!(cron.matches(&monday(12, 0)));
+
+        assert!(Cron::parse("@daily").unwrap().matches(&monday(0, 0)));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in &[
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert!(Cron::parse(expr).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::*;
+use aes::Aes128;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use hmac_sha256::HMAC;
+
+type Aes128Cbc = Cbc<Aes128, Pkcs7>;
+
+/// the aes key is the md5 digest from the device key
+fn cipher_key(device_key: &str) -> [u8; 16] {
+    md5::compute(device_key.as_bytes()).0
+}
+
+pub fn random_iv() -> [u8; 16] {
+    rand::random()
+}
+
+/// encrypts the plaintext with aes-128-cbc - returns the base64 encoded ciphertext
+pub fn encrypt(device_key: &str, iv: &[u8], plaintext: &[u8]) -> Result<String> {
+    Ok(base64::encode(encrypt_raw(
+        &cipher_key(device_key),
+        iv,
+        plaintext,
+    )?))
+}
+
+/// decrypts the base64 encoded ciphertext with the base64 encoded iv
+pub fn decrypt(device_key: &str, iv: &str, data: &str) -> Result<Vec<u8>> {
+    decrypt_raw(&cipher_key(device_key), &decode(iv)?, &decode(data)?)
+}
+
+/// encrypts the plaintext with aes-128-cbc and the given 16 byte key
+pub fn encrypt_raw(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    Ok(cipher(key, iv)?.encrypt_vec(plaintext))
+}
+
+/// decrypts the aes-128-cbc ciphertext with the given 16 byte key
+pub fn decrypt_raw(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    cipher(key, iv)?
+        .decrypt_vec(ciphertext)
+        .map_err(|err| Error::CryptoError {
+            msg: format!("unable to decrypt: {}", err),
+        })
+}
+
+/// derives a key from the passphrase per pbkdf2-hmac-sha256 (first block only)
+pub fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut u = HMAC::mac(&[salt, &1u32.to_be_bytes()].concat(), passphrase.as_bytes());
+    let mut key = u;
+    for _ in 1..rounds {
+        u = HMAC::mac(&u, passphrase.as_bytes());
+        key.iter_mut().zip(u.iter()).for_each(|(k, u)| *k ^= u);
+    }
+    key
+}
+
+/// compares the macs in constant time - the time does not depend on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    base64::decode(s).map_err(|err| Error::CryptoError {
+        msg: format!("invalid base64: {}", err),
+    })
+}
+
+fn cipher(key: &[u8], iv: &[u8]) -> Result<Aes128Cbc> {
+    Aes128Cbc::new_from_slices(key, iv).map_err(|err| Error::CryptoError {
+        msg: err.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // generated with:
+    //   KEY=$(printf '%s' "$DEVICE_KEY" | openssl md5 -binary | xxd -p)
+    //   printf '%s' "$PLAINTEXT" | openssl enc -aes-128-cbc -K $KEY -iv $IV | base64
+    const DEVICE_KEY: &str = "0123456789abcdef0123456789abcdef";
+    const IV: &[u8] = b"0123456789abcdef";
+    const IV_BASE64: &str = "MDEyMzQ1Njc4OWFiY2RlZg==";
+
+    #[test]
+    fn cipher_key_should_be_the_md5_digest() {
+        let expected = [
+            0x85, 0x16, 0xac, 0x99, 0xdc, 0x60, 0x60, 0x32, 0x95, 0xde, 0x7b, 0xdb, 0x6a, 0x15,
+            0x35, 0x30,
+        ];
+        assert_eq!(cipher_key(DEVICE_KEY), expected);
+    }
+
+    #[test]
+    fn encrypt_known_answer() {
+        assert_eq!(
+            encrypt(DEVICE_KEY, IV, br#"{"switch":"on"}"#).unwrap(),
+            "bHZ9aM+2iSXf3ovNEWuCFw=="
+        );
+    }
+
+    #[test]
+    fn decrypt_known_answer() {
+        let plaintext = decrypt(
+            DEVICE_KEY,
+            IV_BASE64,
+            concat!(
+                "t8z/ieuzmnE/T0t7MsuFeOnsIaZVZZzKiL/kD6epL7/ZcbdM0Z+ePm22XEtpRn2m",
+                "SHuJe/oXiUTIV6QGIbRVs0xrZCRD7RpAbR6t/lUZ1BL440AAO9NIGXE3Gm/vBXCU"
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(plaintext).unwrap(),
+            concat!(
+                r#"{"switch":"off","startup":"stay","pulse":"off","#,
+                r#""sledOnline":"on","pulseWidth":500,"rssi":-55}"#
+            )
+        );
+    }
+
+    #[test]
+    fn derive_key_known_answer() {
+        // rfc 7914 section 11 and the rfc 6070 inputs with sha256
+        let hex = |key: [u8; 32]| key.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+        assert_eq!(
+            hex(derive_key("passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert_eq!(
+            hex(derive_key("password", b"salt", 4096)),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"
+        );
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key_should_fail() {
+        assert!(decrypt("wrong key", IV_BASE64, "bHZ9aM+2iSXf3ovNEWuCFw==").is_err());
+    }
+
+    #[test]
+    fn constant_time_eq_compares_all_bytes() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::*;
+use log::debug;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// how long the scanner listens for announcements per round
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(5);
+
+/// max. number of devices which are probed at the same time
+const PARALLEL_PROBES: usize = 8;
+
+/// the state of a flash job which was triggered per api
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum FlashJob {
+    Running,
+    Done { msg: String },
+    Failed { error: String },
+}
+
+/// the prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Clone)]
+pub struct RegistryEntry {
+    pub device: Device,
+    /// seconds since the epoch from the last mdns announcement
+    pub last_seen: Option<u64>,
+    /// '/zeroconf/info' answered in the last discovery round
+    pub reachable: Option<bool>,
+    /// from the last '/zeroconf/info' probe - `None` if it failed
+    pub info: Option<DeviceInfo>,
+    pub flash: Option<FlashJob>,
+}
+
+impl RegistryEntry {
+    fn new(device: Device, last_seen: Option<u64>) -> Self {
+        RegistryEntry {
+            device,
+            last_seen,
+            reachable: None,
+            info: None,
+            flash: None,
+        }
+    }
+}
+
+/// the known devices - fed by the scanner
+#[derive(Debug, Clone, Default)]
+pub struct Registry(Arc<Mutex<BTreeMap<String, RegistryEntry>>>);
+
+impl Registry {
+    pub fn new(devices: Vec<Device>) -> Self {
+        let entries = devices
+            .into_iter()
+            .map(|device| (device.id.clone(), RegistryEntry::new(device, None)))
+            .collect();
+        Registry(Arc::new(Mutex::new(entries)))
+    }
+
+    pub fn devices(&self) -> Vec<Device> {
+        let entries = self.0.lock().unwrap();
+        entries.values().map(|e| e.device.clone()).collect()
+    }
+
+    pub fn entries(&self) -> Vec<RegistryEntry> {
+        let entries = self.0.lock().unwrap();
+        entries.values().cloned().collect()
+    }
+
+    pub fn get(&self, device_id: &str) -> Result<Device> {
+        let entries = self.0.lock().unwrap();
+        entries
+            .get(device_id)
+            .map(|e| e.device.clone())
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.to_string(),
+            })
+    }
+
+    /// updates the device from an announcement - returns `true` for a new device
+    pub fn announced(&self, mut device: Device) -> bool {
+        let mut entries = self.0.lock().unwrap();
+        let now = Some(now());
+        match entries.get_mut(&device.id) {
+            Some(entry) => {
+                device.tags = entry.device.tags.clone();
+                device.key = device.key.or_else(|| entry.device.key.clone());
+                entry.device = device;
+                entry.last_seen = now;
+                false
+            }
+            None => {
+                entries.insert(device.id.clone(), RegistryEntry::new(device, now));
+                true
+            }
+        }
+    }
+
+    /// sets the keys of the encrypted devices - without touching 'last_seen'
+    pub fn assign_keys(&self, keys: &KeyStore) {
+        for entry in self.0.lock().unwrap().values_mut() {
+            keys.assign_keys(std::slice::from_mut(&mut entry.device));
+        }
+    }
+
+    /// the device was announced within the given time
+    pub fn is_present(&self, device_id: &str, max_age: Duration) -> bool {
+        let entries = self.0.lock().unwrap();
+        match entries.get(device_id).and_then(|e| e.last_seen) {
+            Some(last_seen) => now().saturating_sub(last_seen) <= max_age.as_secs(),
+            None => false,
+        }
+    }
+
+    /// records the result from the device info probe
+    pub fn probed(&self, device_id: &str, info: Option<&DeviceInfo>) {
+        if let Some(entry) = self.0.lock().unwrap().get_mut(device_id) {
+            entry.reachable = Some(info.is_some());
+            entry.info = info.cloned();
+        }
+    }
+
+    /// marks the flash as running - fails if one is already running for the device
+    fn start_flash(&self, device_id: &str) -> Result<()> {
+        let mut entries = self.0.lock().unwrap();
+        let entry = entries
+            .get_mut(device_id)
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.to_string(),
+            })?;
+        if let Some(FlashJob::Running) = entry.flash {
+            return Err(Error::DeviceBusy {
+                device_id: device_id.to_string(),
+            });
+        }
+        entry.flash = Some(FlashJob::Running);
+        Ok(())
+    }
+
+    fn set_flash(&self, device_id: &str, job: FlashJob) {
+        if let Some(entry) = self.0.lock().unwrap().get_mut(device_id) {
+            entry.flash = Some(job);
+        }
+    }
+
+    fn to_json(&self, device_id: &str) -> Result<Value> {
+        let entries = self.0.lock().unwrap();
+        let entry = entries
+            .get(device_id)
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.to_string(),
+            })?;
+        Ok(entry_json(entry))
+    }
+
+    fn to_json_list(&self) -> Value {
+        let entries = self.0.lock().unwrap();
+        Value::Array(entries.values().map(entry_json).collect())
+    }
+}
+
+/// seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn entry_json(entry: &RegistryEntry) -> Value {
+    let device = &entry.device;
+    json!({
+        "id": device.id,
+        "name": device.name,
+        "ip": device.ip,
+        "port": device.port,
+        "tags": device.tags,
+        "encrypt": device.encrypt,
+        "lastSeen": entry.last_seen,
+        "reachable": entry.reachable,
+        "state": device.txt_data().ok(),
+        "flash": entry.flash,
+    })
+}
+
+/// long running process with a device registry and a local http / json api
+///
+///   GET  /devices                  all devices with the state from the last announcement
+///   GET  /devices/<id>             a single device
+///   GET  /devices/<id>/info        queries the device info
+///   POST /devices/<id>/switch      {"state": "on|off"}
+///   POST /devices/<id>/startup     {"state": "on|off|stay"}
+///   POST /devices/<id>/pulse       {"state": "on|off", "width": 500}
+///   POST /devices/<id>/flash       {"bin": "<file>"} - the progress is in 'GET /devices/<id>'
+///   GET  /metrics                  device gauges and request / ota counters for prometheus
+///
+/// the api serves only loopback addresses - unless the requests must carry a token.
+/// flashing is disabled without a directory with the binaries.
+pub struct Daemon {
+    listener: TcpListener,
+    addr: SocketAddr,
+    registry: Registry,
+    device_cache: Arc<Mutex<DeviceCache>>,
+    keys: Option<Arc<KeyStore>>,
+    flash_timeout: Duration,
+    token: Option<String>,
+    flash_dir: Option<PathBuf>,
+}
+
+impl Daemon {
+    pub fn new(addr: SocketAddr, device_cache: DeviceCache) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).map_err(|source| Error::BindError { addr, source })?;
+        let addr = listener.local_addr()?;
+        debug!("daemon bound to {}", addr);
+        Ok(Daemon {
+            listener,
+            addr,
+            registry: Registry::new(device_cache.devices()),
+            device_cache: Arc::new(Mutex::new(device_cache)),
+            keys: None,
+            flash_timeout: Duration::from_secs(300),
+            token: None,
+            flash_dir: None,
+        })
+    }
+
+    /// key store for encrypted devices
+    pub fn keys(mut self, keys: KeyStore) -> Self {
+        self.registry.assign_keys(&keys);
+        self.keys = Some(Arc::new(keys));
+        self
+    }
+
+    pub fn flash_timeout(mut self, timeout: Duration) -> Self {
+        self.flash_timeout = timeout;
+        self
+    }
+
+    /// requests must send 'Authorization: Bearer <token>'
+    pub fn token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// the flash endpoint serves only the binaries from this directory
+    pub fn flash_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.flash_dir = Some(dir.into());
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
+    /// keeps the registry up to date - new devices are added to the device cache.
+    ///
+    /// every round probes '/zeroconf/info' of the known devices for the reachability.
+    pub fn discover(&self, service_name: &str, interval: Duration) -> thread::JoinHandle<()> {
+        let registry = self.registry.clone();
+        let device_cache = Arc::clone(&self.device_cache);
+        let keys = self.keys.clone();
+        let scanner = Scanner::new(service_name.to_string());
+        thread::spawn(move || loop {
+            match scanner.announcements(DISCOVERY_WINDOW) {
+                Ok(devices) => {
+                    for mut device in devices {
+                        if let Some(ref keys) = keys {
+                            keys.assign_keys(std::slice::from_mut(&mut device));
+                        }
+                        if registry.announced(device.clone()) {
+                            println!("new device: {}", device);
+                            if let Err(err) = device_cache.lock().unwrap().add(&device) {
+                                eprintln!("unable to update the device cache: {}", err);
+                            }
+                        }
+                    }
+                }
+                Err(err) => eprintln!("discovery failed: {}", err),
+            }
+            let probes = fanout::fan_out(registry.devices(), PARALLEL_PROBES, |device| {
+                device.device_info()
+            });
+            for (device, info) in probes {
+                probed(&registry, &device, info);
+            }
+            thread::sleep(interval);
+        })
+    }
+
+    /// fails for a non-loopback address without a token
+    pub fn start(self) -> Result<thread::JoinHandle<()>> {
+        if !self.addr.ip().is_loopback() && self.token.is_none() {
+            return Err(Error::GenericError {
+                msg: format!(
+                    "refuse to serve the api at the non-loopback address {} without a token",
+                    self.addr
+                ),
+            });
+        }
+        let listener = self.listener.try_clone()?;
+        let daemon = Arc::new(self);
+        Ok(thread::spawn(move || {
+            http::serve(listener, httpd::DEFAULT_WORKERS, None, move |stream| {
+                daemon.handle_request(stream)
+            })
+        }))
+    }
+
+    /// returns `true` if the connection should be kept alive
+    fn handle_request(&self, stream: &mut TcpStream) -> bool {
+        let (method, path, headers, body) = match http::read_request(stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => return false,
+            Err(err) => {
+                debug!("invalid request: {}", err);
+                return false;
+            }
+        };
+        debug!("{} {}", method, path);
+
+        let (status, content_type, body) = if !self.authorized(&headers) {
+            let body = json!({ "error": "missing or invalid token" });
+            ("401 Unauthorized", "application/json", body.to_string())
+        } else if let Some(msg) = self.cross_site(&method, &headers) {
+            let body = json!({ "error": msg });
+            ("403 Forbidden", "application/json", body.to_string())
+        } else if method == "GET" && path.starts_with("/metrics") {
+            let metrics = metrics::render(&self.registry.entries(), now());
+            ("200 OK", METRICS_CONTENT_TYPE, metrics)
+        } else {
+            let (status, body) = match self.route(&method, &path, &body) {
+                Ok((status, body)) => (status, body),
+                Err(err) => error_response(err),
+            };
+            (status, "application/json", body.to_string())
+        };
+        match http::write_response(stream, status, content_type, body.as_bytes()) {
+            Ok(_) => true,
+            Err(err) => {
+                debug!("unable to respond: {}", err);
+                false
+            }
+        }
+    }
+
+    fn authorized(&self, headers: &http::Headers) -> bool {
+        match self.token {
+            Some(ref token) => headers
+                .get("authorization")
+                .map(|value| {
+                    crypto::constant_time_eq(
+                        value.trim().as_bytes(),
+                        format!("Bearer {}", token).as_bytes(),
+                    )
+                })
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// requests which a web page in the browser of the user could send
+    ///
+    /// a foreign origin, a foreign host name (dns rebinding) without a token or a post
+    /// which a html form can send - json posts need a cors preflight, which isn't answered.
+    fn cross_site(&self, method: &str, headers: &http::Headers) -> Option<&'static str> {
+        if let Some(origin) = headers.get("origin") {
+            let host = origin
+                .trim()
+                .trim_start_matches("http://")
+                .trim_start_matches("https://");
+            if !is_loopback_host(host) {
+                return Some("requests from other origins are not allowed");
+            }
+        }
+        let loopback = headers
+            .get("host")
+            .map(|host| is_loopback_host(host))
+            .unwrap_or(false);
+        if self.token.is_none() && !loopback {
+            return Some("the host must be a loopback address");
+        }
+        let json = headers
+            .get("content-type")
+            .and_then(|value| value.split(';').next())
+            .map(|t| t.trim().eq_ignore_ascii_case("application/json"))
+            .unwrap_or(false);
+        if method == "POST" && !json {
+            return Some("the content type must be 'application/json'");
+        }
+        None
+    }
+
+    fn route(&self, method: &str, path: &str, body: &str) -> Result<(&'static str, Value)> {
+        let path = path.split('?').next().unwrap_or("");
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        let ok = |msg: String| -> Result<(&'static str, Value)> {
+            Ok(("200 OK", serde_json::from_str(&msg)?))
+        };
+        match (method, segments.as_slice()) {
+            ("GET", ["devices"]) => Ok(("200 OK", self.registry.to_json_list())),
+            ("GET", ["devices", id]) => Ok(("200 OK", self.registry.to_json(id)?)),
+            ("GET", ["devices", id, "info"]) => {
+                let info = self.registry.get(id)?.device_info()?;
+                Ok(("200 OK", serde_json::to_value(info)?))
+            }
+            ("POST", ["devices", id, "switch"]) => {
+                let device = self.registry.get(id)?;
+                let res = device.switch(param(body, "state")?.parse()?)?;
+                probed(&self.registry, &device, device.device_info());
+                ok(res)
+            }
+            ("POST", ["devices", id, "startup"]) => {
+                let device = self.registry.get(id)?;
+                let res = device.startup(param(body, "state")?.parse()?)?;
+                probed(&self.registry, &device, device.device_info());
+                ok(res)
+            }
+            ("POST", ["devices", id, "pulse"]) => {
+                let device = self.registry.get(id)?;
+                let state = match param(body, "state")?.as_str() {
+                    "on" => PulseState::On {
+                        width: match optional_param(body, "width")? {
+                            Some(width) => width.parse().map_err(|_| Error::InvalidRequest {
+                                msg: format!("invalid width: '{}'", width),
+                            })?,
+                            None => device::PULSE_WIDTH_MIN,
+                        },
+                    },
+                    "off" => PulseState::Off,
+                    state => {
+                        return Err(Error::InvalidRequest {
+                            msg: format!("invalid state: '{}'", state),
+                        })
+                    }
+                };
+                let res = device.pulse(state)?;
+                probed(&self.registry, &device, device.device_info());
+                ok(res)
+            }
+            ("POST", ["devices", id, "flash"]) => {
+                let device = self.registry.get(id)?;
+                let bin = self.flash_binary(&param(body, "bin")?)?;
+                bin.validate()?;
+                self.flash(device, bin)?;
+                Ok(("202 Accepted", json!({ "flash": FlashJob::Running })))
+            }
+            _ => Ok((
+                "404 Not Found",
+                json!({ "error": format!("unknown endpoint: {} {}", method, path) }),
+            )),
+        }
+    }
+
+    /// the binary with the given file name from the flash directory
+    fn flash_binary(&self, name: &str) -> Result<Binary> {
+        let dir = self
+            .flash_dir
+            .as_ref()
+            .ok_or_else(|| Error::InvalidRequest {
+                msg: "flashing is disabled - no flash directory configured".into(),
+            })?;
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => {
+                return Err(Error::InvalidRequest {
+                    msg: format!("'{}' is not a file name in the flash directory", name),
+                })
+            }
+        }
+        let bin = Binary::new(dir.join(name))?;
+        // a symlink must not point outside of the directory
+        if !dir
+            .join(name)
+            .canonicalize()?
+            .starts_with(dir.canonicalize()?)
+        {
+            return Err(Error::InvalidRequest {
+                msg: format!("'{}' is not a file name in the flash directory", name),
+            });
+        }
+        Ok(bin)
+    }
+
+    /// flashes the device in the background - one flash per device at a time
+    fn flash(&self, device: Device, bin: Binary) -> Result<()> {
+        self.registry.start_flash(&device.id)?;
+        let (httpd, batch) = match self.serve_binary(&device, &bin) {
+            Ok(started) => started,
+            Err(err) => {
+                self.registry.set_flash(
+                    &device.id,
+                    FlashJob::Failed {
+                        error: err.to_string(),
+                    },
+                );
+                return Err(err);
+            }
+        };
+
+        let registry = self.registry.clone();
+        thread::spawn(move || {
+            let job = match batch.flash(&device) {
+                Ok(msg) => FlashJob::Done { msg },
+                Err(err) => FlashJob::Failed {
+                    error: err.to_string(),
+                },
+            };
+            httpd.shutdown();
+            println!("flash {}: {:?}", device.id, job);
+            registry.set_flash(&device.id, job);
+        });
+        Ok(())
+    }
+
+    /// starts a web-server for the binary - stop it with the returned handle
+    fn serve_binary(&self, device: &Device, bin: &Binary) -> Result<(http::Shutdown, BatchFlash)> {
+        let httpd_ip = netutils::matching_host_ip_for(&device.ip)?;
+        let httpd = Httpd::new(&httpd_ip, 0, bin)?.verbose(false);
+        let transfers = httpd.transfers();
+        let shutdown = httpd.shutdown_handle();
+        let (bin_endpoint, _) = httpd.start();
+        let batch = BatchFlash::new(bin_endpoint, bin.sha256sum(), transfers, self.flash_timeout);
+        Ok((shutdown, batch))
+    }
+}
+
+/// records the info probe - the metrics show the state from it
+fn probed(registry: &Registry, device: &Device, info: Result<DeviceInfo>) {
+    let info = info
+        .map_err(|err| debug!("{} not reachable: {}", device.id, err))
+        .ok();
+    registry.probed(&device.id, info.as_ref());
+}
+
+/// the string value of the field in the json request body
+/// 'localhost' or a loopback ip - with an optional port
+fn is_loopback_host(host: &str) -> bool {
+    let host = host.trim();
+    // '[::1]:8990' or '127.0.0.1:8990'
+    let name = match host.find(']') {
+        Some(end) => host[..end].trim_start_matches('['),
+        None => host.split(':').next().unwrap_or(""),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(false)
+}
+
+fn param(body: &str, name: &str) -> Result<String> {
+    optional_param(body, name)?.ok_or(Error::InvalidRequest {
+        msg: format!("'{}' is missing", name),
+    })
+}
+
+fn optional_param(body: &str, name: &str) -> Result<Option<String>> {
+    let body: Value = serde_json::from_str(body).map_err(|_| Error::InvalidRequest {
+        msg: "the body must be a json object".into(),
+    })?;
+    match body.get(name) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(Value::Number(n)) => Ok(Some(n.to_string())),
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => Err(Error::InvalidRequest {
+            msg: format!("invalid {}: {}", name, value),
+        }),
+    }
+}
+
+fn error_response(err: Error) -> (&'static str, Value) {
+    let status = match err {
+        Error::DeviceNotFound { .. } => "404 Not Found",
+        Error::DeviceBusy { .. } => "409 Conflict",
+        Error::InvalidRequest { .. } | Error::InvalidBinary { .. } | Error::GenericError { .. } => {
+            "400 Bad Request"
+        }
+        Error::DeviceError { .. } | Error::ReqwestError { .. } => "502 Bad Gateway",
+        _ => "500 Internal Server Error",
+    };
+    let body = match err {
+        Error::DeviceError { code } => json!({ "error": err.to_string(), "code": code }),
+        _ => json!({ "error": err.to_string() }),
+    };
+    (status, body)
+}
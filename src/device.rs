@@ -2,7 +2,22 @@ use crate::*;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, json, to_string_pretty, Value};
-use std::{fmt, net::IpAddr};
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// pulse width limits in ms from the sonoff diy api documentation
+pub const PULSE_WIDTH_MIN: u32 = 500;
+pub const PULSE_WIDTH_MAX: u32 = 36_000_000;
+
+/// timeout for a single request to the device
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// read-only requests are retried when the connection fails
+const READ_ONLY_RETRIES: usize = 2;
+const READ_ONLY_ENDPOINTS: &[&str] = &["info", "signal_strength"];
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Device {
@@ -11,6 +26,16 @@ pub struct Device {
     pub desc: Vec<String>,
     pub ip: IpAddr,
     pub port: u16,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// the device expects encrypted requests - txt: 'encrypt=true'
+    #[serde(default)]
+    pub encrypt: bool,
+    /// the device key - needed for encrypted devices - from the key store
+    ///
+    /// older caches stored the key - it's read to move it in the key store
+    #[serde(default, skip_serializing)]
+    pub key: Option<String>,
 }
 
 impl Device {
@@ -21,17 +46,108 @@ impl Device {
             desc: desc.to_vec(),
             ip,
             port,
+            tags: Vec::new(),
+            encrypt: desc.iter().any(|s| s == "encrypt=true"),
+            key: None,
+        }
+    }
+
+    /// the state from the txt record ('data1' - 'data4') - decrypted for encrypted devices
+    pub fn txt_data(&self) -> Result<Value> {
+        let data = ["data1", "data2", "data3", "data4"]
+            .iter()
+            .filter_map(|name| self.txt_field(name))
+            .collect::<String>();
+        if data.is_empty() {
+            return Err(Error::JSONLookupError {
+                msg: "'data1' in txt record not found".to_string(),
+            });
+        }
+
+        if self.encrypt {
+            let iv = self.txt_field("iv").ok_or(Error::JSONLookupError {
+                msg: "'iv' in txt record not found".to_string(),
+            })?;
+            let data = crypto::decrypt(self.key()?, iv, &data)?;
+            Ok(serde_json::from_slice(&data)?)
+        } else {
+            Ok(from_str(&data)?)
         }
     }
 
+    /// the device type from the txt record, e.g. 'diy_plug'
+    pub fn device_type(&self) -> Option<&str> {
+        self.txt_field("type")
+    }
+
+    pub(crate) fn txt_field(&self, name: &str) -> Option<&str> {
+        self.desc.iter().find_map(|s| {
+            let mut iter = s.splitn(2, '=');
+            match (iter.next(), iter.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+    }
+
+    fn key(&self) -> Result<&str> {
+        self.key.as_deref().ok_or_else(|| Error::DeviceKeyMissing {
+            device_id: self.id.clone(),
+        })
+    }
+
+    /// creates the device from the info of the device at the given address
+    pub fn probe(ip: IpAddr, port: u16) -> Result<Self> {
+        let info = Device::new("", "", &[], ip, port).device_info()?;
+        let id = info.deviceid.ok_or(Error::JSONLookupError {
+            msg: "'deviceid' in response not found".to_string(),
+        })?;
+        Ok(Device::new(&format!("eWeLink_{}", id), &id, &[], ip, port))
+    }
+
     pub fn info(&self) -> Result<String> {
+        Ok(to_string_pretty(&self.info_data()?)?)
+    }
+
+    pub fn device_info(&self) -> Result<DeviceInfo> {
+        Ok(serde_json::from_value(self.info_data()?)?)
+    }
+
+    /// the wifi signal strength in dbm
+    pub fn signal_strength(&self) -> Result<i32> {
+        self.read_data("signal_strength")?
+            .get("signalStrength")
+            .and_then(Value::as_i64)
+            .map(|v| v as i32)
+            .ok_or(Error::JSONLookupError {
+                msg: "'signalStrength' in response not found".to_string(),
+            })
+    }
+
+    /// the sequence number of the device state - increases with every change and
+    /// when the device is back in the network after a wifi change
+    pub fn seq(&self) -> Result<u64> {
         let payload = json!({
             "deviceid": &self.id,
             "data": {},
         });
+        response_seq(&self.post("info", payload)?)
+    }
+
+    fn info_data(&self) -> Result<Value> {
+        self.read_data("info")
+    }
 
-        match self.post("info", payload)?.get("data") {
-            Some(Value::String(ref data)) => Ok(to_string_pretty(&from_str::<Value>(data)?)?),
+    fn read_data(&self, endpoint: &str) -> Result<Value> {
+        let payload = json!({
+            "deviceid": &self.id,
+            "data": {},
+        });
+
+        // older firmware sends 'data' as json encoded string
+        match self.post(endpoint, payload)?.get("data") {
+            Some(Value::String(ref data)) => Ok(from_str::<Value>(data)?),
+            Some(data @ Value::Object(_)) => Ok(data.clone()),
             _ => Err(Error::JSONLookupError {
                 msg: "'data' in response not found".to_string(),
             }),
@@ -39,18 +155,70 @@ impl Device {
     }
 
     pub fn switch(&self, state: SwitchState) -> Result<String> {
+        let payload = json!({
+            "deviceid": &self.id,
+            "data": {
+                "switch": switch_state(state),
+            },
+        });
+        self.post_("switch", payload)
+    }
+
+    /// switches the given outlets of a multi channel device
+    pub fn switches(&self, outlets: &[(u8, SwitchState)]) -> Result<String> {
+        let switches = outlets
+            .iter()
+            .map(|(outlet, state)| json!({ "switch": switch_state(*state), "outlet": outlet }))
+            .collect::<Vec<_>>();
+
+        let payload = json!({
+            "deviceid": &self.id,
+            "data": {
+                "switches": switches,
+            },
+        });
+        self.post_("switches", payload)
+    }
+
+    /// the relay state after a power loss
+    pub fn startup(&self, state: StartupState) -> Result<String> {
         let state = match state {
-            SwitchState::On => "on",
-            SwitchState::Off => "off",
+            StartupState::On => "on",
+            StartupState::Off => "off",
+            StartupState::Stay => "stay",
         };
 
         let payload = json!({
             "deviceid": &self.id,
             "data": {
-                "switch": state,
+                "startup": state,
             },
         });
-        self.post_("switch", payload)
+        self.post_("startup", payload)
+    }
+
+    /// inching: the relay switches off after the pulse width
+    pub fn pulse(&self, state: PulseState) -> Result<String> {
+        let data = match state {
+            PulseState::On { width } => {
+                if !(PULSE_WIDTH_MIN..=PULSE_WIDTH_MAX).contains(&width) || width % 500 != 0 {
+                    return Err(Error::GenericError {
+                        msg: format!(
+                            "the pulse width must be a multiple of 500 in {} - {} ms",
+                            PULSE_WIDTH_MIN, PULSE_WIDTH_MAX
+                        ),
+                    });
+                }
+                json!({ "pulse": "on", "pulseWidth": width })
+            }
+            PulseState::Off => json!({ "pulse": "off" }),
+        };
+
+        let payload = json!({
+            "deviceid": &self.id,
+            "data": data,
+        });
+        self.post_("pulse", payload)
     }
 
     pub fn unlock(&self) -> Result<String> {
@@ -66,11 +234,13 @@ impl Device {
     where
         S: Into<String>,
     {
+        let (ssid, pwd) = (ssid.into(), pwd.into());
+        wifi::validate(&ssid, &pwd)?;
         let payload = json!({
             "deviceid": &self.id,
             "data": {
-                "ssid": ssid.into(),
-                "password": pwd.into(),
+                "ssid": ssid,
+                "password": pwd,
             },
         });
 
@@ -96,21 +266,91 @@ impl Device {
     where
         S: Into<String>,
     {
-        let client = reqwest::Client::new();
-        let url = format!("http://{}:{}/zeroconf/{}", self.ip, self.port, p.into());
+        let endpoint = p.into();
+        // state changing requests are recorded in the audit log
+        let audited = if READ_ONLY_ENDPOINTS.contains(&endpoint.as_str()) {
+            None
+        } else {
+            Some(payload.clone())
+        };
+        let start = Instant::now();
+        let res = self.request(&endpoint, payload);
+        metrics::observe_request(&endpoint, start.elapsed(), res.as_ref().err());
+        if let Some(payload) = audited {
+            audit::record(&self.id, &endpoint, &payload, &res);
+        }
+        res
+    }
+
+    fn request(&self, endpoint: &str, payload: Value) -> Result<Value> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let url = format!("http://{}:{}/zeroconf/{}", self.ip, self.port, endpoint);
         debug!(
             "post to: {} with payload: {:?}",
             url,
             to_string_pretty(&payload)?
         );
-        let res = client
-            .post(&url)
-            .json(&payload)
-            .send()?
-            .json()
-            .map_err(|e| e.into());
+        let payload = if self.encrypt {
+            self.encrypt_payload(payload)?
+        } else {
+            payload
+        };
+        let retries = if READ_ONLY_ENDPOINTS.contains(&endpoint) {
+            READ_ONLY_RETRIES
+        } else {
+            0
+        };
+        let mut attempt = 0;
+        let res: Value = loop {
+            match client.post(&url).json(&payload).send() {
+                Ok(mut resp) => break resp.json()?,
+                Err(err) if attempt < retries && !err.is_timeout() => {
+                    debug!("request failed - retry: {}", err);
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
         debug!("response: {:#?}", res);
-        res
+        // the devices answer a rejected request with http 200 and a non-zero 'error'.
+        // without this check a rejected 'switch' or 'wifi' would print the error
+        // response and exit with 0 - and batch runs would count it as success.
+        match res.get("error").and_then(Value::as_i64) {
+            Some(0) | None if self.encrypt => self.decrypt_response(res),
+            Some(0) | None => Ok(res),
+            Some(code) => Err(Error::DeviceError { code }),
+        }
+    }
+
+    /// wraps the 'data' part from the payload in an encrypted request
+    fn encrypt_payload(&self, payload: Value) -> Result<Value> {
+        let data = payload.get("data").cloned().unwrap_or_else(|| json!({}));
+        let iv = crypto::random_iv();
+        let sequence = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        Ok(json!({
+            "sequence": sequence.to_string(),
+            "deviceid": &self.id,
+            "selfApikey": "123",
+            "iv": base64::encode(iv),
+            "encrypt": true,
+            "data": crypto::encrypt(self.key()?, &iv, data.to_string().as_bytes())?,
+        }))
+    }
+
+    fn decrypt_response(&self, mut res: Value) -> Result<Value> {
+        let data = match (res.get("iv"), res.get("data")) {
+            (Some(Value::String(iv)), Some(Value::String(data))) => {
+                crypto::decrypt(self.key()?, iv, data)?
+            }
+            _ => return Ok(res),
+        };
+        res["data"] = serde_json::from_slice(&data)?;
+        Ok(res)
     }
 
     fn post_<S>(&self, p: S, payload: Value) -> Result<String>
@@ -122,12 +362,33 @@ impl Device {
     }
 }
 
+/// the 'seq' from a device response
+pub(crate) fn response_seq(response: &Value) -> Result<u64> {
+    response
+        .get("seq")
+        .and_then(Value::as_u64)
+        .ok_or(Error::JSONLookupError {
+            msg: "'seq' in response not found".to_string(),
+        })
+}
+
+fn switch_state(state: SwitchState) -> &'static str {
+    match state {
+        SwitchState::On => "on",
+        SwitchState::Off => "off",
+    }
+}
+
 impl fmt::Display for Device {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "device-name: {}, id: {}, ip: {}",
             self.name, self.id, self.ip
-        )
+        )?;
+        if !self.tags.is_empty() {
+            write!(f, ", tags: {}", self.tags.join(","))?;
+        }
+        Ok(())
     }
 }
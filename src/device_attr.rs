@@ -125,6 +125,32 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(feature = "emulator")]
+    fn announcement_without_id_is_ignored() {
+        let announce = |missing_txt: Vec<String>| {
+            let mut state = crate::emulator::EmulatorState::new("1000000107");
+            state.missing_txt = missing_txt;
+            let txt = state.txt_records();
+            let mut attrs = DeviceAttributes::default();
+            attrs.add(&mk_a_record(
+                "eWeLink_1000000107.local",
+                Ipv4Addr::new(127, 0, 0, 1),
+            ));
+            attrs.add(&mk_srv_record(
+                "eWeLink_1000000107._ewelink._tcp.local",
+                8081,
+            ));
+            attrs.add(&mk_txt_record(
+                "eWeLink_1000000107._ewelink._tcp.local",
+                txt.iter().map(String::as_str).collect(),
+            ))
+        };
+
+        assert_eq!(announce(Vec::new()).unwrap().id, "1000000107");
+        assert_eq!(announce(vec!["id".into()]), None);
+    }
+
     fn mk_txt_record(name: &str, v: Vec<&str>) -> Record {
         Record {
             name: name.to_string(),
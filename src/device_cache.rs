@@ -1,11 +1,7 @@
 use crate::*;
 use log::debug;
 
-use std::{
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
-};
+use std::{fs::File, io::BufReader, path::PathBuf, str::FromStr};
 
 pub struct DeviceCache(Vec<Device>, PathBuf);
 
@@ -16,6 +12,14 @@ impl DeviceCache {
         Self(devices, path)
     }
 
+    pub fn path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.1 = path.into();
+        self
+    }
+
     pub fn add(&mut self, device: &Device) -> Result<()> {
         if self.0.iter().find(|d| d.id == device.id).is_none() {
             debug!("add device to cache: {}", device);
@@ -43,6 +47,71 @@ impl DeviceCache {
             .ok_or(Error::DeviceNotFound { device_id })
     }
 
+    /// updates the address and txt record from a device which changed the network
+    pub fn update(&mut self, device: &Device) -> Result<()> {
+        match self.0.iter_mut().find(|d| d.id == device.id) {
+            Some(cached) => {
+                debug!("update device in cache: {}", device);
+                cached.ip = device.ip;
+                cached.port = device.port;
+                cached.desc = device.desc.clone();
+                cached.encrypt = device.encrypt;
+                self.save()
+            }
+            None => self.add(device),
+        }
+    }
+
+    /// resolves the selected devices
+    pub fn select(&self, targets: &Targets) -> Result<Vec<Device>> {
+        if targets.all {
+            Ok(self.devices())
+        } else if let Some(ref tag) = targets.tag {
+            Ok(self
+                .0
+                .iter()
+                .filter(|d| d.tags.contains(tag))
+                .cloned()
+                .collect())
+        } else if let Some(ref filter) = targets.filter {
+            Ok(self
+                .0
+                .iter()
+                .filter(|d| filter.matches(d))
+                .cloned()
+                .collect())
+        } else {
+            targets
+                .device_id
+                .iter()
+                .map(|id| self.lookup(id.as_str()))
+                .collect()
+        }
+    }
+
+    pub fn tag(&mut self, device_id: &str, tags: &[String], remove: bool) -> Result<Device> {
+        let device = self
+            .0
+            .iter_mut()
+            .find(|d| d.id == device_id)
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.to_string(),
+            })?;
+
+        if remove {
+            device.tags.retain(|t| !tags.contains(t));
+        } else {
+            for tag in tags {
+                if !device.tags.contains(tag) {
+                    device.tags.push(tag.clone());
+                }
+            }
+        }
+        let device = device.clone();
+        self.save()?;
+        Ok(device)
+    }
+
     pub fn load() -> Result<Self> {
         let mut cache = DeviceCache::new(Vec::new());
         debug!("load cache from {}", cache.1.display());
@@ -53,11 +122,10 @@ impl DeviceCache {
         Ok(cache)
     }
 
+    /// replaces the file - the daemon saves it while other commands read it
     pub fn save(&self) -> Result<()> {
         debug!("save cache to: {}", self.1.display());
-        let file = File::create(&self.1)?;
-        let writer = BufWriter::new(file);
-        Ok(serde_json::to_writer(writer, &self.0)?)
+        fsutils::write_atomic(&self.1, &serde_json::to_vec(&self.0)?, false)
     }
 }
 
@@ -66,3 +134,115 @@ impl Default for DeviceCache {
         Self::new(Vec::new())
     }
 }
+
+/// selects devices by their attributes, e.g. 'type=diy_plug,tag!=outdoor,name=kitchen*'
+///
+/// all terms must match - the values can contain '*' as wildcard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter(Vec<FilterTerm>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct FilterTerm {
+    field: String,
+    value: String,
+    negate: bool,
+}
+
+impl Filter {
+    pub fn matches(&self, device: &Device) -> bool {
+        self.0.iter().all(|term| {
+            let values = match term.field.as_str() {
+                "id" => vec![device.id.clone()],
+                "name" => vec![device.name.clone()],
+                "ip" => vec![device.ip.to_string()],
+                "type" => device.device_type().map(String::from).into_iter().collect(),
+                "tag" => device.tags.clone(),
+                _ => unreachable!("checked by the parser"),
+            };
+            values.iter().any(|v| glob_match(&term.value, v)) != term.negate
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let terms = s
+            .split(',')
+            .map(|term| {
+                let (field, value, negate) = match term.find("!=") {
+                    Some(idx) => (&term[..idx], &term[idx + 2..], true),
+                    None => match term.find('=') {
+                        Some(idx) => (&term[..idx], &term[idx + 1..], false),
+                        None => ("", "", false),
+                    },
+                };
+                let field = field.trim();
+                if !["id", "name", "ip", "type", "tag"].contains(&field) {
+                    return Err(Error::InvalidRequest {
+                        msg: format!(
+                            "invalid filter term '{}' - expected '<id|name|ip|type|tag>=<value>' or '!='",
+                            term
+                        ),
+                    });
+                }
+                Ok(FilterTerm {
+                    field: field.to_string(),
+                    value: value.trim().to_string(),
+                    negate,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Filter(terms))
+    }
+}
+
+/// matches the value against the pattern - '*' matches any characters
+fn glob_match(pattern: &str, value: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == value,
+        Some(idx) => {
+            let (prefix, rest) = (&pattern[..idx], &pattern[idx + 1..]);
+            value.starts_with(prefix)
+                && (prefix.len()..=value.len())
+                    .filter(|i| value.is_char_boundary(*i))
+                    .any(|i| glob_match(rest, &value[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_devices() {
+        let mut kitchen = Device::new(
+            "kitchen light",
+            "1000000001",
+            &["type=diy_plug".to_string()],
+            "192.168.1.10".parse().unwrap(),
+            8081,
+        );
+        kitchen.tags = vec!["indoor".into()];
+        let garden = Device::new(
+            "garden",
+            "1000000002",
+            &[],
+            "192.168.1.11".parse().unwrap(),
+            8081,
+        );
+
+        let filter = |s: &str| s.parse::<Filter>().unwrap();
+        assert!(filter("type=diy_plug").matches(&kitchen));
+        assert!(!filter("type=diy_plug").matches(&garden));
+        assert!(filter("name=kitchen*,tag=indoor").matches(&kitchen));
+        assert!(filter("tag!=indoor").matches(&garden));
+        assert!(!filter("tag!=indoor").matches(&kitchen));
+        assert!(filter("ip=192.168.1.*").matches(&garden));
+        assert!(filter("id=*02").matches(&garden));
+        assert!("color=red".parse::<Filter>().is_err());
+        assert!("type".parse::<Filter>().is_err());
+    }
+}
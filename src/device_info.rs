@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+/// the 'data' part from the '/zeroconf/info' response
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub switch: Option<String>,
+    pub startup: Option<String>,
+    pub pulse: Option<String>,
+    pub pulse_width: Option<u32>,
+    pub ssid: Option<String>,
+    pub ota_unlock: Option<bool>,
+    pub fw_version: Option<String>,
+    pub deviceid: Option<String>,
+    pub bssid: Option<String>,
+    pub signal_strength: Option<i32>,
+    /// the outlets of multi channel devices
+    pub switches: Option<Vec<OutletState>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OutletState {
+    pub switch: String,
+    pub outlet: u8,
+}
+
+impl DeviceInfo {
+    pub fn is_unlocked(&self) -> bool {
+        self.ota_unlock.unwrap_or(false)
+    }
+
+    /// the relay is on - for multi channel devices the given outlet
+    pub fn is_on(&self, outlet: Option<u8>) -> Option<bool> {
+        match (outlet, &self.switches) {
+            (Some(outlet), Some(switches)) => switches
+                .iter()
+                .find(|s| s.outlet == outlet)
+                .map(|s| s.switch == "on"),
+            (Some(_), None) => None,
+            (None, _) => self.switch.as_ref().map(|s| s == "on"),
+        }
+    }
+}
@@ -0,0 +1,613 @@
+use crate::scenario::{RequestFaults, Scenario};
+use crate::*;
+use log::debug;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use serde_json::{json, Value};
+use std::{
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SERVICE_TYPE: &str = "_ewelink._tcp.local.";
+
+/// chunk size which is used by the real devices
+const DOWNLOAD_CHUNK_SIZE: usize = 4096;
+
+/// the device restarts the wifi after a change - it keeps its address
+const WIFI_RECONNECT: Duration = Duration::from_millis(500);
+
+/// the state of the emulated device
+#[derive(Debug, Clone)]
+pub struct EmulatorState {
+    pub id: String,
+    pub device_type: String,
+    pub switch: String,
+    pub startup: String,
+    pub pulse: String,
+    pub pulse_width: u32,
+    pub ssid: String,
+    pub password: String,
+    pub ota_unlock: bool,
+    pub fw_version: String,
+    pub signal_strength: i32,
+    pub seq: u64,
+    pub ota: OtaStatus,
+    /// the firmware from the last successful ota update
+    pub firmware: Option<Vec<u8>>,
+    /// txt fields which are not announced
+    pub missing_txt: Vec<String>,
+    /// the device key - the device expects encrypted requests if set
+    pub key: Option<String>,
+    /// the device is back in the network after a wifi change - the seq increases
+    pub reconnect_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtaStatus {
+    Idle,
+    Downloading { received: usize, total: usize },
+    Done,
+    Failed { code: i64 },
+}
+
+impl EmulatorState {
+    pub fn new(id: &str) -> Self {
+        EmulatorState {
+            id: id.to_string(),
+            device_type: "diy_plug".into(),
+            switch: "off".into(),
+            startup: "off".into(),
+            pulse: "off".into(),
+            pulse_width: 500,
+            ssid: "sonoffDiy".into(),
+            password: "20170618sn".into(),
+            ota_unlock: false,
+            fw_version: "3.3.0".into(),
+            signal_strength: -48,
+            seq: 1,
+            ota: OtaStatus::Idle,
+            firmware: None,
+            missing_txt: Vec::new(),
+            key: None,
+            reconnect_at: None,
+        }
+    }
+
+    /// the txt record which the device announces per mdns
+    pub fn txt_records(&self) -> Vec<String> {
+        let data1 = json!({
+            "switch": self.switch,
+            "startup": self.startup,
+            "pulse": self.pulse,
+            "pulseWidth": self.pulse_width,
+            "rssi": self.signal_strength,
+        });
+        let mut txt = vec![
+            ("txtvers", "1".to_string()),
+            ("id", self.id.clone()),
+            ("type", self.device_type.clone()),
+            ("apivers", "1".to_string()),
+            ("seq", self.seq.to_string()),
+        ];
+        match self.key {
+            Some(ref key) => {
+                // the encrypted state is split in chunks of max. 249 chars
+                let iv = crypto::random_iv();
+                let data = crypto::encrypt(key, &iv, data1.to_string().as_bytes())
+                    .expect("unable to encrypt the txt data");
+                txt.push(("encrypt", "true".to_string()));
+                txt.push(("iv", base64::encode(iv)));
+                for (idx, chunk) in data.as_bytes().chunks(249).enumerate() {
+                    let name = ["data1", "data2", "data3", "data4"][idx];
+                    txt.push((name, String::from_utf8_lossy(chunk).to_string()));
+                }
+            }
+            None => txt.push(("data1", data1.to_string())),
+        }
+        txt.into_iter()
+            .filter(|(key, _)| !self.missing_txt.iter().any(|m| m == key))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+
+    fn info(&self) -> Value {
+        json!({
+            "switch": self.switch,
+            "startup": self.startup,
+            "pulse": self.pulse,
+            "pulseWidth": self.pulse_width,
+            "ssid": self.ssid,
+            "otaUnlock": self.ota_unlock,
+            "fwVersion": self.fw_version,
+            "deviceid": self.id,
+            "bssid": "ec:1:d5:21:e2:37",
+            "signalStrength": self.signal_strength,
+        })
+    }
+}
+
+type SharedState = Arc<Mutex<EmulatorState>>;
+
+/// emulates a sonoff device in diy mode.
+///
+/// serves the '/zeroconf/*' endpoints, announces itself per mdns (optional)
+/// and downloads the firmware per range requests on 'ota_flash'.
+pub struct Emulator {
+    listener: TcpListener,
+    handler: Handler,
+}
+
+/// serves the requests - shared between the connections and the ota download
+#[derive(Clone)]
+struct Handler {
+    addr: Arc<Mutex<SocketAddr>>,
+    state: SharedState,
+    mdns: Arc<Mutex<Option<mdns_sd::ServiceDaemon>>>,
+    scenario: Arc<Scenario>,
+    faults: Arc<Mutex<RequestFaults>>,
+}
+
+impl Emulator {
+    /// binds the emulator - use port `0` to let the os pick a free port
+    pub fn new(ip: &IpAddr, port: u16, device_id: &str) -> Result<Self> {
+        let addr = SocketAddr::new(*ip, port);
+        let listener =
+            TcpListener::bind(addr).map_err(|source| Error::BindError { addr, source })?;
+        let addr = listener.local_addr()?;
+        debug!("emulator bound to {}", addr);
+        Ok(Emulator {
+            listener,
+            handler: Handler {
+                addr: Arc::new(Mutex::new(addr)),
+                state: Arc::new(Mutex::new(EmulatorState::new(device_id))),
+                mdns: Arc::new(Mutex::new(None)),
+                scenario: Arc::new(Scenario::default()),
+                faults: Arc::new(Mutex::new(RequestFaults::default())),
+            },
+        })
+    }
+
+    /// inject the faults from the given scenario
+    pub fn scenario(mut self, scenario: Scenario) -> Self {
+        self.handler.state.lock().unwrap().missing_txt = scenario.missing_txt.clone();
+        self.handler.faults = Arc::new(Mutex::new(RequestFaults::new(&scenario.requests)));
+        self.handler.scenario = Arc::new(scenario);
+        self
+    }
+
+    /// emulate an encrypted device (lan mode) with the given device key
+    pub fn key(self, key: &str) -> Self {
+        self.handler.state.lock().unwrap().key = Some(key.to_string());
+        self
+    }
+
+    /// the current address of the device - changes with 'ip_after_reboot'
+    pub fn local_addr(&self) -> SocketAddr {
+        *self.handler.addr.lock().unwrap()
+    }
+
+    /// handle to inspect / modify the state of the emulated device
+    pub fn state(&self) -> SharedState {
+        Arc::clone(&self.handler.state)
+    }
+
+    /// the emulated device as it would be found from the scanner
+    pub fn device(&self) -> Device {
+        self.handler.device()
+    }
+
+    /// announce the device per mdns as '_ewelink._tcp'
+    pub fn advertise(&self) -> Result<()> {
+        let responder = mdns_sd::ServiceDaemon::new().map_err(|err| Error::GenericError {
+            msg: format!("unable to start the mdns responder: {}", err),
+        })?;
+        *self.handler.mdns.lock().unwrap() = Some(responder);
+        self.handler.announce(&self.handler.state.lock().unwrap());
+        Ok(())
+    }
+
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let Emulator { listener, handler } = self;
+        thread::spawn(move || handler.serve(listener))
+    }
+}
+
+impl Handler {
+    fn device(&self) -> Device {
+        let addr = *self.addr.lock().unwrap();
+        let state = self.state.lock().unwrap();
+        let mut device = Device::new(
+            &format!("eWeLink_{}", state.id),
+            &state.id,
+            &state.txt_records(),
+            addr.ip(),
+            addr.port(),
+        );
+        device.key = state.key.clone();
+        device
+    }
+
+    fn serve(self, listener: TcpListener) {
+        http::serve(listener, httpd::DEFAULT_WORKERS, None, move |stream| {
+            self.handle_connection(stream)
+        });
+    }
+
+    /// returns `false` if the connection should be closed
+    fn handle_connection(&self, stream: &mut TcpStream) -> bool {
+        // the device is gone from this address after an ip change
+        let addr = *self.addr.lock().unwrap();
+        if stream
+            .local_addr()
+            .map(|a| a.ip() != addr.ip())
+            .unwrap_or(true)
+        {
+            debug!("drop connection to the old address");
+            return false;
+        }
+
+        match http::read_request(stream) {
+            Ok(Some((method, path, _, body))) => {
+                match self.handle_request(stream, &method, &path, &body) {
+                    Ok(keep_alive) => keep_alive,
+                    Err(err) => {
+                        debug!("unable to handle request: {}", err);
+                        false
+                    }
+                }
+            }
+            Ok(None) => false,
+            Err(err) => {
+                debug!("unable to read request: {}", err);
+                false
+            }
+        }
+    }
+
+    /// returns `false` if the connection should be closed
+    fn handle_request(
+        &self,
+        stream: &mut TcpStream,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<bool> {
+        debug!("emulator request: {} {} - {}", method, path, body);
+        const PREFIX: &str = "/zeroconf/";
+        if method != "POST" || !path.starts_with(PREFIX) {
+            http::write_response(stream, "404 Not Found", "text/plain", b"")?;
+            return Ok(true);
+        }
+        let endpoint = &path[PREFIX.len()..];
+
+        let fault = self.faults.lock().unwrap().next(endpoint);
+        if let Some(fault) = fault {
+            debug!("inject fault: {:?}", fault);
+            thread::sleep(Duration::from_millis(fault.delay_ms));
+            if fault.drop_connection {
+                return Ok(false);
+            }
+            if let Some(code) = fault.error {
+                let seq = self.state.lock().unwrap().seq;
+                respond(stream, json!({ "seq": seq, "error": code }))?;
+                return Ok(true);
+            }
+        }
+
+        let key = self.state.lock().unwrap().key.clone();
+        let response = match (serde_json::from_str(body), key) {
+            (Ok(payload), None) => self.dispatch(endpoint, &payload),
+            (Ok(payload), Some(key)) => match decrypt_request(&key, payload) {
+                Ok(payload) => encrypt_response(&key, self.dispatch(endpoint, &payload))?,
+                Err(code) => json!({ "seq": 0, "error": code }),
+            },
+            (Err(_), _) => json!({ "seq": 0, "error": 400 }),
+        };
+        respond(stream, response)?;
+        Ok(true)
+    }
+
+    fn dispatch(&self, endpoint: &str, payload: &Value) -> Value {
+        let mut state = self.state.lock().unwrap();
+        if state.reconnect_at.map(|at| at <= Instant::now()) == Some(true) {
+            debug!("reconnected after the wifi change");
+            state.reconnect_at = None;
+            state.seq += 1;
+            self.announce(&state);
+        }
+        let device_id = payload.get("deviceid").and_then(Value::as_str);
+        if device_id.map(|id| !id.is_empty() && id != state.id) == Some(true) {
+            return json!({ "seq": state.seq, "error": 404 });
+        }
+
+        let data = payload.get("data").cloned().unwrap_or(Value::Null);
+        let res = match endpoint {
+            "info" => {
+                // 'data' is a json encoded string like in the firmware 3.3.x
+                let data = state.info().to_string();
+                return json!({ "seq": state.seq, "error": 0, "data": data });
+            }
+            "signal_strength" => {
+                return json!({
+                    "seq": state.seq,
+                    "error": 0,
+                    "data": { "signalStrength": state.signal_strength },
+                });
+            }
+            "switch" => on_off(&data, "switch").map(|v| state.switch = v),
+            "startup" => match data.get("startup").and_then(Value::as_str) {
+                Some(v @ "on") | Some(v @ "off") | Some(v @ "stay") => {
+                    state.startup = v.to_string();
+                    Ok(())
+                }
+                _ => Err(422),
+            },
+            "pulse" => on_off(&data, "pulse").and_then(|pulse| {
+                let width = data
+                    .get("pulseWidth")
+                    .and_then(Value::as_u64)
+                    .unwrap_or_else(|| state.pulse_width.into());
+                if !(500..=36_000_000).contains(&width) || width % 500 != 0 {
+                    return Err(422);
+                }
+                state.pulse = pulse;
+                state.pulse_width = width as u32;
+                Ok(())
+            }),
+            "wifi" => match (
+                data.get("ssid").and_then(Value::as_str),
+                data.get("password").and_then(Value::as_str),
+            ) {
+                (Some(ssid), Some(password)) => {
+                    state.ssid = ssid.to_string();
+                    state.password = password.to_string();
+                    state.reconnect_at = Some(Instant::now() + WIFI_RECONNECT);
+                    Ok(())
+                }
+                _ => Err(422),
+            },
+            "ota_unlock" => {
+                state.ota_unlock = true;
+                Ok(())
+            }
+            "ota_flash" => self.ota_flash(&mut state, &data),
+            _ => Err(400),
+        };
+
+        match res {
+            Ok(()) => {
+                state.seq += 1;
+                self.announce(&state);
+                json!({ "seq": state.seq, "error": 0 })
+            }
+            Err(code) => json!({ "seq": state.seq, "error": code }),
+        }
+    }
+
+    fn ota_flash(&self, state: &mut EmulatorState, data: &Value) -> std::result::Result<(), i64> {
+        if !state.ota_unlock {
+            return Err(403);
+        }
+        if let OtaStatus::Downloading { .. } = state.ota {
+            return Err(422);
+        }
+        let (url, sha256sum) = match (
+            data.get("downloadUrl").and_then(Value::as_str),
+            data.get("sha256sum").and_then(Value::as_str),
+        ) {
+            (Some(url), Some(sha256sum)) => (url.to_string(), sha256sum.to_string()),
+            _ => return Err(422),
+        };
+
+        state.ota = OtaStatus::Downloading {
+            received: 0,
+            total: 0,
+        };
+        let handler = self.clone();
+        thread::spawn(move || handler.ota_download(&url, &sha256sum));
+        Ok(())
+    }
+
+    /// downloads the firmware in chunks like the real device and posts the result
+    fn ota_download(&self, url: &str, sha256sum: &str) {
+        let client = reqwest::Client::new();
+        let code = match self.download(&client, url) {
+            Ok(firmware) => {
+                let actual = hmac_sha256::Hash::hash(&firmware)
+                    .iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<String>();
+                if actual == sha256sum && !self.scenario.download.wrong_sha256 {
+                    self.state.lock().unwrap().firmware = Some(firmware);
+                    0
+                } else {
+                    debug!(
+                        "sha256sum mismatch - expected: {}, actual: {}",
+                        sha256sum, actual
+                    );
+                    471
+                }
+            }
+            Err(err) => {
+                debug!("firmware download failed: {}", err);
+                424
+            }
+        };
+
+        self.state.lock().unwrap().ota = if code == 0 {
+            OtaStatus::Done
+        } else {
+            OtaStatus::Failed { code }
+        };
+        if let Err(err) = client.post(url).json(&json!({ "error": code })).send() {
+            debug!("unable to post the ota result: {}", err);
+        }
+
+        if let (0, Some(ip)) = (code, self.scenario.ip_after_reboot) {
+            if let Err(err) = self.change_ip(ip) {
+                eprintln!("unable to change the ip: {}", err);
+            }
+        }
+    }
+
+    fn download(&self, client: &reqwest::Client, url: &str) -> Result<Vec<u8>> {
+        let faults = &self.scenario.download;
+        let mut firmware = Vec::new();
+        let mut total = None;
+        let mut chunk = 0;
+        while total.map(|total| firmware.len() < total).unwrap_or(true) {
+            if faults.abort_at_chunk == Some(chunk) {
+                return Err(Error::GenericError {
+                    msg: format!("download aborted at chunk {}", chunk),
+                });
+            }
+            if faults.stall_at_chunk == Some(chunk) {
+                thread::sleep(Duration::from_millis(faults.stall_ms));
+            }
+
+            let from = firmware.len();
+            let range = format!("bytes={}-{}", from, from + DOWNLOAD_CHUNK_SIZE - 1);
+            if faults.repeat_chunk == Some(chunk) {
+                let mut discard = Vec::new();
+                client
+                    .get(url)
+                    .header(RANGE, range.as_str())
+                    .send()?
+                    .copy_to(&mut discard)?;
+            }
+            let mut resp = client
+                .get(url)
+                .header(RANGE, range)
+                .send()?
+                .error_for_status()?;
+
+            // content-range: bytes <from>-<to>/<total>
+            let content_range = resp
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit('/').next())
+                .and_then(|v| v.parse().ok())
+                .ok_or(Error::InvalidRequest {
+                    msg: "invalid content-range header".into(),
+                })?;
+            total = Some(content_range);
+
+            let before = firmware.len();
+            resp.copy_to(&mut firmware)?;
+            if firmware.len() == before {
+                return Err(Error::InvalidRequest {
+                    msg: "empty chunk".into(),
+                });
+            }
+            self.state.lock().unwrap().ota = OtaStatus::Downloading {
+                received: firmware.len(),
+                total: content_range,
+            };
+            chunk += 1;
+        }
+        Ok(firmware)
+    }
+
+    /// emulates a new dhcp lease after the reboot
+    fn change_ip(&self, ip: IpAddr) -> Result<()> {
+        let addr = SocketAddr::new(ip, self.addr.lock().unwrap().port());
+        let listener =
+            TcpListener::bind(addr).map_err(|source| Error::BindError { addr, source })?;
+        debug!("emulator changed the address to {}", addr);
+        *self.addr.lock().unwrap() = addr;
+
+        let handler = self.clone();
+        thread::spawn(move || handler.serve(listener));
+        Ok(())
+    }
+
+    /// (re-)registers the mdns service with the current txt record
+    fn announce(&self, state: &EmulatorState) {
+        if let Some(ref responder) = *self.mdns.lock().unwrap() {
+            let txt = state
+                .txt_records()
+                .iter()
+                .map(|record| {
+                    let mut kv = record.splitn(2, '=');
+                    let key = kv.next().unwrap_or_default().to_string();
+                    (key, kv.next().unwrap_or_default().to_string())
+                })
+                .collect::<Vec<_>>();
+            let addr = *self.addr.lock().unwrap();
+            let name = format!("eWeLink_{}", state.id);
+            // announce all host addresses if the emulator listens on all interfaces
+            let all = addr.ip().is_unspecified();
+            let ip = if all {
+                String::new()
+            } else {
+                addr.ip().to_string()
+            };
+            let service = mdns_sd::ServiceInfo::new(
+                SERVICE_TYPE,
+                &name,
+                &format!("{}.local.", name),
+                ip,
+                addr.port(),
+                &txt[..],
+            )
+            .map(|service| {
+                if all {
+                    service.enable_addr_auto()
+                } else {
+                    service
+                }
+            });
+            // registering the same name again replaces the announced txt record
+            if let Err(err) = service.and_then(|service| responder.register(service)) {
+                eprintln!("unable to announce the device: {}", err);
+            }
+        }
+    }
+}
+
+/// replaces the encrypted 'data' with the decrypted json
+fn decrypt_request(key: &str, mut payload: Value) -> std::result::Result<Value, i64> {
+    if payload.get("encrypt") != Some(&Value::Bool(true)) {
+        return Err(401);
+    }
+    let data = match (payload.get("iv"), payload.get("data")) {
+        (Some(Value::String(iv)), Some(Value::String(data))) => {
+            crypto::decrypt(key, iv, data).map_err(|_| 400)?
+        }
+        _ => return Err(400),
+    };
+    payload["data"] = serde_json::from_slice(&data).map_err(|_| 400)?;
+    Ok(payload)
+}
+
+fn encrypt_response(key: &str, mut response: Value) -> Result<Value> {
+    let data = match response.get("data") {
+        Some(Value::String(data)) => data.clone(),
+        Some(data) => data.to_string(),
+        None => return Ok(response),
+    };
+    let iv = crypto::random_iv();
+    response["data"] = crypto::encrypt(key, &iv, data.as_bytes())?.into();
+    response["iv"] = base64::encode(iv).into();
+    response["encrypt"] = true.into();
+    Ok(response)
+}
+
+fn on_off(data: &Value, key: &str) -> std::result::Result<String, i64> {
+    match data.get(key).and_then(Value::as_str) {
+        Some(v @ "on") | Some(v @ "off") => Ok(v.to_string()),
+        _ => Err(422),
+    }
+}
+
+fn respond(stream: &mut TcpStream, response: Value) -> Result<()> {
+    http::write_response(
+        stream,
+        "200 OK",
+        "application/json",
+        response.to_string().as_bytes(),
+    )
+}
@@ -1,5 +1,5 @@
 use snafu::{ErrorCompat, Snafu};
-use std::io;
+use std::{io, net::SocketAddr};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -9,6 +9,12 @@ pub enum Error {
     #[snafu(display("Device with id: {} not found", device_id))]
     DeviceNotFound { device_id: String },
 
+    #[snafu(display("Device {} is busy - a flash is running", device_id))]
+    DeviceBusy { device_id: String },
+
+    #[snafu(display("Unable to bind the web-server to {}: {}", addr, source))]
+    BindError { addr: SocketAddr, source: io::Error },
+
     #[snafu(display("IO error: {}", source))]
     IOError { source: io::Error },
 
@@ -21,6 +27,21 @@ pub enum Error {
     #[snafu(display("Request error: {}", source))]
     ReqwestError { source: reqwest::Error },
 
+    #[snafu(display("Device responded with error {}: {}", code, describe_device_error(*code)))]
+    DeviceError { code: i64 },
+
+    #[snafu(display("MQTT error: {}", msg))]
+    MqttError { msg: String },
+
+    #[snafu(display("Crypto error: {}", msg))]
+    CryptoError { msg: String },
+
+    #[snafu(display("No key for the encrypted device {} found", device_id))]
+    DeviceKeyMissing { device_id: String },
+
+    #[snafu(display("Invalid wifi credentials: {}", msg))]
+    InvalidWifiCredentials { msg: String },
+
     #[snafu(display("Invalid binary: {}", msg))]
     InvalidBinary { msg: String },
 
@@ -30,10 +51,53 @@ pub enum Error {
     #[snafu(display("Invalid request: {}", msg))]
     InvalidRequest { msg: String },
 
+    #[snafu(display("Verification of device {} failed: {}", device_id, msg))]
+    VerificationFailed { device_id: String, msg: String },
+
+    #[snafu(display(
+        "Provisioning failed at step '{}': {} - run the command again to resume",
+        step,
+        source
+    ))]
+    ProvisionFailed { step: String, source: Box<Error> },
+
+    #[snafu(display(
+        "Wifi migration failed at device {}: {} - run the command again to resume",
+        device_id,
+        source
+    ))]
+    MigrationFailed {
+        device_id: String,
+        source: Box<Error>,
+    },
+
+    #[snafu(display("{} of {} devices failed", failed, total))]
+    PartialFailure { failed: usize, total: usize },
+
+    #[snafu(display("Timeout: {}", msg))]
+    Timeout { msg: String },
+
     #[snafu(display("{}", msg))]
     GenericError { msg: String },
 }
 
+/// error codes from the sonoff diy api documentation
+fn describe_device_error(code: i64) -> &'static str {
+    match code {
+        400 => "the request was formatted incorrectly",
+        401 => "the request is unauthorized",
+        403 => "the ota function was not unlocked",
+        404 => "the device does not exist",
+        408 => "the firmware download timed out",
+        413 => "the firmware is too large",
+        422 => "the request parameters are invalid",
+        424 => "the firmware could not be downloaded",
+        471 => "the firmware integrity check failed",
+        500 => "the device has errors",
+        _ => "unknown error",
+    }
+}
+
 impl Error {
     pub fn print_backtrace(&self) {
         if let Some(backtrace) = ErrorCompat::backtrace(self) {
@@ -73,3 +137,11 @@ impl From<std::num::ParseIntError> for Error {
         }
     }
 }
+
+impl From<rumqttc::ClientError> for Error {
+    fn from(err: rumqttc::ClientError) -> Self {
+        Error::MqttError {
+            msg: err.to_string(),
+        }
+    }
+}
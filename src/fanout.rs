@@ -0,0 +1,116 @@
+use crate::*;
+use std::{
+    cmp,
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc,
+    sync::Arc,
+};
+use threadpool::ThreadPool;
+
+/// calls `f` for every device - at most `parallel` devices at the same time.
+///
+/// the results are in the same order as the given devices - a panic in `f` is
+/// returned as error for the device.
+pub fn fan_out<T, F>(devices: Vec<Device>, parallel: usize, f: F) -> Vec<(Device, Result<T>)>
+where
+    T: Send + 'static,
+    F: Fn(&Device) -> Result<T> + Send + Sync + 'static,
+{
+    let pool = ThreadPool::new(cmp::max(parallel, 1));
+    let f = Arc::new(f);
+    let (tx, rx) = mpsc::channel();
+    for (idx, device) in devices.into_iter().enumerate() {
+        let f = Arc::clone(&f);
+        let tx = tx.clone();
+        pool.execute(move || {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| f(&device)))
+                .unwrap_or_else(|panic| Err(panicked(panic.as_ref())));
+            tx.send((idx, device, res))
+                .expect("unable to report the result");
+        });
+    }
+    drop(tx);
+
+    let mut results = rx.iter().collect::<Vec<_>>();
+    results.sort_by_key(|(idx, _, _)| *idx);
+    results
+        .into_iter()
+        .map(|(_, device, res)| (device, res))
+        .collect()
+}
+
+fn panicked(panic: &(dyn std::any::Any + Send)) -> Error {
+    let msg = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".into());
+    Error::GenericError {
+        msg: format!("panicked: {}", msg),
+    }
+}
+
+/// prints one line per device and returns the number of failed devices
+pub fn print_results<T, F>(results: &[(Device, Result<T>)], fmt_ok: F) -> usize
+where
+    F: Fn(&T) -> String,
+{
+    let id_width = results
+        .iter()
+        .map(|(device, _)| device.id.len())
+        .max()
+        .unwrap_or(0);
+    let mut failed = 0;
+    for (device, res) in results {
+        let msg = match res {
+            Ok(v) => fmt_ok(v),
+            Err(err) => {
+                failed += 1;
+                format!("error: {}", err)
+            }
+        };
+        println!(
+            "{:id_width$}  {:15}  {}",
+            device.id,
+            device.ip.to_string(),
+            msg,
+            id_width = id_width
+        );
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn panic_is_an_error_for_the_device() {
+        let devices = ["a", "b", "c"]
+            .iter()
+            .map(|id| Device::new(id, id, &[], IpAddr::V4(Ipv4Addr::LOCALHOST), 8081))
+            .collect();
+        let results = fan_out(devices, 2, |device| {
+            if device.id == "b" {
+                panic!("device {} is broken", device.id);
+            }
+            Ok(device.id.clone())
+        });
+        let results = results
+            .into_iter()
+            .map(|(device, res)| (device.id, res.map_err(|err| err.to_string())))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            vec![
+                ("a".to_string(), Ok("a".to_string())),
+                (
+                    "b".to_string(),
+                    Err("panicked: device b is broken".to_string())
+                ),
+                ("c".to_string(), Ok("c".to_string())),
+            ]
+        );
+    }
+}
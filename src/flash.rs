@@ -0,0 +1,138 @@
+use crate::*;
+use log::debug;
+use serde_json::Value;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// how long to wait until the device reports 'otaUnlock: true'
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// flash many devices with the same binary from a single web-server
+#[derive(Clone)]
+pub struct BatchFlash {
+    endpoint: String,
+    sha256sum: String,
+    transfers: Transfers,
+    timeout: Duration,
+    verify_timeout: Option<Duration>,
+}
+
+impl BatchFlash {
+    pub fn new(
+        endpoint: String,
+        sha256sum: String,
+        transfers: Transfers,
+        timeout: Duration,
+    ) -> Self {
+        BatchFlash {
+            endpoint,
+            sha256sum,
+            transfers,
+            timeout,
+            verify_timeout: None,
+        }
+    }
+
+    /// verify that the new firmware comes up after the update
+    pub fn verify(mut self, timeout: Duration) -> Self {
+        self.verify_timeout = Some(timeout);
+        self
+    }
+
+    /// unlocks the device if necessary, starts the ota update and waits until the
+    /// device reports the result
+    pub fn flash(&self, device: &Device) -> Result<String> {
+        ensure_unlocked(device)?;
+
+        debug!("{}: start the ota update", device.id);
+        device.flash(self.download_url(device), self.sha256sum.clone())?;
+        let result = self.await_completion(device)?;
+        match self.verify_timeout {
+            Some(timeout) => verify_firmware(device, timeout),
+            None => Ok(result),
+        }
+    }
+
+    /// the download url for the device - the web-server tracks the progress per device
+    pub fn download_url(&self, device: &Device) -> String {
+        httpd::device_endpoint(&self.endpoint, &device.id)
+    }
+
+    /// waits until the device reports the result of the download
+    pub fn await_completion(&self, device: &Device) -> Result<String> {
+        let start = Instant::now();
+        let mut reported = 0;
+        loop {
+            let transfer = self
+                .transfers
+                .get(&device.id)
+                .or_else(|| self.transfers.get(&device.ip.to_string()));
+            if let Some(transfer) = transfer {
+                if let Some(result) = transfer.result {
+                    return check_upload_result(&result);
+                }
+
+                // report the progress in 10% steps
+                let percent = transfer.percent() as usize / 10 * 10;
+                if percent > reported {
+                    println!("{}: {:3}%", device.id, percent);
+                    reported = percent;
+                }
+            }
+
+            if start.elapsed() > self.timeout {
+                return Err(Error::Timeout {
+                    msg: format!("device {} has not finished the update", device.id),
+                });
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// unlocks the device and waits until the device confirms the unlock
+pub fn ensure_unlocked(device: &Device) -> Result<()> {
+    if device.device_info()?.is_unlocked() {
+        return Ok(());
+    }
+
+    println!("{}: unlock the device", device.id);
+    device.unlock()?;
+    let start = Instant::now();
+    while start.elapsed() < UNLOCK_TIMEOUT {
+        thread::sleep(POLL_INTERVAL);
+        if device.device_info()?.is_unlocked() {
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout {
+        msg: format!("device {} was not unlocked", device.id),
+    })
+}
+
+pub fn verify_firmware(device: &Device, timeout: Duration) -> Result<String> {
+    println!("{}: verify the new firmware", device.id);
+    match verify::verify(device, timeout)? {
+        verify::Verdict::Success(firmware) => Ok(format!("flashed - {} is up", firmware)),
+        verdict => Err(Error::VerificationFailed {
+            device_id: device.id.clone(),
+            msg: verdict.to_string(),
+        }),
+    }
+}
+
+/// the device posts '{"error":0}' after a successful update
+fn check_upload_result(result: &str) -> Result<String> {
+    let value: Value = serde_json::from_str(result)?;
+    match value.get("error").and_then(Value::as_i64) {
+        Some(0) => Ok("flashed".into()),
+        Some(code) => Err(Error::DeviceError { code }),
+        None => Err(Error::JSONLookupError {
+            msg: format!("'error' in upload result '{}' not found", result),
+        }),
+    }
+}
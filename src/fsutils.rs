@@ -0,0 +1,65 @@
+use crate::*;
+use std::{
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// writes the file per temp file and rename - a crash or a concurrent reader never
+/// sees a partially written file.
+///
+/// `private` files are only readable by the owner (unix).
+pub fn write_atomic(path: &Path, content: &[u8], private: bool) -> Result<()> {
+    let tmp = tmp_path(path);
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let res = options
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(res?)
+}
+
+/// '<file>.<pid>.tmp' next to the file - the rename stays on the same file system
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_else(|| "file".into());
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("sonoff-diy-atomic-{}.json", std::process::id()));
+        fs::write(&path, "old content which is longer").unwrap();
+        write_atomic(&path, b"new", false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!tmp_path(&path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
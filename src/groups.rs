@@ -0,0 +1,295 @@
+use crate::*;
+use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// a device or an outlet of a multi channel device - 'id' or 'id:outlet'
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub device_id: String,
+    pub outlet: Option<u8>,
+}
+
+impl FromStr for Member {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidRequest {
+            msg: format!("invalid member '{}' - expected 'id' or 'id:outlet'", s),
+        };
+        let mut parts = s.splitn(2, ':');
+        let device_id = parts
+            .next()
+            .filter(|id| !id.is_empty())
+            .ok_or_else(invalid)?;
+        let outlet = match parts.next() {
+            Some(outlet) => Some(outlet.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        Ok(Member {
+            device_id: device_id.to_string(),
+            outlet,
+        })
+    }
+}
+
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.outlet {
+            Some(outlet) => write!(f, "{}:{}", self.device_id, outlet),
+            None => write!(f, "{}", self.device_id),
+        }
+    }
+}
+
+impl Serialize for Member {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Member {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// the desired state of a scene member - 'id=on' or 'id:outlet=off'
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneMember {
+    pub member: Member,
+    pub state: SwitchState,
+}
+
+impl FromStr for SceneMember {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(member), Some(state)) => Ok(SceneMember {
+                member: member.parse()?,
+                state: state.parse()?,
+            }),
+            _ => Err(Error::InvalidRequest {
+                msg: format!(
+                    "invalid scene member '{}' - expected 'id=on' or 'id:outlet=off'",
+                    s
+                ),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for SceneMember {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.member, self.state)
+    }
+}
+
+/// named groups and scenes - stored next to the device cache
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Groups {
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<Member>>,
+    #[serde(default)]
+    scenes: BTreeMap<String, Vec<SceneMember>>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Groups {
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-groups.json");
+        path
+    }
+
+    /// loads the groups and scenes - none if the file does not exist
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let mut groups = if path.exists() {
+            debug!("load groups from {}", path.display());
+            let file = File::open(&path)?;
+            serde_json::from_reader::<_, Groups>(BufReader::new(file))?
+        } else {
+            Groups::default()
+        };
+        groups.path = path;
+        Ok(groups)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        debug!("save groups to: {}", self.path.display());
+        fsutils::write_atomic(&self.path, &serde_json::to_vec_pretty(self)?, false)
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Vec<Member>> {
+        &self.groups
+    }
+
+    pub fn scenes(&self) -> &BTreeMap<String, Vec<SceneMember>> {
+        &self.scenes
+    }
+
+    pub fn group(&self, name: &str) -> Result<&[Member]> {
+        self.groups
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| Error::GenericError {
+                msg: format!("no group '{}'", name),
+            })
+    }
+
+    pub fn scene(&self, name: &str) -> Result<&[SceneMember]> {
+        self.scenes
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| Error::GenericError {
+                msg: format!("no scene '{}'", name),
+            })
+    }
+
+    /// creates or replaces the group
+    pub fn set_group(&mut self, name: &str, members: Vec<Member>) -> Result<()> {
+        check_members(members.iter())?;
+        self.groups.insert(name.to_string(), members);
+        Ok(())
+    }
+
+    /// creates or replaces the scene
+    pub fn set_scene(&mut self, name: &str, members: Vec<SceneMember>) -> Result<()> {
+        check_members(members.iter().map(|m| &m.member))?;
+        self.scenes.insert(name.to_string(), members);
+        Ok(())
+    }
+
+    pub fn remove_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    pub fn remove_scene(&mut self, name: &str) -> bool {
+        self.scenes.remove(name).is_some()
+    }
+}
+
+/// a device is either a whole member or with single outlets - and every member only once
+fn check_members<'a, I>(members: I) -> Result<()>
+where
+    I: Iterator<Item = &'a Member>,
+{
+    let mut seen: Vec<&Member> = Vec::new();
+    for member in members {
+        let conflict = seen.iter().any(|m| {
+            m.device_id == member.device_id
+                && (m.outlet == member.outlet || m.outlet.is_none() || member.outlet.is_none())
+        });
+        if conflict {
+            return Err(Error::InvalidRequest {
+                msg: format!("'{}' overlaps with another member", member),
+            });
+        }
+        seen.push(member);
+    }
+    if seen.is_empty() {
+        return Err(Error::InvalidRequest {
+            msg: "no members".into(),
+        });
+    }
+    Ok(())
+}
+
+/// switches the members - one request per device, the devices in parallel.
+///
+/// the results are per device in the order of the members - fails before any
+/// request if a member is not in the given devices.
+pub fn apply(
+    devices: &[Device],
+    members: &[SceneMember],
+    parallel: usize,
+) -> Result<Vec<(Device, Result<String>)>> {
+    let mut outlets: HashMap<String, Vec<(u8, SwitchState)>> = HashMap::new();
+    let mut whole: HashMap<String, SwitchState> = HashMap::new();
+    let mut targets: Vec<Device> = Vec::new();
+    for m in members {
+        if !targets.iter().any(|d| d.id == m.member.device_id) {
+            let device = devices
+                .iter()
+                .find(|d| d.id == m.member.device_id)
+                .ok_or_else(|| Error::DeviceNotFound {
+                    device_id: m.member.device_id.clone(),
+                })?;
+            targets.push(device.clone());
+        }
+        match m.member.outlet {
+            Some(outlet) => outlets
+                .entry(m.member.device_id.clone())
+                .or_default()
+                .push((outlet, m.state)),
+            None => {
+                whole.insert(m.member.device_id.clone(), m.state);
+            }
+        }
+    }
+
+    let (outlets, whole) = (Arc::new(outlets), Arc::new(whole));
+    Ok(fanout::fan_out(targets, parallel, move |device| {
+        match (whole.get(&device.id), outlets.get(&device.id)) {
+            (Some(state), _) => device.switch(*state).map(|_| state.to_string()),
+            (None, Some(outlets)) => device.switches(outlets).map(|_| {
+                outlets
+                    .iter()
+                    .map(|(outlet, state)| format!("outlet {}: {}", outlet, state))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+            (None, None) => unreachable!("every device has a member"),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_members() {
+        assert_eq!(
+            "1000000001:2=off".parse::<SceneMember>().unwrap(),
+            SceneMember {
+                member: Member {
+                    device_id: "1000000001".into(),
+                    outlet: Some(2),
+                },
+                state: SwitchState::Off,
+            }
+        );
+        assert_eq!("1000000001".parse::<Member>().unwrap().outlet, None);
+        for s in &["", ":1", "1000000001:x", "1000000001=dim"] {
+            assert!(s.parse::<SceneMember>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn overlapping_members() {
+        let members = |s: &[&str]| s.iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>();
+        let mut groups = Groups::default();
+        assert!(groups.set_group("a", members(&["1:0", "1:1", "2"])).is_ok());
+        assert!(groups.set_group("b", members(&["1:0", "1"])).is_err());
+        assert!(groups.set_group("c", members(&["2", "2"])).is_err());
+        assert!(groups.set_group("d", Vec::new()).is_err());
+        assert_eq!(groups.groups().len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::*;
+use serde_json::{json, Value};
+
+/// the home assistant mqtt discovery configs for a device - (topic, payload)
+///
+/// the entities use the topics from the mqtt bridge with the given prefix.
+pub fn discovery_configs(
+    discovery_prefix: &str,
+    prefix: &str,
+    device: &Device,
+    state: &Value,
+) -> Vec<(String, Value)> {
+    let unique_id = format!("sonoff_diy_{}", device.id);
+    let topic = |name: &str| format!("{}/{}/{}", prefix, device.id, name);
+    let availability = json!([
+        { "topic": format!("{}/bridge/availability", prefix) },
+        { "topic": topic("availability") },
+    ]);
+    let ha_device = json!({
+        "identifiers": [&unique_id],
+        "name": &device.name,
+        "manufacturer": "ITEAD",
+        "model": device.device_type().unwrap_or("Sonoff DIY"),
+        "sw_version": state.get("fwVersion"),
+    });
+
+    let switch = json!({
+        "name": &device.name,
+        "unique_id": &unique_id,
+        "command_topic": topic("set"),
+        "state_topic": topic("state"),
+        "value_template": "{{ value_json.switch }}",
+        "payload_on": "on",
+        "payload_off": "off",
+        "state_on": "on",
+        "state_off": "off",
+        "availability": &availability,
+        "availability_mode": "all",
+        "device": &ha_device,
+    });
+
+    let rssi = json!({
+        "name": format!("{} RSSI", device.name),
+        "unique_id": format!("{}_rssi", unique_id),
+        "state_topic": topic("state"),
+        "value_template": "{{ value_json.signalStrength | default(value_json.rssi) }}",
+        "device_class": "signal_strength",
+        "unit_of_measurement": "dBm",
+        "state_class": "measurement",
+        "entity_category": "diagnostic",
+        "availability": &availability,
+        "availability_mode": "all",
+        "device": &ha_device,
+    });
+
+    let fw_version = json!({
+        "name": format!("{} firmware", device.name),
+        "unique_id": format!("{}_fw_version", unique_id),
+        "state_topic": topic("state"),
+        "value_template": "{{ value_json.fwVersion }}",
+        "icon": "mdi:chip",
+        "entity_category": "diagnostic",
+        "availability": &availability,
+        "availability_mode": "all",
+        "device": &ha_device,
+    });
+
+    vec![
+        (
+            format!("{}/switch/{}/config", discovery_prefix, device.id),
+            switch,
+        ),
+        (
+            format!("{}/sensor/{}/rssi/config", discovery_prefix, device.id),
+            rssi,
+        ),
+        (
+            format!(
+                "{}/sensor/{}/fw_version/config",
+                discovery_prefix, device.id
+            ),
+            fw_version,
+        ),
+    ]
+}
@@ -0,0 +1,208 @@
+use crate::*;
+use log::debug;
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use threadpool::ThreadPool;
+
+/// a request must be complete within this time - also closes idle keep-alive connections
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// max. size of a request with the header - the api and the devices send small json bodies
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+pub type Method = String;
+pub type Path = String;
+pub type Headers = HashMap<String, String>;
+pub type Body = String;
+
+/// stops a server which runs in `serve`
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new(addr: SocketAddr) -> Self {
+        Shutdown {
+            addr,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// closes the listener - open connections are served until they are idle
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the blocking accept
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if let Err(err) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            debug!("unable to wake up the server at {}: {}", addr, err);
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// accepts connections until the shutdown and serves them on a pool with the
+/// given workers - `handle_request` returns `true` to keep the connection alive.
+pub fn serve<F>(
+    listener: TcpListener,
+    workers: usize,
+    shutdown: Option<Shutdown>,
+    handle_request: F,
+) where
+    F: Fn(&mut TcpStream) -> bool + Send + Sync + 'static,
+{
+    let pool = ThreadPool::new(workers);
+    let handle_request = Arc::new(handle_request);
+    for stream in listener.incoming() {
+        if shutdown.as_ref().map(Shutdown::is_stopped).unwrap_or(false) {
+            debug!("server at {:?} stopped", listener.local_addr());
+            break;
+        }
+        match stream {
+            Ok(mut stream) => {
+                let handle_request = Arc::clone(&handle_request);
+                pool.execute(move || while handle_request(&mut stream) {});
+            }
+            Err(err) => eprintln!("unable to accept connection: {}", err),
+        }
+    }
+}
+
+/// reads a single request - returns `None` if the peer has closed the connection
+pub fn read_request(stream: &mut TcpStream) -> Result<Option<(Method, Path, Headers, Body)>> {
+    let deadline = Instant::now() + READ_TIMEOUT;
+    let mut raw = Vec::new();
+    let mut buf = [0_u8; 4096];
+    loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| *d > Duration::from_millis(0))
+            .ok_or(Error::Timeout {
+                msg: "incomplete request".into(),
+            })?;
+        stream.set_read_timeout(Some(remaining))?;
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            if !raw.is_empty() {
+                debug!("connection closed within the request");
+            }
+            return Ok(None);
+        }
+        raw.extend_from_slice(&buf[..n]);
+        if raw.len() > MAX_REQUEST_SIZE {
+            return Err(too_large());
+        }
+
+        // wait for the complete body
+        if let Some(idx) = find_body_start(&raw) {
+            let (_, _, headers, _) = parse_request(&raw)?;
+            let content_length: usize = headers
+                .get("content-length")
+                .map(|v| v.trim().parse())
+                .transpose()?
+                .unwrap_or(0);
+            if idx + content_length > MAX_REQUEST_SIZE {
+                return Err(too_large());
+            }
+            if raw.len() - idx >= content_length {
+                return parse_request(&raw).map(Some);
+            }
+        }
+    }
+}
+
+fn too_large() -> Error {
+    Error::InvalidRequest {
+        msg: format!("request larger than {} bytes", MAX_REQUEST_SIZE),
+    }
+}
+
+pub fn parse_request(raw: &[u8]) -> Result<(Method, Path, Headers, Body)> {
+    let raw = String::from_utf8_lossy(raw);
+    debug!("parse request: {}", raw);
+    match raw.splitn(2, "\r\n\r\n").collect::<Vec<_>>().as_slice() {
+        [header, body] => match header
+            .splitn(4, |c: char| c.is_whitespace())
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [method, path, _, raw_headers] => {
+                let headers = parse_headers(raw_headers);
+                Ok((
+                    method.to_string(),
+                    path.to_string(),
+                    headers,
+                    body.to_string(),
+                ))
+            }
+            [method, path, _] => Ok((
+                method.to_string(),
+                path.to_string(),
+                HashMap::new(),
+                body.to_string(),
+            )),
+            _ => Err(Error::InvalidRequest {
+                msg: format!("unexpected request: '{}'", raw),
+            }),
+        },
+        _ => Err(Error::InvalidRequest {
+            msg: format!("header / body separator in '{}' not found", raw),
+        }),
+    }
+}
+
+fn parse_headers(s: &str) -> Headers {
+    let mut headers = HashMap::new();
+    for line in s.lines() {
+        let mut iter = line.splitn(2, ": ");
+        match (iter.next(), iter.next()) {
+            (Some(name), Some(value)) => {
+                let name = name.to_lowercase();
+                headers.insert(name, value.into());
+            }
+            _ => debug!("ignore invalid header: {}", line),
+        }
+    }
+    headers
+}
+
+fn find_body_start(raw: &[u8]) -> Option<usize> {
+    raw.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|idx| idx + 4)
+}
+
+/// writes a complete response with the given status line, e.g. '200 OK'
+pub fn write_response<W: Write>(
+    stream: W,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let mut b = BufWriter::new(stream);
+    write!(b, "HTTP/1.1 {}\r\n", status)?;
+    write!(b, "Content-Type: {}\r\n", content_type)?;
+    write!(b, "Content-Length: {}\r\n", body.len())?;
+    b.write_all(b"\r\n")?;
+    b.write_all(body)?;
+    b.flush()?;
+    Ok(())
+}
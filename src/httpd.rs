@@ -3,119 +3,189 @@ use log::debug;
 use std::{
     cmp,
     collections::HashMap,
-    io::{BufWriter, Read, Write},
-    net::{IpAddr, TcpListener, TcpStream},
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
     thread,
 };
 
+/// number of connections which are served concurrently per default
+pub const DEFAULT_WORKERS: usize = 4;
+
 pub struct Httpd {
-    ip: IpAddr,
-    port: u16,
+    /// moved to the accept loop on start
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+    workers: usize,
+    verbose: bool,
     bin: Binary,
-    bin_content: Vec<u8>,
+    bin_content: Arc<[u8]>,
+    transfers: Transfers,
+    shutdown: http::Shutdown,
+}
+
+/// download state of a single client
+#[derive(Debug, Clone, Default)]
+pub struct Transfer {
+    /// highest byte offset served
+    pub served: usize,
+    pub total: usize,
+    /// the body from the devices 'upload done' post
+    pub result: Option<String>,
+}
+
+impl Transfer {
+    pub fn percent(&self) -> f32 {
+        if self.total == 0 {
+            0.0
+        } else {
+            100.0 / self.total as f32 * self.served as f32
+        }
+    }
 }
 
-type Method = String;
-type Path = String;
-type Headers = HashMap<String, String>;
-type Body = String;
+/// download states from all clients.
+///
+/// keyed by the device id from the download url ('/<device-id>/<binary>') - or
+/// by the client ip for urls without a device id.
+#[derive(Debug, Clone, Default)]
+pub struct Transfers(Arc<Mutex<HashMap<String, Transfer>>>);
+
+impl Transfers {
+    pub fn get(&self, key: &str) -> Option<Transfer> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn update<F>(&self, key: String, f: F)
+    where
+        F: FnOnce(&mut Transfer),
+    {
+        f(self.0.lock().unwrap().entry(key).or_default())
+    }
+}
+
+/// the download url of the binary for the given device - the progress of the
+/// device is tracked under its id.
+pub fn device_endpoint(endpoint: &str, device_id: &str) -> String {
+    match endpoint.rfind('/') {
+        Some(idx) => format!("{}/{}{}", &endpoint[..idx], device_id, &endpoint[idx..]),
+        None => endpoint.to_string(),
+    }
+}
 
 impl Httpd {
+    /// binds the web-server - use port `0` to let the os pick a free port
     pub fn new(ip: &IpAddr, port: u16, bin: &Binary) -> Result<Self> {
-        let ip = *ip;
+        let addr = SocketAddr::new(*ip, port);
+        let listener =
+            TcpListener::bind(addr).map_err(|source| Error::BindError { addr, source })?;
+        let addr = listener.local_addr()?;
+        debug!("web-server bound to {}", addr);
+
         let bin = bin.clone();
-        let bin_content = bin.slurp()?;
+        let bin_content = bin.slurp()?.into();
         Ok(Httpd {
-            ip,
-            port,
+            listener: Some(listener),
+            addr,
+            workers: DEFAULT_WORKERS,
+            verbose: true,
             bin,
             bin_content,
+            transfers: Transfers::default(),
+            shutdown: http::Shutdown::new(addr),
         })
     }
 
-    pub fn start(self) -> (String, thread::JoinHandle<()>) {
-        let bin_endpoint = format!("http://{}:{}/{}", &self.ip, self.port, self.bin.basename());
+    /// print every served chunk
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// handle to follow the download progress of the clients
+    pub fn transfers(&self) -> Transfers {
+        self.transfers.clone()
+    }
+
+    /// handle to stop the web-server after the downloads
+    pub fn shutdown_handle(&self) -> http::Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// max. number of connections which are served concurrently
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = cmp::max(workers, 1);
+        self
+    }
+
+    /// the address where the web-server listens
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn start(mut self) -> (String, thread::JoinHandle<()>) {
+        let bin_endpoint = format!("http://{}/{}", self.addr, self.bin.basename());
         let hndl = thread::spawn(move || {
-            let listener = TcpListener::bind((self.ip, self.port)).unwrap();
-            for stream in listener.incoming() {
-                let mut stream = stream.expect("unable to start server");
-                self.handle_request(&mut stream);
-            }
+            let listener = self.listener.take().expect("started once");
+            let (workers, shutdown) = (self.workers, self.shutdown.clone());
+            let httpd = Arc::new(self);
+            http::serve(listener, workers, Some(shutdown), move |stream| {
+                httpd.handle_request(stream)
+            });
         });
         (bin_endpoint, hndl)
     }
 
-    fn handle_request(&self, stream: &mut TcpStream) {
-        let mut buf = [0_u8; 4096];
-        let n = stream.read(&mut buf).expect("unable to read from stream");
-        if n == 0 {
-            debug!("empty request");
-            return;
-        }
-        match Httpd::parse_request(&buf[..n]) {
-            Ok((method, path, headers, _))
+    /// returns `true` if the connection should be kept alive
+    fn handle_request(&self, stream: &mut TcpStream) -> bool {
+        match http::read_request(stream) {
+            Ok(None) => {
+                debug!("empty request");
+            }
+            Ok(Some((method, path, headers, _)))
                 if method == "GET" && path.contains(&self.bin.basename()) =>
             {
-                match self.handle_bin_download(stream, headers) {
-                    Ok(_) => self.handle_request(stream),
+                match self.handle_bin_download(stream, &path, headers) {
+                    Ok(_) => return true,
                     Err(err) => eprintln!("Unable to serve the binary: {}", err),
                 }
             }
-            Ok((method, _, _, body)) if method == "POST" => {
-                println!("upload done - resonse: {}", body)
+            Ok(Some((method, path, _, body))) if method == "POST" => {
+                if self.verbose {
+                    println!("upload done - resonse: {}", body)
+                }
+                metrics::ota_download_done(&body);
+                if let Ok(key) = self.transfer_key(stream, &path) {
+                    self.transfers
+                        .update(key, |t| t.result = Some(body.trim().to_string()));
+                }
+                if let Err(err) = http::write_response(stream, "200 OK", "text/plain", b"") {
+                    debug!("unable to respond: {}", err);
+                }
             }
-            Ok(t) => eprintln!("unexpected request: {:#?}", t),
+            Ok(Some(t)) => eprintln!("unexpected request: {:#?}", t),
+            Err(Error::IOError { source }) => debug!("unable to read from stream: {}", source),
             Err(err) => eprintln!("{}", err),
         };
+        false
     }
 
-    fn parse_request(raw: &[u8]) -> Result<(Method, Path, Headers, Body)> {
-        let raw = String::from_utf8_lossy(raw);
-        debug!("parse request: {}", raw);
-        match raw.splitn(2, "\r\n\r\n").collect::<Vec<_>>().as_slice() {
-            [header, body] => match header
-                .splitn(4, |c: char| c.is_whitespace())
-                .collect::<Vec<_>>()
-                .as_slice()
-            {
-                [method, path, _, raw_headers] => {
-                    let headers = Httpd::parse_headers(raw_headers);
-                    Ok((
-                        method.to_string(),
-                        path.to_string(),
-                        headers,
-                        body.to_string(),
-                    ))
-                }
-                _ => Err(Error::InvalidRequest {
-                    msg: format!("unexpected request: '{}'", raw),
-                }),
-            },
-            _ => Err(Error::InvalidRequest {
-                msg: format!("header / body separator in '{}' not found", raw),
-            }),
-        }
-    }
-
-    fn parse_headers(s: &str) -> Headers {
-        let mut headers = HashMap::new();
-        for line in s.lines() {
-            let mut iter = line.splitn(2, ": ");
-            match (iter.next(), iter.next()) {
-                (Some(name), Some(value)) => {
-                    let name = name.to_lowercase();
-                    headers.insert(name, value.into());
-                }
-                _ => debug!("ignore invalid header: {}", line),
+    /// the device id from '/<device-id>/<binary>' - or the client ip
+    fn transfer_key(&self, stream: &TcpStream, path: &str) -> Result<String> {
+        let mut parts = path.trim_start_matches('/').splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some(device_id), Some(bin)) if !device_id.is_empty() && bin == self.bin.basename() => {
+                Ok(device_id.to_string())
             }
+            _ => Ok(stream.peer_addr()?.ip().to_string()),
         }
-        headers
     }
 
     fn handle_bin_download(
         &self,
         stream: &mut TcpStream,
-        headers: HashMap<String, String>,
+        path: &str,
+        headers: http::Headers,
     ) -> Result<()> {
         // parse the range header
         let range_header = headers.get("range").ok_or(Error::InvalidRequest {
@@ -123,16 +193,34 @@ impl Httpd {
         })?;
         let len = self.bin_content.len();
         let (from, to) = Httpd::parse_range_header(&range_header)?;
-        println!(
-            "{:5.1}% - serve chunk from: {}, to: {}",
-            100.0 / len as f32 * (to + 1) as f32,
-            from,
-            to
-        );
+        if from > to || from >= len {
+            debug!("unsatisfiable range: {}", range_header);
+            let mut b = BufWriter::new(stream);
+            b.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\n")?;
+            write!(b, "Content-Range: bytes */{}\r\n", len)?;
+            b.write_all(b"Content-Length: 0\r\n\r\n")?;
+            b.flush()?;
+            return Ok(());
+        }
+        let peer = stream.peer_addr()?.ip();
+        let key = self.transfer_key(stream, path)?;
+        if self.verbose {
+            println!(
+                "{:5.1}% - serve chunk from: {}, to: {} ({})",
+                100.0 / len as f32 * (to + 1) as f32,
+                from,
+                to,
+                peer
+            );
+        }
 
         // extract the requested chunk
         let to = cmp::min(to, len - 1);
         let chunk = &self.bin_content[from..=to];
+        self.transfers.update(key, |t| {
+            t.total = len;
+            t.served = cmp::max(t.served, to + 1);
+        });
 
         let mut b = BufWriter::new(stream);
         b.write_all(b"HTTP/1.1 206 Partial Content\r\n")?;
@@ -150,6 +238,7 @@ impl Httpd {
         b.write_all(b"\r\n")?;
         b.write_all(chunk)?;
         b.flush()?;
+        metrics::ota_chunk_served(chunk.len());
         Ok(())
     }
 
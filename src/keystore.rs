@@ -0,0 +1,246 @@
+use crate::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+/// env var with the passphrase for the key store
+pub const PASSPHRASE_ENV: &str = "SONOFF_DIY_PASSPHRASE";
+
+/// pbkdf2 rounds for new key stores
+const DEFAULT_ROUNDS: u32 = 100_000;
+
+const VERSION: u8 = 1;
+
+/// the secrets - device keys per device id and wifi passwords per essid
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Secrets {
+    #[serde(default)]
+    devices: BTreeMap<String, String>,
+    #[serde(default)]
+    wifi: BTreeMap<String, String>,
+}
+
+/// the on-disk format: the aes-128-cbc encrypted secrets with a hmac-sha256 over iv + data
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    version: u8,
+    rounds: u32,
+    salt: String,
+    iv: String,
+    data: String,
+    mac: String,
+}
+
+/// encrypted store for device keys and wifi passwords
+pub struct KeyStore {
+    path: PathBuf,
+    passphrase: String,
+    rounds: u32,
+    secrets: Secrets,
+}
+
+impl KeyStore {
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-keys.json");
+        path
+    }
+
+    /// opens the key store at the default location
+    ///
+    /// the passphrase is read from `SONOFF_DIY_PASSPHRASE` or asked for on the terminal
+    pub fn open() -> Result<Self> {
+        let path = Self::default_path();
+        let passphrase = match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => passphrase,
+            Err(_) if path.exists() => prompt("key store passphrase: ")?,
+            Err(_) => {
+                let passphrase = prompt("new key store passphrase: ")?;
+                if passphrase != prompt("repeat the passphrase: ")? {
+                    return Err(Error::GenericError {
+                        msg: "the passphrases do not match".into(),
+                    });
+                }
+                passphrase
+            }
+        };
+        Self::load(path, &passphrase)
+    }
+
+    /// loads the key store - an empty store is returned if the file doesn't exist
+    pub fn load<P>(path: P, passphrase: &str) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let mut store = KeyStore {
+            path: path.into(),
+            passphrase: passphrase.to_string(),
+            rounds: DEFAULT_ROUNDS,
+            secrets: Secrets::default(),
+        };
+        if !store.path.exists() {
+            debug!("key store {} not found - start empty", store.path.display());
+            return Ok(store);
+        }
+
+        debug!("load key store from {}", store.path.display());
+        let envelope: Envelope = serde_json::from_slice(&fs::read(&store.path)?)?;
+        if envelope.version != VERSION {
+            return Err(Error::CryptoError {
+                msg: format!("unsupported key store version: {}", envelope.version),
+            });
+        }
+        let (iv, data) = (
+            crypto::decode(&envelope.iv)?,
+            crypto::decode(&envelope.data)?,
+        );
+        let key = crypto::derive_key(
+            passphrase,
+            &crypto::decode(&envelope.salt)?,
+            envelope.rounds,
+        );
+        if !crypto::constant_time_eq(&crypto::decode(&envelope.mac)?, &mac(&key, &iv, &data)) {
+            return Err(Error::CryptoError {
+                msg: "unable to open the key store - wrong passphrase?".into(),
+            });
+        }
+        store.rounds = envelope.rounds;
+        store.secrets = serde_json::from_slice(&crypto::decrypt_raw(&key[..16], &iv, &data)?)?;
+        Ok(store)
+    }
+
+    /// writes the key store - only readable by the owner
+    pub fn save(&self) -> Result<()> {
+        debug!("save key store to: {}", self.path.display());
+        let salt = crypto::random_iv();
+        let iv = crypto::random_iv();
+        let key = crypto::derive_key(&self.passphrase, &salt, self.rounds);
+        let data = crypto::encrypt_raw(&key[..16], &iv, &serde_json::to_vec(&self.secrets)?)?;
+        let envelope = Envelope {
+            version: VERSION,
+            rounds: self.rounds,
+            salt: base64::encode(salt),
+            iv: base64::encode(iv),
+            mac: base64::encode(mac(&key, &iv, &data)),
+            data: base64::encode(data),
+        };
+
+        // a crash while writing must not lose the keys
+        fsutils::write_atomic(&self.path, &serde_json::to_vec_pretty(&envelope)?, true)
+    }
+
+    pub fn device_key(&self, device_id: &str) -> Option<&str> {
+        self.secrets.devices.get(device_id).map(String::as_str)
+    }
+
+    pub fn set_device_key(&mut self, device_id: &str, key: &str) {
+        self.secrets
+            .devices
+            .insert(device_id.to_string(), key.to_string());
+    }
+
+    pub fn remove_device_key(&mut self, device_id: &str) -> bool {
+        self.secrets.devices.remove(device_id).is_some()
+    }
+
+    pub fn device_ids(&self) -> Vec<&str> {
+        self.secrets.devices.keys().map(String::as_str).collect()
+    }
+
+    pub fn wifi_password(&self, essid: &str) -> Option<&str> {
+        self.secrets.wifi.get(essid).map(String::as_str)
+    }
+
+    pub fn set_wifi_password(&mut self, essid: &str, pwd: &str) {
+        self.secrets.wifi.insert(essid.to_string(), pwd.to_string());
+    }
+
+    pub fn remove_wifi_password(&mut self, essid: &str) -> bool {
+        self.secrets.wifi.remove(essid).is_some()
+    }
+
+    pub fn essids(&self) -> Vec<&str> {
+        self.secrets.wifi.keys().map(String::as_str).collect()
+    }
+
+    /// sets the keys from the store in the given devices
+    pub fn assign_keys(&self, devices: &mut [Device]) {
+        for device in devices.iter_mut().filter(|d| d.key.is_none()) {
+            device.key = self.device_key(&device.id).map(String::from);
+        }
+    }
+}
+
+fn mac(key: &[u8; 32], iv: &[u8], data: &[u8]) -> [u8; 32] {
+    hmac_sha256::HMAC::mac(&[iv, data].concat(), &key[16..])
+}
+
+/// reads a secret from the terminal without echo
+pub fn prompt(msg: &str) -> Result<String> {
+    Ok(rpassword::read_password_from_tty(Some(msg))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "sonoff-diy-keystore-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("roundtrip");
+        let mut store = KeyStore::load(&path, "secret").unwrap();
+        store.set_device_key("1000000001", "device-key");
+        store.set_wifi_password("iot", "wifi-password");
+        store.save().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("device-key"));
+        assert!(!content.contains("wifi-password"));
+
+        let store = KeyStore::load(&path, "secret").unwrap();
+        assert_eq!(store.device_key("1000000001"), Some("device-key"));
+        assert_eq!(store.wifi_password("iot"), Some("wifi-password"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_with_wrong_passphrase_should_fail() {
+        let path = temp_path("wrong-passphrase");
+        KeyStore::load(&path, "secret").unwrap().save().unwrap();
+
+        match KeyStore::load(&path, "guess") {
+            Err(Error::CryptoError { .. }) => (),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path("permissions");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let store = KeyStore {
+            path: path.clone(),
+            passphrase: "secret".into(),
+            rounds: 1,
+            secrets: Secrets::default(),
+        };
+        store.save().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,20 +1,61 @@
 mod args;
+pub mod audit;
+pub mod backup;
 mod binary;
+pub mod countdown;
+mod cron;
+pub mod crypto;
+pub mod daemon;
 mod device;
 mod device_attr;
 mod device_cache;
+mod device_info;
+#[cfg(feature = "emulator")]
+pub mod emulator;
 mod error;
+pub mod fanout;
+mod flash;
+pub mod fsutils;
+pub mod groups;
+pub mod homeassistant;
+mod http;
 mod httpd;
+pub mod keystore;
+pub mod metrics;
+mod migration;
+pub mod mqtt;
 pub mod netutils;
+mod provision;
+pub mod report;
+pub mod rules;
 mod scanner;
+#[cfg(feature = "emulator")]
+pub mod scenario;
+pub mod scheduler;
+pub mod solar;
+pub mod survey;
+pub mod verify;
+pub mod wifi;
 
 pub use args::*;
 pub use binary::Binary;
+pub use cron::Cron;
+pub use daemon::Daemon;
 pub use device::Device;
 use device_attr::DeviceAttributes;
-pub use device_cache::DeviceCache;
+pub use device_cache::{DeviceCache, Filter};
+pub use device_info::{DeviceInfo, OutletState};
+#[cfg(feature = "emulator")]
+pub use emulator::Emulator;
 pub use error::Error;
-pub use httpd::Httpd;
+pub use flash::{verify_firmware, BatchFlash};
+pub use httpd::{Httpd, Transfer, Transfers};
+pub use keystore::KeyStore;
+pub use migration::WifiMigration;
+pub use mqtt::{MqttBridge, MqttOpts};
+pub use provision::{Provision, ProvisionOpts};
+pub use rules::{Automation, Rules};
 pub use scanner::Scanner;
+pub use scheduler::Scheduler;
 
 pub type Result<T, E = crate::Error> = std::result::Result<T, E>;
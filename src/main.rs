@@ -1,4 +1,6 @@
+use sonoff_diy::scheduler::Clock;
 use sonoff_diy::*;
+use std::{net::IpAddr, time::Duration};
 
 #[paw::main]
 fn main(args: Args) {
@@ -6,11 +8,22 @@ fn main(args: Args) {
     if let Err(err) = run(args) {
         eprintln!("Error: {}", err);
         err.print_backtrace();
+        // 2: some of the selected devices failed
+        let code = match err {
+            Error::PartialFailure { failed, total } if failed < total => 2,
+            _ => 1,
+        };
+        std::process::exit(code);
     }
 }
 
 fn run(args: Args) -> Result<()> {
+    audit::enable(audit::default_path());
     let mut device_cache = DeviceCache::load().unwrap_or_default();
+    if device_cache.devices().iter().any(|d| d.key.is_some()) {
+        migrate_keys(&device_cache)?;
+    }
+    warn_overdue_reverts();
 
     match args.cmd {
         Command::Scan { service_name } => {
@@ -27,65 +40,878 @@ fn run(args: Args) -> Result<()> {
                 println!("{}", device);
             }
         }
-        Command::Info { device_id } => {
-            let device = device_cache.lookup(&device_id)?;
-            println!("{}", device.info()?)
-        }
+        Command::Info {
+            targets,
+            parallel: Parallel { parallel },
+        } => for_targets(&device_cache, &targets, parallel, |device| device.info())?,
         Command::Wifi {
-            device_id,
+            targets,
             essid,
             pwd,
+            wait,
+            wait_timeout,
+            restart,
         } => {
-            let device = device_cache.lookup(&device_id)?;
-            println!("{}", device.wifi(essid, pwd)?)
+            let wait_timeout = Duration::from_secs(wait_timeout);
+            if targets.is_batch() {
+                let devices = with_keys(device_cache.select(&targets)?)?;
+                let pwd = wifi::read_password(&pwd, &essid)?;
+                return WifiMigration::new(&essid, &pwd, wait_timeout).run(
+                    &devices,
+                    &mut device_cache,
+                    restart,
+                );
+            }
+
+            let device = lookup(&device_cache, &targets.device_id[0])?;
+            let pwd = wifi::read_password(&pwd, &essid)?;
+            let response = device.wifi(essid.as_str(), pwd.as_str())?;
+            println!("{}", response);
+            if wait {
+                println!("wait for the device in the network '{}'", essid);
+                let device = wifi::await_reconnect(&device, &essid, &response, wait_timeout)?;
+                println!("device connected: {}", device);
+                device_cache.update(&device)?;
+            }
+        }
+        Command::Switch {
+            targets,
+            parallel: Parallel { parallel },
+            cmd,
+        } => switch(&device_cache, &targets, parallel, &cmd)?,
+        Command::Startup {
+            targets,
+            parallel: Parallel { parallel },
+            state,
+        } => for_targets(&device_cache, &targets, parallel, move |device| {
+            device.startup(state)
+        })?,
+        Command::Pulse {
+            targets,
+            parallel: Parallel { parallel },
+            state,
+        } => for_targets(&device_cache, &targets, parallel, move |device| {
+            device.pulse(state)
+        })?,
+        Command::Unlock {
+            targets,
+            parallel: Parallel { parallel },
+        } => for_targets(&device_cache, &targets, parallel, |device| device.unlock())?,
+        Command::Tag {
+            device_id,
+            remove,
+            tags,
+        } => println!("{}", device_cache.tag(&device_id, &tags, remove)?),
+        Command::Keys { cmd } => keys(cmd)?,
+        Command::Schedule { cmd } => schedule(cmd, &device_cache)?,
+        Command::Backup {
+            targets,
+            output,
+            parallel: Parallel { parallel },
+        } => {
+            let devices = select(&device_cache, &targets)?;
+            let total = devices.len();
+            let (backup, results) = backup::backup(devices, parallel, countdown::now());
+            let failed = fanout::print_results(&results, |_| "saved".to_string());
+            if backup.devices.is_empty() {
+                return failures(failed, total);
+            }
+            let output = match output {
+                Some(output) => output,
+                None => backup::create_default_dir()?,
+            };
+            println!("backup saved to {}", backup.save(&output)?.display());
+            failures(failed, total)?
         }
-        Command::Switch { device_id, state } => {
-            let device = device_cache.lookup(&device_id)?;
-            println!("{}", device.switch(state)?)
+        Command::Restore {
+            file,
+            device_id,
+            to,
+            switch,
+        } => {
+            let backup = backup::Backup::load(&file)?;
+            let pairs = backup_pairs(&device_cache, &backup, device_id, to)?;
+            let total = pairs.len();
+            let mut failed = 0;
+            for (saved, target, device) in pairs {
+                let results = match device {
+                    Ok(device) => saved.settings.restore(&device, switch),
+                    Err(err) => vec![("device", Err(err))],
+                };
+                if results.iter().any(|(_, res)| res.is_err()) {
+                    failed += 1;
+                }
+                for (setting, res) in results {
+                    match res {
+                        Ok(_) => println!("{}  {}: restored", target, setting),
+                        Err(err) => println!("{}  {}: error: {}", target, setting, err),
+                    }
+                }
+            }
+            failures(failed, total)?
         }
-        Command::Unlock { device_id } => {
-            let device = device_cache.lookup(&device_id)?;
-            println!("{}", device.unlock()?)
+        Command::Diff {
+            file,
+            device_id,
+            to,
+        } => {
+            let backup = backup::Backup::load(&file)?;
+            let pairs = backup_pairs(&device_cache, &backup, device_id, to)?;
+            let total = pairs.len();
+            let mut failed = 0;
+            for (saved, target, device) in pairs {
+                let live = device.and_then(|d| d.device_info());
+                match live {
+                    Ok(info) => {
+                        let diff = saved.settings.diff(&info.into());
+                        if diff.is_empty() {
+                            println!("{}  unchanged", target);
+                        }
+                        for (name, saved, live) in diff {
+                            println!("{}  {}: {} -> {}", target, name, saved, live);
+                        }
+                    }
+                    Err(err) => {
+                        failed += 1;
+                        println!("{}  error: {}", target, err);
+                    }
+                }
+            }
+            failures(failed, total)?
         }
-        Command::Flash {
+        Command::Audit {
             device_id,
+            since,
+            until,
+            json,
+        } => {
+            let now = countdown::now();
+            let query = audit::Query {
+                device_id,
+                since: since.map(|s| audit::parse_time(&s, now)).transpose()?,
+                until: until.map(|s| audit::parse_time(&s, now)).transpose()?,
+            };
+            for record in query.run(&audit::default_path())? {
+                if json {
+                    println!("{}", serde_json::to_string(&record)?);
+                    continue;
+                }
+                let data = record
+                    .payload
+                    .get("data")
+                    .map(|data| data.to_string())
+                    .unwrap_or_default();
+                let result = match record.error {
+                    Some(ref err) => format!("error: {}", err),
+                    None => "ok".to_string(),
+                };
+                println!(
+                    "{}  {}@{}  {}  {} {}  {}",
+                    record.time,
+                    record.user,
+                    record.host,
+                    record.device_id,
+                    record.endpoint,
+                    data,
+                    result
+                );
+            }
+        }
+        Command::Report { cmd } => report_cmd(cmd, &device_cache)?,
+        Command::Survey {
+            targets,
+            interval,
+            duration,
+            output,
+            txt,
+            service_name,
+            parallel: Parallel { parallel },
+        } => {
+            let survey = survey::Survey::new(select(&device_cache, &targets)?, parallel);
+            if txt {
+                survey.listen(&service_name);
+            }
+            let mut csv = match output {
+                Some(ref path) => Some(std::io::BufWriter::new(std::fs::File::create(path)?)),
+                None => None,
+            };
+            let summary = survey.run(
+                Duration::from_secs(interval.max(1)),
+                duration,
+                csv.as_mut().map(|w| w as &mut dyn std::io::Write),
+            )?;
+            println!();
+            summary.print();
+            if let Some(path) = output {
+                println!("samples saved to {}", path.display());
+            }
+        }
+        Command::Group { cmd } => group(cmd, &device_cache)?,
+        Command::Scene { cmd } => scene(cmd, &device_cache)?,
+        Command::Daemon {
+            listen,
+            token_env,
+            flash_dir,
+            service_name,
+            scan_interval,
+            flash_timeout,
+        } => {
+            let encrypted = device_cache.devices().iter().any(|d| d.encrypt);
+            let mut daemon = Daemon::new(listen, device_cache)?
+                .flash_timeout(Duration::from_secs(flash_timeout));
+            if encrypted {
+                daemon = daemon.keys(KeyStore::open()?);
+            }
+            if let Some(var) = token_env {
+                let token = std::env::var(&var).map_err(|_| Error::GenericError {
+                    msg: format!("environment variable '{}' not set", var),
+                })?;
+                daemon = daemon.token(token);
+            }
+            if let Some(dir) = flash_dir {
+                daemon = daemon.flash_dir(dir);
+            }
+            println!("serve the api at http://{}", daemon.local_addr());
+            daemon.discover(&service_name, Duration::from_secs(scan_interval));
+            daemon.start()?.join().unwrap();
+        }
+        Command::Mqtt {
+            host,
+            port,
+            client_id,
+            username,
+            password_env,
+            prefix,
+            service_name,
+            interval,
+            offline_after,
+            homeassistant,
+            discovery_prefix,
+        } => {
+            let credentials = match (username, password_env) {
+                (Some(username), Some(var)) => {
+                    let password = std::env::var(&var).map_err(|_| Error::GenericError {
+                        msg: format!("environment variable '{}' not set", var),
+                    })?;
+                    Some((username, password))
+                }
+                (Some(username), None) => Some((username, String::new())),
+                _ => None,
+            };
+            let opts = MqttOpts {
+                host,
+                port,
+                client_id,
+                credentials,
+                prefix,
+                service_name,
+                interval: Duration::from_secs(interval),
+                offline_after: Duration::from_secs(offline_after),
+                homeassistant: if homeassistant {
+                    Some(discovery_prefix)
+                } else {
+                    None
+                },
+            };
+            MqttBridge::new(opts, with_keys(device_cache.devices())?).run()?
+        }
+        Command::Rules {
+            config,
+            dry_run,
+            service_name,
+            poll_interval,
+        } => {
+            let path = config.unwrap_or_else(Rules::default_path);
+            let rules = Rules::load(&path)?;
+            for device_id in rules.device_ids() {
+                if device_cache.lookup(device_id).is_err() {
+                    eprintln!(
+                        "warning: unknown device {} - waiting for an announcement",
+                        device_id
+                    );
+                }
+            }
+            println!("run {} rules from {}", rules.rules.len(), path.display());
+            let mut automation =
+                Automation::new(rules, with_keys(device_cache.devices())?).dry_run(dry_run);
+            if let Some(interval) = poll_interval {
+                automation = automation.poll_interval(Duration::from_secs(interval));
+            }
+            automation.run(&service_name)?
+        }
+        Command::Flash {
+            targets,
             bin,
             httpd_port,
+            httpd_ip,
             external_httpd_url,
             bin_sha256sum,
+            parallel: Parallel { parallel },
+            flash_timeout,
+            verify,
+            verify_timeout,
         } => {
-            let device = device_cache.lookup(&device_id)?;
+            // no default_value - clap would see it as a conflict with the external web-server
+            let httpd_port = httpd_port.unwrap_or(8989);
+            let flash_timeout = Duration::from_secs(flash_timeout);
+            let verify_timeout = if verify {
+                Some(Duration::from_secs(verify_timeout))
+            } else {
+                None
+            };
+
+            if targets.is_batch() {
+                let bin = bin.ok_or(Error::GenericError {
+                    msg: "flashing multiple devices needs '--bin'".into(),
+                })?;
+                return batch_flash(
+                    with_keys(device_cache.select(&targets)?)?,
+                    Binary::new(bin)?,
+                    httpd_ip,
+                    httpd_port,
+                    parallel,
+                    flash_timeout,
+                    verify_timeout,
+                );
+            }
+
+            let device = lookup(&device_cache, &targets.device_id[0])?;
 
             match (bin, external_httpd_url, bin_sha256sum) {
                 (Some(bin), None, None) => {
                     let bin = Binary::new(bin)?;
-                    let httpd_ip = netutils::matching_host_ip_for(&device.ip)?;
+                    let bin_sha256sum = bin.sha256sum();
+                    let httpd_ip = match httpd_ip {
+                        Some(ip) => ip,
+                        None => netutils::matching_host_ip_for(&device.ip)?,
+                    };
+                    let httpd = Httpd::new(&httpd_ip, httpd_port, &bin)?;
                     println!(
                         "startup the embedded web-server at {} to serve the binary",
-                        httpd_ip
+                        httpd.local_addr()
                     );
-                    let httpd = Httpd::new(&httpd_ip, httpd_port, &bin)?;
+                    let transfers = httpd.transfers();
                     let (bin_endpoint, hndl) = httpd.start();
                     println!(
                         "Initialize flash process: {}",
-                        device.flash(bin_endpoint, bin.sha256sum())?
+                        device.flash(bin_endpoint.clone(), bin_sha256sum.clone())?
                     );
-                    println!("hit <CTRL-C> to shudown the embedded web-server");
-                    hndl.join().unwrap();
+                    if let Some(verify_timeout) = verify_timeout {
+                        BatchFlash::new(bin_endpoint, bin_sha256sum, transfers, flash_timeout)
+                            .await_completion(&device)?;
+                        println!("{}", verify_firmware(&device, verify_timeout)?);
+                    } else {
+                        println!("hit <CTRL-C> to shudown the embedded web-server");
+                        hndl.join().unwrap();
+                    }
                 }
                 (None, Some(external_httpd_url), Some(bin_sha256sum)) => {
                     println!(
                         "Initialize flash process: {}",
                         device.flash(external_httpd_url, bin_sha256sum)?
                     );
+                    if let Some(verify_timeout) = verify_timeout {
+                        println!("{}", verify_firmware(&device, verify_timeout)?);
+                    }
                 }
                 _ => unreachable!(),
             };
         }
+        Command::Provision {
+            device_id,
+            ip,
+            port,
+            bin,
+            httpd_port,
+            httpd_ip,
+            scan_timeout,
+            flash_timeout,
+            verify_timeout,
+            restart,
+        } => {
+            let opts = ProvisionOpts {
+                device_id,
+                ip,
+                port,
+                bin: Binary::new(bin)?,
+                httpd_port,
+                httpd_ip,
+                scan_timeout: Duration::from_secs(scan_timeout),
+                flash_timeout: Duration::from_secs(flash_timeout),
+                verify_timeout: Duration::from_secs(verify_timeout),
+            };
+            Provision::new(opts).run(&mut device_cache, restart)?
+        }
     };
     Ok(())
 }
 
+/// looks up the device in the cache - encrypted devices get their key from the key store
+fn lookup(device_cache: &DeviceCache, device_id: &str) -> Result<Device> {
+    let device = device_cache.lookup(device_id)?;
+    Ok(with_keys(vec![device])?.remove(0))
+}
+
+fn with_keys(mut devices: Vec<Device>) -> Result<Vec<Device>> {
+    if devices.iter().any(|d| d.encrypt && d.key.is_none()) {
+        KeyStore::open()?.assign_keys(&mut devices);
+    }
+    Ok(devices)
+}
+
+/// moves the device keys from older caches in the key store
+fn migrate_keys(device_cache: &DeviceCache) -> Result<()> {
+    println!("move the device keys from the device cache in the key store");
+    let mut keys = KeyStore::open()?;
+    for device in device_cache.devices() {
+        match device.key {
+            Some(ref key) if keys.device_key(&device.id).is_none() => {
+                keys.set_device_key(&device.id, key)
+            }
+            _ => (),
+        }
+    }
+    keys.save()?;
+    device_cache.save()
+}
+
+fn keys(cmd: KeysCommand) -> Result<()> {
+    let mut keys = KeyStore::open()?;
+    match cmd {
+        KeysCommand::Add { entry } => {
+            match (entry.device_id, entry.essid) {
+                (Some(device_id), _) => {
+                    let key = keystore::prompt(&format!("device key for {}: ", device_id))?;
+                    keys.set_device_key(&device_id, &key);
+                }
+                (None, Some(essid)) => {
+                    let pwd = keystore::prompt(&format!("wifi password for {}: ", essid))?;
+                    keys.set_wifi_password(&essid, &pwd);
+                }
+                (None, None) => unreachable!(),
+            }
+            keys.save()
+        }
+        KeysCommand::List => {
+            for device_id in keys.device_ids() {
+                println!("device: {}", device_id);
+            }
+            for essid in keys.essids() {
+                println!("wifi:   {}", essid);
+            }
+            Ok(())
+        }
+        KeysCommand::Remove { entry } => {
+            let removed = match (entry.device_id, entry.essid) {
+                (Some(device_id), _) => keys.remove_device_key(&device_id),
+                (None, Some(essid)) => keys.remove_wifi_password(&essid),
+                (None, None) => unreachable!(),
+            };
+            if !removed {
+                return Err(Error::GenericError {
+                    msg: "no such entry in the key store".into(),
+                });
+            }
+            keys.save()
+        }
+    }
+}
+
+/// switches the devices - with '--for', the switch back is recorded and sent when it is due
+fn switch(
+    device_cache: &DeviceCache,
+    targets: &Targets,
+    parallel: usize,
+    cmd: &SwitchCommand,
+) -> Result<()> {
+    let devices = select(device_cache, targets)?;
+    let path = countdown::Countdowns::default_path();
+    let mut countdowns = countdown::Countdowns::load(&path)?;
+    let (state, countdown) = (cmd.state(), cmd.countdown());
+
+    // record the reverts first - so they are not lost if the tool is stopped
+    let reverts = match countdown.duration {
+        Some(duration) => devices
+            .iter()
+            .map(|d| countdown::Revert::new(&d.id, state.opposite(), duration))
+            .collect(),
+        None => Vec::new(),
+    };
+    if !reverts.is_empty() {
+        for revert in &reverts {
+            countdowns.add(revert.clone());
+        }
+        countdowns.save()?;
+    }
+
+    let results = fanout::fan_out(devices, parallel, move |device| device.switch(state));
+
+    // a switch cancels a pending revert - a failed switch its own revert
+    let mut countdowns = countdown::Countdowns::load(&path)?;
+    let mut cancelled = Vec::new();
+    for (device, res) in &results {
+        if res.is_ok() == countdown.duration.is_none() {
+            if let Some(revert) = countdowns.cancel(&device.id) {
+                cancelled.push(revert);
+            }
+        }
+    }
+    if !cancelled.is_empty() {
+        countdowns.save()?;
+    }
+
+    let (devices, reverts): (Vec<_>, Vec<_>) = results
+        .iter()
+        .zip(reverts)
+        .filter(|((_, res), _)| res.is_ok())
+        .map(|((device, _), revert)| (device.clone(), revert))
+        .unzip();
+
+    let res = report(targets, results);
+    if countdown.duration.is_none() {
+        for revert in cancelled {
+            println!(
+                "cancelled the pending switch {} of {}",
+                revert.state, revert.device_id
+            );
+        }
+        return res;
+    }
+
+    if let Some(revert) = reverts.first() {
+        let at = scheduler::format_time(&scheduler::SystemClock, revert.due);
+        if countdown.no_wait {
+            println!("switch {} at {} - sent by 'schedule run'", revert.state, at);
+        } else {
+            println!("switch {} at {} - keep this running", revert.state, at);
+            countdown::await_reverts(&path, &devices, &reverts)?;
+        }
+    }
+    res
+}
+
+/// runs `f` for the selected devices - in parallel for multiple devices
+fn for_targets<F>(
+    device_cache: &DeviceCache,
+    targets: &Targets,
+    parallel: usize,
+    f: F,
+) -> Result<()>
+where
+    F: Fn(&Device) -> Result<String> + Send + Sync + 'static,
+{
+    let devices = select(device_cache, targets)?;
+    report(targets, fanout::fan_out(devices, parallel, f))
+}
+
+/// the selected devices with their keys
+fn select(device_cache: &DeviceCache, targets: &Targets) -> Result<Vec<Device>> {
+    let devices = with_keys(device_cache.select(targets)?)?;
+    if devices.is_empty() {
+        return Err(Error::GenericError {
+            msg: "no devices selected".into(),
+        });
+    }
+    Ok(devices)
+}
+
+/// prints the plain response for a single device - one line per device otherwise
+fn report(targets: &Targets, results: Vec<(Device, Result<String>)>) -> Result<()> {
+    if !targets.is_batch() {
+        for (_, res) in results {
+            println!("{}", res?);
+        }
+        return Ok(());
+    }
+    // one line per device
+    let compact = |res: &String| match serde_json::from_str::<serde_json::Value>(res) {
+        Ok(value) => value.to_string(),
+        Err(_) => res.clone(),
+    };
+    failures(fanout::print_results(&results, compact), results.len())
+}
+
+fn failures(failed: usize, total: usize) -> Result<()> {
+    if failed > 0 {
+        Err(Error::PartialFailure { failed, total })
+    } else {
+        Ok(())
+    }
+}
+
+/// a forgotten revert can be expensive (pumps, heaters) - so remind on every call
+fn warn_overdue_reverts() {
+    let countdowns =
+        countdown::Countdowns::load(countdown::Countdowns::default_path()).unwrap_or_default();
+    for revert in countdowns.overdue(countdown::now()) {
+        eprintln!(
+            "warning: switch {} of {} was due at {} and is not sent yet{} - run 'schedule run'",
+            revert.state,
+            revert.device_id,
+            scheduler::format_time(&scheduler::SystemClock, revert.due),
+            revert
+                .last_error
+                .map(|err| format!(" ({})", err))
+                .unwrap_or_default()
+        );
+    }
+}
+
+fn schedule(cmd: ScheduleCommand, device_cache: &DeviceCache) -> Result<()> {
+    let mut schedule = scheduler::Schedule::load(scheduler::Schedule::default_path())?;
+    match cmd {
+        ScheduleCommand::List => {
+            for job in schedule.jobs() {
+                println!("{}", job);
+            }
+            Ok(())
+        }
+        ScheduleCommand::Add {
+            device_id,
+            outlet,
+            trigger,
+            action,
+        } => {
+            device_cache.lookup(device_id.as_str())?;
+            println!("{}", schedule.add(&device_id, outlet, trigger, action)?);
+            schedule.save()
+        }
+        ScheduleCommand::Remove { id } => {
+            if !schedule.remove(id) {
+                return Err(Error::GenericError {
+                    msg: format!("no job with the id {}", id),
+                });
+            }
+            schedule.save()
+        }
+        ScheduleCommand::Location {
+            latitude,
+            longitude,
+        } => {
+            schedule.set_location(solar::Location {
+                latitude,
+                longitude,
+            })?;
+            schedule.save()
+        }
+        ScheduleCommand::Preview { id, trigger, count } => {
+            let clock = scheduler::SystemClock;
+            let now = clock.now();
+            let triggers = match (trigger, id) {
+                (Some(trigger), _) => vec![(trigger.to_string(), trigger)],
+                (None, id) => schedule
+                    .jobs()
+                    .iter()
+                    .filter(|job| id.map(|id| id == job.id).unwrap_or(true))
+                    .map(|job| (job.to_string(), job.trigger.clone()))
+                    .collect(),
+            };
+            if triggers.is_empty() {
+                return Err(Error::GenericError {
+                    msg: "no such job".into(),
+                });
+            }
+            for (title, trigger) in triggers {
+                let runs = schedule.preview(&trigger, &clock, now, count)?;
+                println!("{}", title);
+                for ts in runs {
+                    println!("  {}", scheduler::format_time(&clock, ts));
+                }
+            }
+            Ok(())
+        }
+        ScheduleCommand::Run => {
+            println!("run {} jobs", schedule.jobs().len());
+            Scheduler::new(with_keys(device_cache.devices())?).run()
+        }
+    }
+}
+
+/// the saved devices with the target id and the device to compare / restore
+fn backup_pairs(
+    device_cache: &DeviceCache,
+    backup: &backup::Backup,
+    device_id: Option<String>,
+    to: Option<String>,
+) -> Result<Vec<(backup::DeviceBackup, String, Result<Device>)>> {
+    let pairs = match device_id {
+        Some(device_id) => {
+            let saved = backup.device(&device_id)?.clone();
+            vec![(saved, to.unwrap_or(device_id))]
+        }
+        None => backup
+            .devices
+            .iter()
+            .map(|d| (d.clone(), d.device_id.clone()))
+            .collect(),
+    };
+    let devices = with_keys(
+        pairs
+            .iter()
+            .filter_map(|(_, target)| device_cache.lookup(target.as_str()).ok())
+            .collect(),
+    )?;
+    Ok(pairs
+        .into_iter()
+        .map(|(saved, target)| {
+            let device = devices
+                .iter()
+                .find(|d| d.id == target)
+                .cloned()
+                .ok_or_else(|| Error::DeviceNotFound {
+                    device_id: target.clone(),
+                });
+            (saved, target, device)
+        })
+        .collect())
+}
+
+fn report_cmd(cmd: ReportCommand, device_cache: &DeviceCache) -> Result<()> {
+    match cmd {
+        ReportCommand::Firmware {
+            policy,
+            min_version,
+            json,
+            parallel: Parallel { parallel },
+        } => {
+            let mut policy = match policy {
+                Some(path) => report::Policy::load(&path)?,
+                None => report::Policy::default(),
+            };
+            if min_version.is_some() {
+                policy.min_version = min_version;
+            }
+            let entries = report::firmware(with_keys(device_cache.devices())?, &policy, parallel);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                report::print_firmware_table(&entries);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn group(cmd: GroupCommand, device_cache: &DeviceCache) -> Result<()> {
+    let mut groups = groups::Groups::load(groups::Groups::default_path())?;
+    match cmd {
+        GroupCommand::List => {
+            for (name, members) in groups.groups() {
+                let members = members.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+                println!("{}: {}", name, members.join(" "));
+            }
+            Ok(())
+        }
+        GroupCommand::Set { name, members } => {
+            for member in &members {
+                device_cache.lookup(member.device_id.as_str())?;
+            }
+            groups.set_group(&name, members)?;
+            groups.save()
+        }
+        GroupCommand::Remove { name } => {
+            groups.group(&name)?;
+            groups.remove_group(&name);
+            groups.save()
+        }
+        GroupCommand::Switch {
+            name,
+            state,
+            parallel: Parallel { parallel },
+        } => {
+            let members = groups
+                .group(&name)?
+                .iter()
+                .map(|member| groups::SceneMember {
+                    member: member.clone(),
+                    state,
+                })
+                .collect::<Vec<_>>();
+            apply_members(device_cache, &members, parallel)
+        }
+    }
+}
+
+fn scene(cmd: SceneCommand, device_cache: &DeviceCache) -> Result<()> {
+    let mut groups = groups::Groups::load(groups::Groups::default_path())?;
+    match cmd {
+        SceneCommand::List => {
+            for (name, members) in groups.scenes() {
+                let members = members.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+                println!("{}: {}", name, members.join(" "));
+            }
+            Ok(())
+        }
+        SceneCommand::Set { name, members } => {
+            for member in &members {
+                device_cache.lookup(member.member.device_id.as_str())?;
+            }
+            groups.set_scene(&name, members)?;
+            groups.save()
+        }
+        SceneCommand::Remove { name } => {
+            groups.scene(&name)?;
+            groups.remove_scene(&name);
+            groups.save()
+        }
+        SceneCommand::Apply {
+            name,
+            parallel: Parallel { parallel },
+        } => apply_members(device_cache, groups.scene(&name)?, parallel),
+    }
+}
+
+fn apply_members(
+    device_cache: &DeviceCache,
+    members: &[groups::SceneMember],
+    parallel: usize,
+) -> Result<()> {
+    let results = groups::apply(&with_keys(device_cache.devices())?, members, parallel)?;
+    failures(
+        fanout::print_results(&results, |msg| msg.clone()),
+        results.len(),
+    )
+}
+
+fn batch_flash(
+    devices: Vec<Device>,
+    bin: Binary,
+    httpd_ip: Option<IpAddr>,
+    httpd_port: u16,
+    parallel: usize,
+    timeout: Duration,
+    verify_timeout: Option<Duration>,
+) -> Result<()> {
+    let httpd_ip = match (httpd_ip, devices.first()) {
+        (Some(ip), _) => ip,
+        (None, Some(device)) => netutils::matching_host_ip_for(&device.ip)?,
+        (None, None) => {
+            return Err(Error::GenericError {
+                msg: "no devices selected".into(),
+            })
+        }
+    };
+    let httpd = Httpd::new(&httpd_ip, httpd_port, &bin)?
+        .workers(parallel)
+        .verbose(false);
+    println!(
+        "startup the embedded web-server at {} to serve the binary",
+        httpd.local_addr()
+    );
+    let transfers = httpd.transfers();
+    let (bin_endpoint, _) = httpd.start();
+
+    let total = devices.len();
+    let mut batch = BatchFlash::new(bin_endpoint, bin.sha256sum(), transfers, timeout);
+    if let Some(verify_timeout) = verify_timeout {
+        batch = batch.verify(verify_timeout);
+    }
+    let results = fanout::fan_out(devices, parallel, move |device| batch.flash(device));
+
+    println!();
+    failures(fanout::print_results(&results, |msg| msg.clone()), total)
+}
+
 fn init_logger(args: &Args) {
     let filter = {
         let level = if args.debug { "debug" } else { "info" };
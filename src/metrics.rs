@@ -0,0 +1,264 @@
+use crate::daemon::RegistryEntry;
+use crate::*;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+/// process wide counters - fed by `Device` and `Httpd`
+#[derive(Debug, Default)]
+struct Metrics {
+    /// (sum in seconds, count) per endpoint
+    latency: BTreeMap<String, (f64, u64)>,
+    /// per (endpoint, code) - the code is the diy error code or 'transport'
+    errors: BTreeMap<(String, String), u64>,
+    ota_chunks: u64,
+    ota_bytes: u64,
+    /// per result from the devices 'upload done' post
+    ota_downloads: BTreeMap<String, u64>,
+}
+
+pub fn observe_request(endpoint: &str, duration: Duration, err: Option<&Error>) {
+    let mut metrics = METRICS.lock().unwrap();
+    let latency = metrics.latency.entry(endpoint.to_string()).or_default();
+    latency.0 += duration.as_secs_f64();
+    latency.1 += 1;
+
+    let code = match err {
+        None => return,
+        Some(Error::DeviceError { code }) => code.to_string(),
+        Some(Error::ReqwestError { .. }) | Some(Error::IOError { .. }) => "transport".to_string(),
+        Some(_) => "other".to_string(),
+    };
+    *metrics
+        .errors
+        .entry((endpoint.to_string(), code))
+        .or_default() += 1;
+}
+
+pub fn ota_chunk_served(bytes: usize) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.ota_chunks += 1;
+    metrics.ota_bytes += bytes as u64;
+}
+
+/// the device has posted the download result, e.g. '{"error":0}'
+pub fn ota_download_done(result: &str) {
+    let code = serde_json::from_str::<Value>(result)
+        .ok()
+        .and_then(|v| v.get("error").and_then(Value::as_i64))
+        .map(|code| code.to_string())
+        .unwrap_or_else(|| "unknown".into());
+    *METRICS
+        .lock()
+        .unwrap()
+        .ota_downloads
+        .entry(code)
+        .or_default() += 1;
+}
+
+/// renders the device gauges and the process counters in the prometheus text format
+pub fn render(entries: &[RegistryEntry], now: u64) -> String {
+    let mut out = String::new();
+
+    let mut relay = Vec::new();
+    let mut rssi = Vec::new();
+    let mut last_seen = Vec::new();
+    let mut seq = Vec::new();
+    let mut reachable = Vec::new();
+    for entry in entries {
+        let device = &entry.device;
+        let labels = format!(
+            "device_id=\"{}\",name=\"{}\"",
+            escape(&device.id),
+            escape(&device.name)
+        );
+        let state = device.txt_data().ok();
+        // the txt record is only as recent as the last announcement
+        for (outlet, on) in outlets(entry.info.as_ref()) {
+            relay.push((
+                format!("{},outlet=\"{}\"", labels, outlet),
+                if on { 1.0 } else { 0.0 },
+            ));
+        }
+        let signal_strength = entry
+            .info
+            .as_ref()
+            .and_then(|info| info.signal_strength)
+            .map(f64::from)
+            .or_else(|| {
+                state
+                    .as_ref()
+                    .and_then(|s| s.get("rssi"))
+                    .and_then(Value::as_f64)
+            });
+        if let Some(signal_strength) = signal_strength {
+            rssi.push((labels.clone(), signal_strength));
+        }
+        if let Some(ts) = entry.last_seen {
+            last_seen.push((labels.clone(), now.saturating_sub(ts) as f64));
+        }
+        if let Some(s) = device.txt_field("seq").and_then(|s| s.parse().ok()) {
+            seq.push((labels.clone(), s));
+        }
+        if let Some(r) = entry.reachable {
+            reachable.push((labels, if r { 1.0 } else { 0.0 }));
+        }
+    }
+
+    #[rustfmt::skip]
+    let gauges = [
+        ("sonoff_diy_relay_state", "relay state per outlet (1 = on)", relay),
+        ("sonoff_diy_signal_strength_dbm", "wifi signal strength", rssi),
+        ("sonoff_diy_last_seen_seconds", "seconds since the last mdns announcement", last_seen),
+        ("sonoff_diy_mdns_seq", "the 'seq' from the txt record", seq),
+        ("sonoff_diy_info_reachable", "'/zeroconf/info' was reachable in the last round", reachable),
+    ];
+    for (name, help, samples) in gauges.iter() {
+        write_metric(&mut out, name, help, "gauge", samples);
+    }
+
+    let metrics = METRICS.lock().unwrap();
+    let (sums, counts) = metrics
+        .latency
+        .iter()
+        .map(|(endpoint, (sum, count))| {
+            let labels = format!("endpoint=\"{}\"", escape(endpoint));
+            ((labels.clone(), *sum), (labels, *count as f64))
+        })
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+    let _ = writeln!(
+        out,
+        "# HELP sonoff_diy_request_duration_seconds requests to the devices"
+    );
+    let _ = writeln!(out, "# TYPE sonoff_diy_request_duration_seconds summary");
+    write_samples(&mut out, "sonoff_diy_request_duration_seconds_sum", &sums);
+    write_samples(
+        &mut out,
+        "sonoff_diy_request_duration_seconds_count",
+        &counts,
+    );
+
+    let errors = metrics
+        .errors
+        .iter()
+        .map(|((endpoint, code), n)| {
+            let labels = format!("endpoint=\"{}\",code=\"{}\"", escape(endpoint), code);
+            (labels, *n as f64)
+        })
+        .collect::<Vec<_>>();
+    write_metric(
+        &mut out,
+        "sonoff_diy_request_errors_total",
+        "failed requests by endpoint and diy error code",
+        "counter",
+        &errors,
+    );
+
+    write_metric(
+        &mut out,
+        "sonoff_diy_ota_chunks_served_total",
+        "firmware chunks served by the embedded web-server",
+        "counter",
+        &[(String::new(), metrics.ota_chunks as f64)],
+    );
+    write_metric(
+        &mut out,
+        "sonoff_diy_ota_bytes_served_total",
+        "firmware bytes served by the embedded web-server",
+        "counter",
+        &[(String::new(), metrics.ota_bytes as f64)],
+    );
+    let downloads = metrics
+        .ota_downloads
+        .iter()
+        .map(|(code, n)| (format!("code=\"{}\"", code), *n as f64))
+        .collect::<Vec<_>>();
+    write_metric(
+        &mut out,
+        "sonoff_diy_ota_downloads_total",
+        "finished firmware downloads by the reported error code",
+        "counter",
+        &downloads,
+    );
+    out
+}
+
+/// (outlet, on) from the device info - multi channel devices report 'switches'
+///
+/// no samples if the relay state is unknown
+fn outlets(info: Option<&DeviceInfo>) -> Vec<(u8, bool)> {
+    match info {
+        Some(DeviceInfo {
+            switches: Some(switches),
+            ..
+        }) => switches
+            .iter()
+            .map(|s| (s.outlet, s.switch == "on"))
+            .collect(),
+        Some(DeviceInfo {
+            switch: Some(switch),
+            ..
+        }) => vec![(0, switch == "on")],
+        _ => Vec::new(),
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, type_: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, type_);
+    write_samples(out, name, samples);
+}
+
+fn write_samples(out: &mut String, name: &str, samples: &[(String, f64)]) {
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn info(value: Value) -> DeviceInfo {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn outlets_from_single_and_multi_channel_devices() {
+        assert_eq!(
+            outlets(Some(&info(json!({ "switch": "on" })))),
+            vec![(0, true)]
+        );
+        assert_eq!(
+            outlets(Some(&info(json!({ "switches": [
+                { "switch": "off", "outlet": 0 },
+                { "switch": "on", "outlet": 1 },
+            ]})))),
+            vec![(0, false), (1, true)]
+        );
+        assert!(outlets(None).is_empty());
+        // unknown relay state
+        assert!(outlets(Some(&info(json!({ "switch": null })))).is_empty());
+        assert!(outlets(Some(&DeviceInfo::default())).is_empty());
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
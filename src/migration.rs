@@ -0,0 +1,171 @@
+use crate::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    net::IpAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum MigrationStatus {
+    Pending,
+    Done { ip: IpAddr },
+    Failed { error: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    device_id: String,
+    #[serde(flatten)]
+    status: MigrationStatus,
+}
+
+/// the progress of a wifi migration - persisted after every device
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Report {
+    essid: String,
+    devices: Vec<Entry>,
+}
+
+impl Report {
+    fn status(&self, device_id: &str) -> MigrationStatus {
+        self.devices
+            .iter()
+            .find(|e| e.device_id == device_id)
+            .map(|e| e.status.clone())
+            .unwrap_or(MigrationStatus::Pending)
+    }
+
+    fn set_status(&mut self, device_id: &str, status: MigrationStatus) {
+        match self.devices.iter_mut().find(|e| e.device_id == device_id) {
+            Some(entry) => entry.status = status,
+            None => self.devices.push(Entry {
+                device_id: device_id.to_string(),
+                status,
+            }),
+        }
+    }
+}
+
+/// moves devices one after another in a new wifi network.
+///
+/// every device must show up in the new network before the next one is moved.
+/// the migration stops at the first failure and can be resumed.
+pub struct WifiMigration {
+    essid: String,
+    pwd: String,
+    timeout: Duration,
+    path: PathBuf,
+}
+
+impl WifiMigration {
+    pub fn new(essid: &str, pwd: &str, timeout: Duration) -> Self {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-wifi-migration.json");
+        WifiMigration {
+            essid: essid.to_string(),
+            pwd: pwd.to_string(),
+            timeout,
+            path,
+        }
+    }
+
+    /// where the progress report is written
+    pub fn report_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.path = path.into();
+        self
+    }
+
+    pub fn run(
+        &self,
+        devices: &[Device],
+        device_cache: &mut DeviceCache,
+        restart: bool,
+    ) -> Result<()> {
+        wifi::validate(&self.essid, &self.pwd)?;
+        let mut report = if restart {
+            Report::default()
+        } else {
+            self.resume()
+        };
+        report.essid = self.essid.clone();
+
+        for (idx, device) in devices.iter().enumerate() {
+            let prefix = format!("[{}/{}] {}", idx + 1, devices.len(), device.id);
+            if let MigrationStatus::Done { ip } = report.status(&device.id) {
+                println!("{}: already in '{}' at {} - skip", prefix, self.essid, ip);
+                continue;
+            }
+
+            println!("{}: move in '{}'", prefix, self.essid);
+            match self.migrate(device) {
+                Ok(moved) => {
+                    println!("{}: connected at {}", prefix, moved.ip);
+                    device_cache.update(&moved)?;
+                    report.set_status(&device.id, MigrationStatus::Done { ip: moved.ip });
+                    self.save(&report)?;
+                }
+                Err(err) => {
+                    report.set_status(
+                        &device.id,
+                        MigrationStatus::Failed {
+                            error: err.to_string(),
+                        },
+                    );
+                    self.save(&report)?;
+                    return Err(Error::MigrationFailed {
+                        device_id: device.id.clone(),
+                        source: Box::new(err),
+                    });
+                }
+            }
+        }
+
+        println!("all devices are in '{}'", self.essid);
+        self.remove()
+    }
+
+    fn migrate(&self, device: &Device) -> Result<Device> {
+        let response = device.wifi(self.essid.as_str(), self.pwd.as_str())?;
+        wifi::await_reconnect(device, &self.essid, &response, self.timeout)
+    }
+
+    fn resume(&self) -> Report {
+        match self.load() {
+            Ok(report) if report.essid == self.essid => {
+                println!("resume the migration in '{}'", self.essid);
+                report
+            }
+            Ok(report) => {
+                println!("discard the unfinished migration in '{}'", report.essid);
+                Report::default()
+            }
+            Err(_) => Report::default(),
+        }
+    }
+
+    fn load(&self) -> Result<Report> {
+        debug!("load migration report from {}", self.path.display());
+        let file = File::open(&self.path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    fn save(&self, report: &Report) -> Result<()> {
+        debug!("save migration report to: {}", self.path.display());
+        fsutils::write_atomic(&self.path, &serde_json::to_vec_pretty(report)?, false)
+    }
+
+    fn remove(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use crate::daemon::Registry;
+use crate::*;
+use log::debug;
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::TryFrom, sync::mpsc, thread, time::Duration};
+
+/// how long the scanner listens for announcements per round
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(5);
+
+/// wait time before the next try after a lost broker connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub struct MqttOpts {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// topic prefix, e.g. 'sonoff-diy'
+    pub prefix: String,
+    pub service_name: String,
+    /// time between the discovery / polling rounds
+    pub interval: Duration,
+    /// a device is offline if it was not announced within this time
+    pub offline_after: Duration,
+    /// publish home assistant discovery configs with this discovery prefix
+    pub homeassistant: Option<String>,
+}
+
+/// publishes the state of the discovered devices and forwards commands to them
+///
+///   <prefix>/bridge/availability   'online' / 'offline' (last will)
+///   <prefix>/<id>/availability     'online' / 'offline' - from the mdns announcements
+///   <prefix>/<id>/state            the txt state merged with the device info
+///   <prefix>/<id>/set              commands: 'on', 'off' or json, e.g.
+///                                    {"switch": "on"}, {"startup": "stay"},
+///                                    {"pulse": "on", "pulseWidth": 2000}
+pub struct MqttBridge {
+    opts: MqttOpts,
+    registry: Registry,
+}
+
+impl MqttBridge {
+    pub fn new(opts: MqttOpts, devices: Vec<Device>) -> Self {
+        MqttBridge {
+            opts,
+            registry: Registry::new(devices),
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let mut options = MqttOptions::new(&self.opts.client_id, &self.opts.host, self.opts.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            self.bridge_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((ref username, ref password)) = self.opts.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut connection) = Client::new(options, 64);
+        println!(
+            "connect to the mqtt broker at {}:{}",
+            self.opts.host, self.opts.port
+        );
+
+        // the device requests must not block the mqtt event loop
+        let (commands, queue) = mpsc::channel::<Publish>();
+        let (registry, prefix) = (self.registry.clone(), self.opts.prefix.clone());
+        thread::spawn(move || {
+            for msg in queue {
+                let device_id = match device_id(&prefix, &msg.topic) {
+                    Some(device_id) => device_id,
+                    None => continue,
+                };
+                let res = registry
+                    .get(device_id)
+                    .and_then(|device| apply_command(&device, &msg.payload));
+                match res {
+                    Ok(_) => println!("{}: {}", msg.topic, String::from_utf8_lossy(&msg.payload)),
+                    Err(err) => eprintln!("{}: {}", msg.topic, err),
+                }
+            }
+        });
+
+        let mut cmd_client = client.clone();
+        let (prefix, bridge_topic) = (self.opts.prefix.clone(), self.bridge_topic());
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        println!("connected to the mqtt broker");
+                        let res = cmd_client
+                            .subscribe(format!("{}/+/set", prefix), QoS::AtLeastOnce)
+                            .and_then(|_| {
+                                cmd_client.publish(&bridge_topic, QoS::AtLeastOnce, true, "online")
+                            });
+                        if let Err(err) = res {
+                            eprintln!("unable to subscribe: {}", err);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(msg))) => {
+                        if commands.send(msg).is_err() {
+                            eprintln!("the command worker is gone");
+                        }
+                    }
+                    Ok(event) => debug!("mqtt event: {:?}", event),
+                    Err(err) => {
+                        eprintln!("mqtt connection error: {}", err);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        self.publish_loop(client)
+    }
+
+    /// discovers the devices and publishes the availability and state changes
+    fn publish_loop(&self, mut client: Client) -> Result<()> {
+        let scanner = Scanner::new(self.opts.service_name.clone());
+        let mut published: HashMap<String, (bool, Option<Value>)> = HashMap::new();
+        loop {
+            match scanner.announcements(DISCOVERY_WINDOW) {
+                Ok(devices) => {
+                    for device in devices {
+                        self.registry.announced(device);
+                    }
+                }
+                Err(err) => eprintln!("discovery failed: {}", err),
+            }
+
+            for device in self.registry.devices() {
+                let online = self
+                    .registry
+                    .is_present(&device.id, self.opts.offline_after);
+                let state = if online {
+                    device_state(&device)
+                        .map_err(|err| debug!("no state from {}: {}", device.id, err))
+                        .ok()
+                } else {
+                    None
+                };
+
+                let last = published.get(&device.id);
+                if last.map(|(o, _)| *o) != Some(online) {
+                    let availability = if online { "online" } else { "offline" };
+                    client.publish(
+                        self.topic(&device, "availability"),
+                        QoS::AtLeastOnce,
+                        true,
+                        availability,
+                    )?;
+                }
+                let last_state = last.and_then(|(_, s)| s.clone());
+                if state.is_some() && state != last_state {
+                    let payload = serde_json::to_vec(&state)?;
+                    client.publish(
+                        self.topic(&device, "state"),
+                        QoS::AtLeastOnce,
+                        true,
+                        payload,
+                    )?;
+                }
+                if let (Some(discovery_prefix), Some(state)) = (&self.opts.homeassistant, &state) {
+                    // (re)publish the configs for new devices and firmware updates
+                    let fw_version = state.get("fwVersion");
+                    if last_state.as_ref().map(|s| s.get("fwVersion")) != Some(fw_version) {
+                        let configs = homeassistant::discovery_configs(
+                            discovery_prefix,
+                            &self.opts.prefix,
+                            &device,
+                            state,
+                        );
+                        for (topic, config) in configs {
+                            let payload = serde_json::to_vec(&config)?;
+                            client.publish(topic, QoS::AtLeastOnce, true, payload)?;
+                        }
+                    }
+                }
+                published.insert(device.id.clone(), (online, state.or(last_state)));
+            }
+            thread::sleep(self.opts.interval);
+        }
+    }
+
+    fn topic(&self, device: &Device, name: &str) -> String {
+        format!("{}/{}/{}", self.opts.prefix, device.id, name)
+    }
+
+    fn bridge_topic(&self) -> String {
+        format!("{}/bridge/availability", self.opts.prefix)
+    }
+}
+
+/// the device id from a '<prefix>/<id>/set' topic
+pub fn device_id<'a>(prefix: &str, topic: &'a str) -> Option<&'a str> {
+    let rest = topic
+        .get(prefix.len()..)
+        .filter(|_| topic.starts_with(prefix))?;
+    match rest.split('/').collect::<Vec<_>>().as_slice() {
+        ["", id, "set"] if !id.is_empty() => Some(id),
+        _ => None,
+    }
+}
+
+/// the state from the txt record merged with the device info
+pub fn device_state(device: &Device) -> Result<Value> {
+    let mut state = device.txt_data().unwrap_or_else(|_| json!({}));
+    if let (Some(state), Value::Object(info)) = (
+        state.as_object_mut(),
+        serde_json::to_value(device.device_info()?)?,
+    ) {
+        for (key, value) in info.into_iter().filter(|(_, v)| !v.is_null()) {
+            state.insert(key, value);
+        }
+    }
+    Ok(state)
+}
+
+/// executes a command from a '<prefix>/<id>/set' message
+pub fn apply_command(device: &Device, payload: &[u8]) -> Result<()> {
+    let payload = String::from_utf8_lossy(payload);
+    let cmd = match payload.trim() {
+        "on" | "off" => json!({ "switch": payload.trim() }),
+        s => serde_json::from_str(s).map_err(|_| Error::InvalidRequest {
+            msg: format!("invalid command: '{}'", s),
+        })?,
+    };
+    let field = |name: &str| cmd.get(name).and_then(Value::as_str);
+
+    let mut applied = false;
+    if let Some(state) = field("switch") {
+        device.switch(state.parse()?)?;
+        applied = true;
+    }
+    if let Some(state) = field("startup") {
+        device.startup(state.parse()?)?;
+        applied = true;
+    }
+    if let Some(state) = field("pulse") {
+        let state = match state {
+            "on" => PulseState::On {
+                width: match cmd.get("pulseWidth") {
+                    Some(Value::Null) | None => device::PULSE_WIDTH_MIN,
+                    Some(width) => width
+                        .as_u64()
+                        .and_then(|w| u32::try_from(w).ok())
+                        .ok_or_else(|| Error::InvalidRequest {
+                            msg: format!("invalid pulse width: {}", width),
+                        })?,
+                },
+            },
+            "off" => PulseState::Off,
+            _ => {
+                return Err(Error::InvalidRequest {
+                    msg: format!("invalid pulse state: '{}'", state),
+                })
+            }
+        };
+        device.pulse(state)?;
+        applied = true;
+    }
+
+    if applied {
+        Ok(())
+    } else {
+        Err(Error::InvalidRequest {
+            msg: format!("unknown command: '{}'", payload),
+        })
+    }
+}
@@ -0,0 +1,312 @@
+use crate::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs::File, io::BufReader, net::IpAddr, path::PathBuf, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum Step {
+    Discover,
+    Info,
+    Unlock,
+    Validate,
+    Flash,
+    Verify,
+}
+
+impl Step {
+    const ALL: [Step; 6] = [
+        Step::Discover,
+        Step::Info,
+        Step::Unlock,
+        Step::Validate,
+        Step::Flash,
+        Step::Verify,
+    ];
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Step::Discover => "discover the device",
+            Step::Info => "query the device info",
+            Step::Unlock => "unlock the device",
+            Step::Validate => "validate the binary",
+            Step::Flash => "flash the binary and wait for completion",
+            Step::Verify => "verify the new firmware",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+pub struct ProvisionOpts {
+    pub device_id: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    pub bin: Binary,
+    pub httpd_port: u16,
+    pub httpd_ip: Option<IpAddr>,
+    pub scan_timeout: Duration,
+    pub flash_timeout: Duration,
+    pub verify_timeout: Duration,
+}
+
+/// the progress of a provisioning run - persisted after every step
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Progress {
+    device: Option<Device>,
+    completed: Option<Step>,
+    bin_sha256sum: Option<String>,
+}
+
+impl Progress {
+    fn is_done(&self, step: Step) -> bool {
+        self.completed.map(|c| c >= step).unwrap_or(false)
+    }
+
+    fn device(&self) -> Result<&Device> {
+        self.device.as_ref().ok_or(Error::GenericError {
+            msg: "device not discovered".into(),
+        })
+    }
+}
+
+/// chains the steps to flash a device in one run.
+///
+/// the progress is saved after every step, so a failed run can be resumed.
+pub struct Provision {
+    opts: ProvisionOpts,
+    bin_sha256sum: String,
+    store: ProvisionStore,
+}
+
+impl Provision {
+    pub fn new(opts: ProvisionOpts) -> Self {
+        let bin_sha256sum = opts.bin.sha256sum();
+        Provision {
+            opts,
+            bin_sha256sum,
+            store: ProvisionStore::load().unwrap_or_default(),
+        }
+    }
+
+    pub fn run(&mut self, device_cache: &mut DeviceCache, restart: bool) -> Result<()> {
+        let mut progress = if restart {
+            Progress::default()
+        } else {
+            self.resume()
+        };
+
+        for (idx, step) in Step::ALL.iter().enumerate() {
+            if progress.is_done(*step) {
+                continue;
+            }
+            println!("[{}/{}] {}", idx + 1, Step::ALL.len(), step);
+            self.run_step(*step, &mut progress, device_cache)
+                .map_err(|err| Error::ProvisionFailed {
+                    step: step.to_string(),
+                    source: Box::new(err),
+                })?;
+            progress.completed = Some(*step);
+            self.store.update(&progress)?;
+        }
+
+        let device = progress.device()?;
+        println!("provisioning of {} done", device.id);
+        self.store.remove(&device.id)
+    }
+
+    fn resume(&self) -> Progress {
+        let mut progress = match self.store.find(&self.opts) {
+            Some(progress) => progress,
+            None => {
+                if self.opts.device_id.is_none() && self.opts.ip.is_none() {
+                    self.store.print_unfinished();
+                }
+                return Progress::default();
+            }
+        };
+
+        if let (Some(device), Some(completed)) = (&progress.device, progress.completed) {
+            println!(
+                "resume the provisioning of {} after step: '{}'",
+                device.id, completed
+            );
+        }
+
+        // validate and flash again when the binary has changed
+        if progress.is_done(Step::Validate)
+            && progress.bin_sha256sum.as_ref() != Some(&self.bin_sha256sum)
+        {
+            println!("the binary has changed since the last run");
+            progress.completed = Some(Step::Unlock);
+        }
+        progress
+    }
+
+    fn run_step(
+        &self,
+        step: Step,
+        progress: &mut Progress,
+        device_cache: &mut DeviceCache,
+    ) -> Result<()> {
+        match step {
+            Step::Discover => {
+                let device = self.discover()?;
+                println!("{}", device);
+                device_cache.add(&device)?;
+                progress.device = Some(device);
+            }
+            Step::Info => println!("{}", progress.device()?.info()?),
+            Step::Unlock => flash::ensure_unlocked(progress.device()?)?,
+            Step::Validate => {
+                self.opts.bin.validate()?;
+                progress.bin_sha256sum = Some(self.bin_sha256sum.clone());
+            }
+            Step::Flash => println!("{}", self.flash(progress.device()?)?),
+            Step::Verify => println!(
+                "{}",
+                verify_firmware(progress.device()?, self.opts.verify_timeout)?
+            ),
+        };
+        Ok(())
+    }
+
+    fn discover(&self) -> Result<Device> {
+        if let Some(ip) = self.opts.ip {
+            return Device::probe(ip, self.opts.port);
+        }
+
+        let mut scanner = Scanner::new("_ewelink._tcp".into());
+        let device = match self.opts.device_id {
+            Some(ref device_id) => scanner.find(device_id, self.opts.scan_timeout)?,
+            None => scanner.scan_timeout(self.opts.scan_timeout)?,
+        };
+        device.ok_or(Error::Timeout {
+            msg: "no device found".into(),
+        })
+    }
+
+    fn flash(&self, device: &Device) -> Result<String> {
+        let httpd_ip = match self.opts.httpd_ip {
+            Some(ip) => ip,
+            None => netutils::matching_host_ip_for(&device.ip)?,
+        };
+        let httpd = Httpd::new(&httpd_ip, self.opts.httpd_port, &self.opts.bin)?.verbose(false);
+        debug!("serve the binary from {}", httpd.local_addr());
+        let transfers = httpd.transfers();
+        let (bin_endpoint, _) = httpd.start();
+
+        let batch = BatchFlash::new(
+            bin_endpoint,
+            self.bin_sha256sum.clone(),
+            transfers,
+            self.opts.flash_timeout,
+        );
+        device.flash(batch.download_url(device), self.bin_sha256sum.clone())?;
+        batch.await_completion(device)
+    }
+}
+
+/// unfinished provisioning runs
+struct ProvisionStore(Vec<Progress>, PathBuf);
+
+impl ProvisionStore {
+    fn new(runs: Vec<Progress>) -> Self {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-provision.json");
+        Self(runs, path)
+    }
+
+    /// looks up the unfinished run for the requested device.
+    ///
+    /// a run is only resumed for a matching device id or ip - without them the
+    /// scan could find any device.
+    fn find(&self, opts: &ProvisionOpts) -> Option<Progress> {
+        let matches = |p: &&Progress| match (&p.device, &opts.device_id, &opts.ip) {
+            (Some(device), Some(device_id), _) => &device.id == device_id,
+            (Some(device), _, Some(ip)) => &device.ip == ip,
+            _ => false,
+        };
+        self.0.iter().find(matches).cloned()
+    }
+
+    /// hints how to resume the unfinished runs
+    fn print_unfinished(&self) {
+        for progress in &self.0 {
+            if let (Some(device), Some(completed)) = (&progress.device, progress.completed) {
+                println!(
+                    "unfinished provisioning of {} after step: '{}' - run with '--device-id {}' to resume",
+                    device.id, completed, device.id
+                );
+            }
+        }
+    }
+
+    fn update(&mut self, progress: &Progress) -> Result<()> {
+        if let Some(ref device) = progress.device {
+            self.0
+                .retain(|p| p.device.as_ref().map(|d| &d.id) != Some(&device.id));
+            self.0.push(progress.clone());
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, device_id: &str) -> Result<()> {
+        self.0
+            .retain(|p| p.device.as_ref().map(|d| d.id.as_str()) != Some(device_id));
+        self.save()
+    }
+
+    fn load() -> Result<Self> {
+        let mut store = ProvisionStore::new(Vec::new());
+        debug!("load provisioning progress from {}", store.1.display());
+        let file = File::open(&store.1)?;
+        store.0 = serde_json::from_reader(BufReader::new(file))?;
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<()> {
+        debug!("save provisioning progress to: {}", self.1.display());
+        fsutils::write_atomic(&self.1, &serde_json::to_vec(&self.0)?, false)
+    }
+}
+
+impl Default for ProvisionStore {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn resume_only_the_requested_device() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let store = ProvisionStore::new(vec![Progress {
+            device: Some(Device::new("a", "1000000001", &[], ip, 8081)),
+            completed: Some(Step::Unlock),
+            bin_sha256sum: None,
+        }]);
+        let opts = |device_id: Option<&str>, ip: Option<IpAddr>| ProvisionOpts {
+            device_id: device_id.map(String::from),
+            ip,
+            port: 8081,
+            bin: Binary::new("shell.nix").unwrap(),
+            httpd_port: 0,
+            httpd_ip: None,
+            scan_timeout: Duration::from_secs(1),
+            flash_timeout: Duration::from_secs(1),
+            verify_timeout: Duration::from_secs(1),
+        };
+
+        assert!(store.find(&opts(Some("1000000001"), None)).is_some());
+        assert!(store.find(&opts(None, Some(ip))).is_some());
+        assert!(store.find(&opts(Some("1000000002"), None)).is_none());
+        // the scan could find any device
+        assert!(store.find(&opts(None, None)).is_none());
+    }
+}
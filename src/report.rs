@@ -0,0 +1,251 @@
+use crate::*;
+use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, fmt, fs::File, io::BufReader, path::Path, str::FromStr};
+
+/// a dotted firmware version, e.g. '3.3.0'
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(Vec<u32>);
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.trim()
+            .split('.')
+            .map(|n| n.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map(|mut parts| {
+                // '3.6' == '3.6.0'
+                while parts.len() > 1 && parts.last() == Some(&0) {
+                    parts.pop();
+                }
+                Version(parts)
+            })
+            .map_err(|_| Error::ParserError {
+                msg: format!("invalid firmware version '{}' - expected e.g. '3.6.0'", s),
+            })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = self.0.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        while parts.len() < 3 {
+            parts.push("0".into());
+        }
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// the minimum firmware versions, e.g.
+///
+/// {"minVersion": "3.6.0", "types": {"diy_plug": "3.7.0"}}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Policy {
+    /// for all devices without an entry in `types`
+    #[serde(default)]
+    pub min_version: Option<Version>,
+    /// per device type from the txt record
+    #[serde(default)]
+    pub types: HashMap<String, Version>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        debug!("load firmware policy from {}", path.display());
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn min_version(&self, device_type: Option<&str>) -> Option<&Version> {
+        device_type
+            .and_then(|t| self.types.get(t))
+            .or(self.min_version.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareStatus {
+    Current,
+    Outdated,
+    /// no minimum version in the policy or no version from the device
+    Unknown,
+    Unreachable,
+}
+
+impl fmt::Display for FirmwareStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            FirmwareStatus::Current => "current",
+            FirmwareStatus::Outdated => "outdated",
+            FirmwareStatus::Unknown => "unknown",
+            FirmwareStatus::Unreachable => "unreachable",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareEntry {
+    pub device_id: String,
+    pub name: String,
+    pub device_type: Option<String>,
+    pub fw_version: Option<String>,
+    pub min_version: Option<Version>,
+    /// the device accepts ota updates
+    pub ota_unlock: Option<bool>,
+    pub status: FirmwareStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// queries the firmware versions of the devices in parallel and compares them with the policy
+pub fn firmware(devices: Vec<Device>, policy: &Policy, parallel: usize) -> Vec<FirmwareEntry> {
+    fanout::fan_out(devices, parallel, |device| device.device_info())
+        .into_iter()
+        .map(|(device, info)| {
+            let device_type = device.device_type().map(String::from);
+            let min_version = policy.min_version(device_type.as_deref()).cloned();
+            let mut entry = FirmwareEntry {
+                device_id: device.id.clone(),
+                name: device.name.clone(),
+                device_type,
+                fw_version: None,
+                min_version,
+                ota_unlock: None,
+                status: FirmwareStatus::Unknown,
+                error: None,
+            };
+            match info {
+                Ok(info) => {
+                    let version = info
+                        .fw_version
+                        .as_ref()
+                        .and_then(|v| v.parse::<Version>().ok());
+                    entry.status = match (version, &entry.min_version) {
+                        (Some(version), Some(min)) if version < *min => FirmwareStatus::Outdated,
+                        (Some(_), Some(_)) => FirmwareStatus::Current,
+                        _ => FirmwareStatus::Unknown,
+                    };
+                    entry.fw_version = info.fw_version;
+                    entry.ota_unlock = info.ota_unlock;
+                }
+                Err(err) => {
+                    entry.status = FirmwareStatus::Unreachable;
+                    entry.error = Some(err.to_string());
+                }
+            }
+            entry
+        })
+        .collect()
+}
+
+/// prints the entries as a table
+pub fn print_firmware_table(entries: &[FirmwareEntry]) {
+    let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".into());
+    let rows = entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.device_id.clone(),
+                e.name.clone(),
+                show(&e.device_type),
+                show(&e.fw_version),
+                show(&e.min_version.as_ref().map(|v| v.to_string())),
+                match e.ota_unlock {
+                    Some(true) => "unlocked".into(),
+                    Some(false) => "locked".into(),
+                    None => "-".into(),
+                },
+                e.status.to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["ID", "NAME", "TYPE", "FIRMWARE", "MINIMUM", "OTA", "STATUS"];
+    let widths = (0..header.len())
+        .map(|idx| {
+            rows.iter()
+                .map(|row| row[idx].len())
+                .chain(Some(header[idx].len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    let line = |cells: Vec<&str>| {
+        let cells = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>();
+        println!("{}", cells.join("  ").trim_end());
+    };
+
+    line(header.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+    for status in &[FirmwareStatus::Outdated, FirmwareStatus::Unreachable] {
+        let count = entries.iter().filter(|e| e.status == *status).count();
+        if count > 0 {
+            println!("{} of {} devices {}", count, entries.len(), status);
+        }
+    }
+    let locked = entries
+        .iter()
+        .filter(|e| e.status == FirmwareStatus::Outdated && e.ota_unlock == Some(false))
+        .count();
+    if locked > 0 {
+        println!(
+            "{} outdated devices are locked for ota - run 'unlock' first",
+            locked
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_versions() {
+        let v = |s: &str| s.parse::<Version>().unwrap();
+        assert!(v("3.3.0") < v("3.6.0"));
+        assert!(v("3.10.1") > v("3.9"));
+        assert_eq!(v("3.6"), v("3.6.0"));
+        assert_eq!(v("3.6").to_string(), "3.6.0");
+        assert!("3.x".parse::<Version>().is_err());
+        assert!("".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn policy_per_type() {
+        let policy: Policy =
+            serde_json::from_str(r#"{"minVersion": "3.6.0", "types": {"mini": "3.7"}}"#).unwrap();
+        assert_eq!(
+            policy.min_version(Some("mini")).unwrap().to_string(),
+            "3.7.0"
+        );
+        assert_eq!(
+            policy.min_version(Some("diy_plug")).unwrap().to_string(),
+            "3.6.0"
+        );
+        assert_eq!(policy.min_version(None).unwrap().to_string(), "3.6.0");
+    }
+}
@@ -0,0 +1,602 @@
+use crate::*;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+/// how long the scanner listens for announcements per round
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(5);
+
+/// the rules from the config file, e.g.
+///
+/// {"rules": [
+///   {"name": "hall", "when": {"deviceId": "A", "switch": "on"}, "delay": 5,
+///    "then": [{"action": "switch", "deviceId": "B", "state": "on"}]},
+///   {"name": "weak wifi", "when": {"deviceId": "A", "rssiBelow": -80}, "for": 600,
+///    "then": [{"action": "alert", "message": "weak wifi at A"}]},
+///   {"name": "mirror", "when": {"deviceId": "A", "switch": "changed"},
+///    "then": [{"action": "mirror", "deviceId": "B"}]}
+/// ]}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-rules.json");
+        path
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        debug!("load rules from {}", path.display());
+        let file = File::open(path)?;
+        let rules: Rules = serde_json::from_reader(BufReader::new(file))?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let rules: Rules = serde_json::from_str(s)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            let invalid = |msg: &str| Error::InvalidRequest {
+                msg: format!("rule '{}': {}", rule.name, msg),
+            };
+            if rule.when.switch.is_none() && rule.when.rssi_below.is_none() {
+                return Err(invalid("'when' needs 'switch' or 'rssiBelow'"));
+            }
+            if rule.then.is_empty() {
+                return Err(invalid("'then' is empty"));
+            }
+            if rule.when.switch == Some(SwitchCondition::Changed) && rule.hold > 0 {
+                return Err(invalid("'for' is not supported with 'switch': 'changed'"));
+            }
+        }
+        Ok(())
+    }
+
+    /// the ids of the devices which are referenced by the rules
+    pub fn device_ids(&self) -> Vec<&str> {
+        let mut ids = Vec::new();
+        for rule in &self.rules {
+            ids.push(rule.when.device_id.as_str());
+            for effect in &rule.then {
+                match effect {
+                    Effect::Switch { device_id, .. } | Effect::Mirror { device_id } => {
+                        ids.push(device_id.as_str())
+                    }
+                    Effect::Alert { .. } => (),
+                }
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub when: Condition,
+    /// seconds the condition must hold before the rule fires
+    #[serde(default, rename = "for")]
+    pub hold: u64,
+    /// seconds between the firing and the actions
+    #[serde(default)]
+    pub delay: u64,
+    pub then: Vec<Effect>,
+}
+
+/// all given predicates must hold
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Condition {
+    pub device_id: String,
+    #[serde(default)]
+    pub switch: Option<SwitchCondition>,
+    /// the signal strength is below the given dbm
+    #[serde(default)]
+    pub rssi_below: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwitchCondition {
+    On,
+    Off,
+    /// every change of the switch state
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Effect {
+    #[serde(rename_all = "camelCase")]
+    Switch {
+        device_id: String,
+        state: SwitchState,
+    },
+    /// switches the device to the state of the device from the condition
+    #[serde(rename_all = "camelCase")]
+    Mirror {
+        device_id: String,
+    },
+    Alert {
+        message: String,
+    },
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Effect::Switch { device_id, state } => write!(f, "switch {} {}", device_id, state),
+            Effect::Mirror { device_id } => write!(f, "mirror the state on {}", device_id),
+            Effect::Alert { message } => write!(f, "alert '{}'", message),
+        }
+    }
+}
+
+/// the last known state of a device - from the txt record or the device info
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceState {
+    pub switch: Option<SwitchState>,
+    pub rssi: Option<i32>,
+}
+
+impl DeviceState {
+    pub fn from_txt(device: &Device) -> Self {
+        let data = device.txt_data().ok();
+        let field = |name: &str| data.as_ref().and_then(|d| d.get(name).cloned());
+        DeviceState {
+            switch: field("switch").and_then(|s| s.as_str().and_then(|s| s.parse().ok())),
+            rssi: field("rssi").and_then(|s| s.as_i64()).map(|s| s as i32),
+        }
+    }
+
+    pub fn from_info(info: &DeviceInfo) -> Self {
+        DeviceState {
+            switch: info.switch.as_ref().and_then(|s| s.parse().ok()),
+            rssi: info.signal_strength,
+        }
+    }
+
+    /// the fields which are set in the other state replace the own
+    fn merge(&self, other: &DeviceState) -> DeviceState {
+        DeviceState {
+            switch: other.switch.or(self.switch),
+            rssi: other.rssi.or(self.rssi),
+        }
+    }
+}
+
+/// an action which is due at `at` (seconds since the epoch)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Planned {
+    pub at: i64,
+    pub rule: String,
+    pub effect: Effect,
+    /// the state of the device from the condition when the rule fired
+    pub source: DeviceState,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    /// the condition holds since
+    since: Option<i64>,
+    fired: bool,
+}
+
+/// evaluates the rules against the device states - without any io
+pub struct Engine {
+    rules: Vec<Rule>,
+    progress: Vec<Progress>,
+    states: HashMap<String, DeviceState>,
+    planned: Vec<Planned>,
+}
+
+impl Engine {
+    pub fn new(rules: Rules) -> Self {
+        Engine {
+            progress: vec![Progress::default(); rules.rules.len()],
+            rules: rules.rules,
+            states: HashMap::new(),
+            planned: Vec::new(),
+        }
+    }
+
+    /// a new (partial) state of the device
+    ///
+    /// a switch condition fires only on a change - not with the first known state.
+    pub fn update(&mut self, device_id: &str, state: &DeviceState, now: i64) {
+        let prev = self.states.get(device_id).cloned();
+        let current = prev.clone().unwrap_or_default().merge(state);
+        if prev.as_ref() != Some(&current) {
+            debug!("{}: {:?}", device_id, current);
+        }
+        self.states.insert(device_id.to_string(), current.clone());
+
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if rule.when.device_id != device_id {
+                continue;
+            }
+            let rssi = match (rule.when.rssi_below, current.rssi) {
+                (Some(below), Some(rssi)) => rssi < below,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+
+            let progress = &mut self.progress[idx];
+            let holds = match rule.when.switch {
+                Some(SwitchCondition::Changed) => {
+                    let prev = prev.as_ref().and_then(|p| p.switch);
+                    if rssi && prev.is_some() && current.switch.is_some() && current.switch != prev
+                    {
+                        for effect in &rule.then {
+                            self.planned.push(Planned {
+                                at: now + rule.delay as i64,
+                                rule: rule.name.clone(),
+                                effect: effect.clone(),
+                                source: current.clone(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                Some(SwitchCondition::On) => rssi && current.switch == Some(SwitchState::On),
+                Some(SwitchCondition::Off) => rssi && current.switch == Some(SwitchState::Off),
+                None => rssi,
+            };
+
+            match (holds, progress.since) {
+                (true, None) => {
+                    progress.since = Some(now);
+                    // the first known state is not a change of the switch - even if the
+                    // device was known before, e.g. from an update with only the rssi
+                    progress.fired = rule.when.switch.is_some()
+                        && prev.as_ref().and_then(|p| p.switch).is_none();
+                }
+                (true, Some(_)) => (),
+                (false, _) => {
+                    *progress = Progress::default();
+                    // the condition does not hold anymore - drop the delayed actions
+                    self.planned.retain(|p| p.rule != rule.name);
+                }
+            }
+        }
+    }
+
+    /// the actions which are due - fires the rules whose condition held long enough
+    pub fn due(&mut self, now: i64) -> Vec<Planned> {
+        for (rule, progress) in self.rules.iter().zip(self.progress.iter_mut()) {
+            match progress.since {
+                Some(since) if !progress.fired && now - since >= rule.hold as i64 => {
+                    progress.fired = true;
+                    let source = self
+                        .states
+                        .get(&rule.when.device_id)
+                        .cloned()
+                        .unwrap_or_default();
+                    for effect in &rule.then {
+                        self.planned.push(Planned {
+                            at: now + rule.delay as i64,
+                            rule: rule.name.clone(),
+                            effect: effect.clone(),
+                            source: source.clone(),
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let (due, planned) = self.planned.drain(..).partition(|p| p.at <= now);
+        self.planned = planned;
+        due
+    }
+
+    /// the time of the next planned action or the end of a 'for'
+    pub fn next_due(&self) -> Option<i64> {
+        let planned = self.planned.iter().map(|p| p.at);
+        let holds = self
+            .rules
+            .iter()
+            .zip(self.progress.iter())
+            .filter(|(_, p)| !p.fired)
+            .filter_map(|(rule, p)| p.since.map(|since| since + rule.hold as i64));
+        planned.chain(holds).min()
+    }
+}
+
+/// runs the rules against the device states from the mdns announcements and
+/// (optional) the polled device infos
+pub struct Automation {
+    engine: Engine,
+    devices: HashMap<String, Device>,
+    dry_run: bool,
+    poll_interval: Option<Duration>,
+}
+
+impl Automation {
+    pub fn new(rules: Rules, devices: Vec<Device>) -> Self {
+        Automation {
+            engine: Engine::new(rules),
+            devices: devices.into_iter().map(|d| (d.id.clone(), d)).collect(),
+            dry_run: false,
+            poll_interval: None,
+        }
+    }
+
+    /// only log the actions
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// query the device infos in this interval - for the rssi and devices which announce rarely
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    pub fn run(mut self, service_name: &str) -> Result<()> {
+        let (tx, rx) = mpsc::channel::<Device>();
+
+        let scanner = Scanner::new(service_name.to_string());
+        let announced = tx.clone();
+        thread::spawn(move || loop {
+            match scanner.announcements(DISCOVERY_WINDOW) {
+                Ok(devices) => {
+                    for device in devices {
+                        if announced.send(device).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(err) => eprintln!("discovery failed: {}", err),
+            }
+        });
+
+        let (poll_tx, poll_rx) = mpsc::channel::<(String, DeviceState)>();
+        if let Some(interval) = self.poll_interval {
+            let devices = self.devices.values().cloned().collect::<Vec<_>>();
+            thread::spawn(move || loop {
+                for device in &devices {
+                    match device.device_info() {
+                        Ok(info) => {
+                            let state = DeviceState::from_info(&info);
+                            if poll_tx.send((device.id.clone(), state)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(err) => debug!("no info from {}: {}", device.id, err),
+                    }
+                }
+                thread::sleep(interval);
+            });
+        }
+        drop(tx);
+
+        loop {
+            let now = countdown::now();
+            let timeout = self
+                .engine
+                .next_due()
+                .map(|at| (at - now).clamp(0, 1) as u64)
+                .unwrap_or(1);
+            match rx.recv_timeout(Duration::from_secs(timeout)) {
+                Ok(device) => {
+                    let results = self.announced(device, now);
+                    self.report(results);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::GenericError {
+                        msg: "the discovery stopped".into(),
+                    })
+                }
+            }
+            while let Ok((device_id, state)) = poll_rx.try_recv() {
+                let results = self.update(&device_id, &state, now);
+                self.report(results);
+            }
+            let results = self.tick(now);
+            self.report(results);
+        }
+    }
+
+    /// a new announcement of the device - executes the due actions
+    pub fn announced(&mut self, mut device: Device, now: i64) -> Vec<(Planned, Result<String>)> {
+        // the key is needed to decrypt the txt record of encrypted devices
+        if let Some(known) = self.devices.get(&device.id) {
+            device.tags = known.tags.clone();
+            device.key = device.key.or_else(|| known.key.clone());
+        }
+        let state = DeviceState::from_txt(&device);
+        self.devices.insert(device.id.clone(), device.clone());
+        self.update(&device.id, &state, now)
+    }
+
+    /// a new state of the device - executes the due actions
+    pub fn update(
+        &mut self,
+        device_id: &str,
+        state: &DeviceState,
+        now: i64,
+    ) -> Vec<(Planned, Result<String>)> {
+        self.engine.update(device_id, state, now);
+        self.tick(now)
+    }
+
+    /// executes the due actions
+    pub fn tick(&mut self, now: i64) -> Vec<(Planned, Result<String>)> {
+        self.engine
+            .due(now)
+            .into_iter()
+            .map(|planned| {
+                let res = self.execute(&planned);
+                (planned, res)
+            })
+            .collect()
+    }
+
+    fn execute(&self, planned: &Planned) -> Result<String> {
+        let (device_id, state) = match planned.effect {
+            Effect::Alert { ref message } => return Ok(message.clone()),
+            Effect::Switch {
+                ref device_id,
+                state,
+            } => (device_id, state),
+            Effect::Mirror { ref device_id } => match planned.source.switch {
+                Some(state) => (device_id, state),
+                None => {
+                    return Err(Error::GenericError {
+                        msg: "the switch state to mirror is unknown".into(),
+                    })
+                }
+            },
+        };
+        let device = self
+            .devices
+            .get(device_id)
+            .ok_or_else(|| Error::DeviceNotFound {
+                device_id: device_id.clone(),
+            })?;
+        if self.dry_run {
+            return Ok(format!("dry-run - would switch {} {}", device_id, state));
+        }
+        device.switch(state)
+    }
+
+    fn report(&self, results: Vec<(Planned, Result<String>)>) {
+        for (planned, res) in results {
+            match (&planned.effect, res) {
+                (Effect::Alert { message }, _) => {
+                    eprintln!("alert from rule '{}': {}", planned.rule, message)
+                }
+                (effect, Ok(msg)) if self.dry_run => {
+                    println!("rule '{}': {} ({})", planned.rule, effect, msg)
+                }
+                (effect, Ok(_)) => println!("rule '{}': {}", planned.rule, effect),
+                (effect, Err(err)) => {
+                    eprintln!("rule '{}': {} failed: {}", planned.rule, effect, err)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{"rules": [
+        {"name": "hall", "when": {"deviceId": "a", "switch": "on"}, "delay": 5,
+         "then": [{"action": "switch", "deviceId": "b", "state": "on"}]},
+        {"name": "weak wifi", "when": {"deviceId": "a", "rssiBelow": -80}, "for": 600,
+         "then": [{"action": "alert", "message": "weak wifi"}]},
+        {"name": "mirror", "when": {"deviceId": "c", "switch": "changed"},
+         "then": [{"action": "mirror", "deviceId": "d"}]}
+    ]}"#;
+
+    fn state(switch: Option<SwitchState>, rssi: Option<i32>) -> DeviceState {
+        DeviceState { switch, rssi }
+    }
+
+    fn effects(planned: Vec<Planned>) -> Vec<String> {
+        planned.iter().map(|p| p.effect.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = Rules::parse(RULES).unwrap();
+        assert_eq!(rules.rules.len(), 3);
+        assert_eq!(rules.device_ids(), vec!["a", "b", "c", "d"]);
+
+        let invalid = r#"{"rules": [{"name": "x", "when": {"deviceId": "a"}, "then": []}]}"#;
+        assert!(Rules::parse(invalid).is_err());
+        let unknown = r#"{"rules": [{"name": "x", "when": {"deviceId": "a", "swtch": "on"},
+            "then": [{"action": "alert", "message": "x"}]}]}"#;
+        assert!(Rules::parse(unknown).is_err());
+    }
+
+    #[test]
+    fn switch_with_delay() {
+        let mut engine = Engine::new(Rules::parse(RULES).unwrap());
+        // the first known state does not fire
+        engine.update("a", &state(Some(SwitchState::On), None), 0);
+        assert!(engine.due(10).is_empty());
+
+        engine.update("a", &state(Some(SwitchState::Off), None), 20);
+        engine.update("a", &state(Some(SwitchState::On), None), 30);
+        assert!(engine.due(30).is_empty());
+        assert_eq!(engine.next_due(), Some(35));
+        assert_eq!(effects(engine.due(35)), vec!["switch b on"]);
+        assert!(engine.due(100).is_empty());
+
+        // switched off before the delay is over
+        engine.update("a", &state(Some(SwitchState::Off), None), 110);
+        engine.update("a", &state(Some(SwitchState::On), None), 120);
+        engine.due(120);
+        engine.update("a", &state(Some(SwitchState::Off), None), 122);
+        assert!(engine.due(130).is_empty());
+    }
+
+    #[test]
+    fn alert_after_hold() {
+        let mut engine = Engine::new(Rules::parse(RULES).unwrap());
+        engine.update("a", &state(None, Some(-85)), 0);
+        assert!(engine.due(599).is_empty());
+        engine.update("a", &state(None, Some(-82)), 300);
+        assert_eq!(effects(engine.due(600)), vec!["alert 'weak wifi'"]);
+        assert!(engine.due(1200).is_empty());
+
+        // recovered and weak again
+        engine.update("a", &state(None, Some(-60)), 1300);
+        engine.update("a", &state(None, Some(-90)), 1400);
+        assert!(engine.due(1500).is_empty());
+        assert_eq!(engine.due(2000).len(), 1);
+    }
+
+    #[test]
+    fn mirror_changes() {
+        let mut engine = Engine::new(Rules::parse(RULES).unwrap());
+        engine.update("c", &state(Some(SwitchState::Off), None), 0);
+        engine.update("c", &state(Some(SwitchState::Off), Some(-50)), 10);
+        assert!(engine.due(10).is_empty());
+
+        engine.update("c", &state(Some(SwitchState::On), None), 20);
+        let due = engine.due(20);
+        assert_eq!(effects(due.clone()), vec!["mirror the state on d"]);
+        assert_eq!(due[0].source.switch, Some(SwitchState::On));
+    }
+
+    #[test]
+    fn first_switch_state_after_rssi_does_not_fire() {
+        let mut engine = Engine::new(Rules::parse(RULES).unwrap());
+        engine.update("a", &state(None, Some(-50)), 0);
+        engine.update("a", &state(Some(SwitchState::On), None), 10);
+        assert!(engine.due(20).is_empty());
+
+        engine.update("a", &state(Some(SwitchState::Off), None), 30);
+        engine.update("a", &state(Some(SwitchState::On), None), 40);
+        assert!(engine.due(40).is_empty());
+        assert_eq!(effects(engine.due(45)), vec!["switch b on"]);
+    }
+}
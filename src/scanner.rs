@@ -1,6 +1,11 @@
 use crate::{Device, DeviceAttributes, Result};
 use log::debug;
-use std::{thread, time::Duration};
+use mdns::RecordKind;
+use std::{
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
 
 pub struct Scanner {
     service_name: String,
@@ -16,8 +21,18 @@ impl Scanner {
     }
 
     pub fn scan(&mut self) -> Result<Device> {
-        let mut attrs = DeviceAttributes::default();
         loop {
+            if let Some(device) = self.scan_timeout(Duration::from_secs(60))? {
+                return Ok(device);
+            }
+        }
+    }
+
+    /// scans for a new device - returns `None` if no new device was found within the timeout
+    pub fn scan_timeout(&mut self, timeout: Duration) -> Result<Option<Device>> {
+        let start = Instant::now();
+        let mut attrs = DeviceAttributes::default();
+        while start.elapsed() < timeout {
             for response in
                 mdns::discover::all(&self.service_name)?.timeout(Duration::from_millis(500))
             {
@@ -25,7 +40,7 @@ impl Scanner {
                     if let Some(device) = attrs.add(record) {
                         if !self.devices.contains(&device) {
                             self.devices.push(device.clone());
-                            return Ok(device);
+                            return Ok(Some(device));
                         } else {
                             debug!("ignore already seen device: {}", device.name);
                         }
@@ -34,6 +49,53 @@ impl Scanner {
             }
             thread::sleep(Duration::from_millis(1000));
         }
+        Ok(None)
+    }
+
+    /// waits until the device with the given id announces itself
+    pub fn find(&mut self, device_id: &str, timeout: Duration) -> Result<Option<Device>> {
+        let start = Instant::now();
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+            match self.scan_timeout(remaining)? {
+                Some(device) if device.id == device_id => return Ok(Some(device)),
+                Some(device) => debug!("ignore other device: {}", device.name),
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// collects all devices which announce themselves within the timeout - also the already seen
+    pub fn announcements(&self, timeout: Duration) -> Result<Vec<Device>> {
+        let mut attrs = DeviceAttributes::default();
+        let mut devices: Vec<Device> = Vec::new();
+        for response in mdns::discover::all(&self.service_name)?.timeout(timeout) {
+            for record in response?.records() {
+                if let Some(device) = attrs.add(record) {
+                    devices.retain(|d| d.id != device.id);
+                    devices.push(device);
+                }
+            }
+        }
+        Ok(devices)
+    }
+
+    /// collects the host names and ips from all announcements of the service
+    pub fn announced_hosts(service_name: &str, timeout: Duration) -> Result<Vec<(String, IpAddr)>> {
+        let mut hosts = Vec::new();
+        for response in mdns::discover::all(service_name)?.timeout(timeout) {
+            for record in response?.records() {
+                let ip: IpAddr = match record.kind {
+                    RecordKind::A(addr) => addr.into(),
+                    RecordKind::AAAA(addr) => addr.into(),
+                    _ => continue,
+                };
+                if !hosts.iter().any(|(_, seen)| seen == &ip) {
+                    hosts.push((record.name.clone(), ip));
+                }
+            }
+        }
+        Ok(hosts)
     }
 
     pub fn scan_loop<F, T>(&mut self, mut cb: F) -> Result<()>
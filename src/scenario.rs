@@ -0,0 +1,101 @@
+use crate::*;
+use serde::Deserialize;
+use std::{fs::File, io::BufReader, net::IpAddr, path::Path};
+
+/// faults which the device emulator injects - loaded from a json file.
+///
+/// ```json
+/// {
+///   "requests": [
+///     { "endpoint": "ota_flash", "error": 403, "times": 1 },
+///     { "endpoint": "info", "drop_connection": true, "skip": 2 }
+///   ],
+///   "download": { "stall_at_chunk": 10, "stall_ms": 5000, "repeat_chunk": 3 },
+///   "missing_txt": ["id"],
+///   "ip_after_reboot": "127.0.0.2"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    /// faults for the '/zeroconf/*' requests - the first matching fault is applied
+    pub requests: Vec<RequestFault>,
+    /// faults while the device downloads the firmware
+    pub download: DownloadFault,
+    /// txt fields which are not announced, e.g. 'id'
+    pub missing_txt: Vec<String>,
+    /// the device gets a new ip after the reboot from a successful ota update
+    pub ip_after_reboot: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestFault {
+    /// endpoint without the '/zeroconf/' prefix - all endpoints if not set
+    pub endpoint: Option<String>,
+    /// ignore the first n matching requests
+    pub skip: usize,
+    /// apply the fault n times - always if not set
+    pub times: Option<usize>,
+    /// delay the response
+    pub delay_ms: u64,
+    /// close the connection without a response
+    pub drop_connection: bool,
+    /// respond with the given error code
+    pub error: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadFault {
+    /// stall the download before the given chunk (0 based)
+    pub stall_at_chunk: Option<usize>,
+    pub stall_ms: u64,
+    /// request the given chunk twice
+    pub repeat_chunk: Option<usize>,
+    /// abort the download before the given chunk
+    pub abort_at_chunk: Option<usize>,
+    /// report a sha256 mismatch after the download
+    pub wrong_sha256: bool,
+}
+
+impl Scenario {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+impl RequestFault {
+    fn matches(&self, endpoint: &str) -> bool {
+        self.endpoint
+            .as_ref()
+            .map(|e| e == endpoint)
+            .unwrap_or(true)
+    }
+}
+
+/// tracks how often the request faults have matched
+#[derive(Debug, Default)]
+pub(crate) struct RequestFaults(Vec<(RequestFault, usize)>);
+
+impl RequestFaults {
+    pub fn new(faults: &[RequestFault]) -> Self {
+        RequestFaults(faults.iter().cloned().map(|f| (f, 0)).collect())
+    }
+
+    /// the fault to apply for the current request
+    pub fn next(&mut self, endpoint: &str) -> Option<RequestFault> {
+        for (fault, seen) in self.0.iter_mut() {
+            if !fault.matches(endpoint) {
+                continue;
+            }
+            *seen += 1;
+            let applied = seen.saturating_sub(fault.skip);
+            if applied > 0 && fault.times.map(|t| applied <= t).unwrap_or(true) {
+                return Some(fault.clone());
+            }
+        }
+        None
+    }
+}
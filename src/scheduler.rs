@@ -0,0 +1,740 @@
+use crate::solar::{Location, SolarEvent};
+use crate::*;
+use chrono::{DateTime, Local, NaiveDateTime};
+use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+
+/// jobs which were missed while the host was down are caught up to this age (seconds)
+const MAX_CATCH_UP: i64 = 24 * 60 * 60;
+
+/// the next run of a job is searched up to this many days ahead
+const MAX_LOOKAHEAD_DAYS: i64 = 366;
+
+/// the time source of the scheduler
+pub trait Clock: Send + Sync {
+    /// seconds since the epoch
+    fn now(&self) -> i64;
+
+    /// the wall time which is matched against the cron expressions
+    fn local(&self, ts: i64) -> NaiveDateTime;
+
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    }
+
+    fn local(&self, ts: i64) -> NaiveDateTime {
+        DateTime::from_timestamp(ts, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+            .naive_local()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// a deterministic clock - the wall time is utc and 'sleep' advances the time
+pub struct ManualClock(Mutex<i64>);
+
+impl ManualClock {
+    pub fn new(now: i64) -> Self {
+        ManualClock(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: i64) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        *self.0.lock().unwrap()
+    }
+
+    fn local(&self, ts: i64) -> NaiveDateTime {
+        DateTime::from_timestamp(ts, 0)
+            .unwrap_or_default()
+            .naive_utc()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration.as_secs() as i64;
+    }
+}
+
+#[derive(StructOpt, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    On,
+    Off,
+    /// switch to the opposite of the current state
+    Toggle,
+    /// switch on - the relay switches off after the pulse width (inching)
+    Pulse {
+        #[structopt(long, default_value = "500")]
+        /// pulse width in ms - a multiple of 500
+        width: u32,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::On => write!(f, "on"),
+            Action::Off => write!(f, "off"),
+            Action::Toggle => write!(f, "toggle"),
+            Action::Pulse { width } => write!(f, "pulse {}ms", width),
+        }
+    }
+}
+
+/// when a job runs: a cron expression or a solar event with an optional offset
+///
+///   '30 7 * * 1-5'   cron expression in the local time
+///   '@sunset+30m'    30 minutes after the sunset
+///   '@dawn-1h'       1 hour before the begin of the civil twilight
+///
+/// the solar events are '@sunrise', '@sunset', '@dawn' and '@dusk'.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    Cron(Cron),
+    Solar {
+        event: SolarEvent,
+        /// seconds after the event - negative for before
+        offset: i64,
+    },
+}
+
+impl Trigger {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let name = s
+            .get(1..)
+            .filter(|_| s.starts_with('@'))
+            .map(|rest| rest.split(&['+', '-'][..]).next().unwrap_or(""));
+        let event = match name.and_then(SolarEvent::from_name) {
+            Some(event) => event,
+            None => return Ok(Trigger::Cron(Cron::parse(s)?)),
+        };
+
+        let offset = &s[event.name().len() + 1..];
+        let offset = if offset.is_empty() {
+            0
+        } else {
+            let invalid = || Error::InvalidRequest {
+                msg: format!("invalid offset in '{}' - expected e.g. '+30m' or '-1h'", s),
+            };
+            let (value, unit) = match offset.chars().last() {
+                Some('m') => (&offset[..offset.len() - 1], 60),
+                Some('h') => (&offset[..offset.len() - 1], 3600),
+                _ => (offset, 60),
+            };
+            let sign = if value.starts_with('-') { -1 } else { 1 };
+            let value = value
+                .get(1..)
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or_else(invalid)?;
+            sign * value * unit
+        };
+        Ok(Trigger::Solar { event, offset })
+    }
+
+    pub fn is_solar(&self) -> bool {
+        match self {
+            Trigger::Solar { .. } => true,
+            Trigger::Cron(_) => false,
+        }
+    }
+
+    /// the trigger fires in the minute which starts at `ts` - `tm` is the local time of `ts`
+    fn matches(&self, ts: i64, tm: &NaiveDateTime, location: Option<&Location>) -> bool {
+        match (self, location) {
+            (Trigger::Cron(cron), _) => cron.matches(tm),
+            (Trigger::Solar { event, offset }, Some(location)) => {
+                let day = (ts - offset).div_euclid(86_400);
+                (day - 1..=day + 1)
+                    .filter_map(|day| event.at(location, day))
+                    .any(|event_ts| (ts..ts + 60).contains(&(event_ts + offset)))
+            }
+            (Trigger::Solar { .. }, None) => false,
+        }
+    }
+
+    /// the start of the next minute after `ts` in which the trigger fires
+    pub fn next_after(
+        &self,
+        clock: &dyn Clock,
+        ts: i64,
+        location: Option<&Location>,
+    ) -> Option<i64> {
+        match (self, location) {
+            (Trigger::Cron(cron), _) => {
+                let minute = ts - ts.rem_euclid(60);
+                (1..=MAX_LOOKAHEAD_DAYS * 24 * 60)
+                    .map(|n| minute + n * 60)
+                    .find(|m| cron.matches(&clock.local(*m)))
+            }
+            (Trigger::Solar { event, offset }, Some(location)) => {
+                let day = (ts - offset).div_euclid(86_400);
+                (day - 1..=day + MAX_LOOKAHEAD_DAYS)
+                    .filter_map(|day| event.at(location, day))
+                    .map(|event_ts| {
+                        let fires = event_ts + offset;
+                        fires - fires.rem_euclid(60)
+                    })
+                    .find(|minute| *minute > ts)
+            }
+            (Trigger::Solar { .. }, None) => None,
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Trigger::parse(s)
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trigger::Cron(cron) => write!(f, "{}", cron),
+            Trigger::Solar { event, offset } => {
+                write!(f, "@{}", event.name())?;
+                match offset {
+                    0 => Ok(()),
+                    offset if offset % 3600 == 0 => write!(f, "{:+}h", offset / 3600),
+                    offset => write!(f, "{:+}m", offset / 60),
+                }
+            }
+        }
+    }
+}
+
+impl Serialize for Trigger {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Trigger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Trigger::parse(&s).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: u32,
+    pub device_id: String,
+    /// the outlet of a multi channel device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlet: Option<u8>,
+    #[serde(alias = "cron")]
+    pub trigger: Trigger,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+impl Job {
+    /// executes the action at the device
+    pub fn execute(&self, device: &Device) -> Result<String> {
+        let switch = |state| match self.outlet {
+            Some(outlet) => device.switches(&[(outlet, state)]),
+            None => device.switch(state),
+        };
+        match self.action {
+            Action::On => switch(SwitchState::On),
+            Action::Off => switch(SwitchState::Off),
+            Action::Toggle => {
+                let on = device.device_info()?.is_on(self.outlet).ok_or_else(|| {
+                    Error::GenericError {
+                        msg: format!("no switch state from {}", device.id),
+                    }
+                })?;
+                switch(if on {
+                    SwitchState::Off
+                } else {
+                    SwitchState::On
+                })
+            }
+            Action::Pulse { width } => {
+                // the pulse is a persistent setting - restore it when the relay is off again
+                let info = device.device_info()?;
+                let previous = match (info.pulse.as_deref(), info.pulse_width) {
+                    (Some("on"), Some(width)) => PulseState::On { width },
+                    _ => PulseState::Off,
+                };
+                device.pulse(PulseState::On { width })?;
+                let res = switch(SwitchState::On);
+                let delay = match res {
+                    Ok(_) => Duration::from_millis(width.into()),
+                    Err(_) => Duration::from_secs(0),
+                };
+                let device = device.clone();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    if let Err(err) = device.pulse(previous) {
+                        eprintln!(
+                            "warning: unable to restore the pulse setting of {}: {}",
+                            device.id, err
+                        );
+                    }
+                });
+                res
+            }
+        }
+    }
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {}", self.id, self.device_id)?;
+        if let Some(outlet) = self.outlet {
+            write!(f, " (outlet {})", outlet)?;
+        }
+        write!(f, ": '{}' {}", self.trigger, self.action)
+    }
+}
+
+/// the jobs and the progress of the scheduler - persisted in the schedule file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    jobs: Vec<Job>,
+    /// the last minute which was processed - seconds since the epoch
+    last_run: Option<i64>,
+    /// for the solar events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    location: Option<Location>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Schedule {
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| "".into());
+        path.push(".sonoff-diy-schedule.json");
+        path
+    }
+
+    /// loads the schedule - an empty schedule if the file does not exist
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let mut schedule = if path.exists() {
+            debug!("load schedule from {}", path.display());
+            let file = File::open(&path)?;
+            serde_json::from_reader::<_, Schedule>(BufReader::new(file))?
+        } else {
+            Schedule::default()
+        };
+        schedule.path = path;
+        Ok(schedule)
+    }
+
+    /// replaces the file - `schedule run` re-reads it and never sees a partial file
+    pub fn save(&self) -> Result<()> {
+        debug!("save schedule to: {}", self.path.display());
+        fsutils::write_atomic(&self.path, &serde_json::to_vec_pretty(self)?, false)
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    pub fn set_location(&mut self, location: Location) -> Result<()> {
+        if !(-90.0..=90.0).contains(&location.latitude)
+            || !(-180.0..=180.0).contains(&location.longitude)
+        {
+            return Err(Error::InvalidRequest {
+                msg: "the latitude must be in -90 - 90 and the longitude in -180 - 180".into(),
+            });
+        }
+        self.location = Some(location);
+        Ok(())
+    }
+
+    pub fn add(
+        &mut self,
+        device_id: &str,
+        outlet: Option<u8>,
+        trigger: Trigger,
+        action: Action,
+    ) -> Result<&Job> {
+        if let (Some(_), Action::Pulse { .. }) = (outlet, &action) {
+            return Err(Error::GenericError {
+                msg: "the pulse width is per device - pulse is not supported for outlets".into(),
+            });
+        }
+        if trigger.is_solar() && self.location.is_none() {
+            return Err(no_location());
+        }
+        let id = self.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            device_id: device_id.to_string(),
+            outlet,
+            trigger,
+            action,
+        });
+        Ok(&self.jobs[self.jobs.len() - 1])
+    }
+
+    /// returns `false` if there is no job with the id
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.jobs.len();
+        self.jobs.retain(|j| j.id != id);
+        self.jobs.len() != len
+    }
+
+    /// the jobs which are due in the minutes after the last run up to `now`.
+    ///
+    /// every job is returned at most once with the last matching minute -
+    /// a job which was missed multiple times runs only once.
+    pub fn due(&self, clock: &dyn Clock, now: i64) -> Vec<(Job, i64)> {
+        let minute = now - now.rem_euclid(60);
+        let from = match self.last_run {
+            Some(last_run) => (last_run + 60).max(minute - MAX_CATCH_UP),
+            None => minute,
+        };
+        let minutes = (from..=minute)
+            .rev()
+            .step_by(60)
+            .map(|ts| (ts, clock.local(ts)))
+            .collect::<Vec<_>>();
+
+        self.jobs
+            .iter()
+            .filter_map(|job| {
+                minutes
+                    .iter()
+                    .find(|(ts, tm)| job.trigger.matches(*ts, tm, self.location.as_ref()))
+                    .map(|(ts, _)| (job.clone(), *ts))
+            })
+            .collect()
+    }
+
+    /// the next `count` runs of the trigger after `now`
+    pub fn preview(
+        &self,
+        trigger: &Trigger,
+        clock: &dyn Clock,
+        now: i64,
+        count: usize,
+    ) -> Result<Vec<i64>> {
+        if trigger.is_solar() && self.location.is_none() {
+            return Err(no_location());
+        }
+        let mut runs = Vec::with_capacity(count);
+        let mut ts = now;
+        while runs.len() < count {
+            match trigger.next_after(clock, ts, self.location.as_ref()) {
+                Some(next) => {
+                    runs.push(next);
+                    ts = next;
+                }
+                None => break,
+            }
+        }
+        Ok(runs)
+    }
+}
+
+fn no_location() -> Error {
+    Error::GenericError {
+        msg: "solar events need the location - see 'schedule location'".into(),
+    }
+}
+
+/// the local time for the output
+pub fn format_time(clock: &dyn Clock, ts: i64) -> String {
+    clock.local(ts).format("%Y-%m-%d %H:%M %a").to_string()
+}
+
+/// runs the jobs from the schedule file at the devices.
+///
+/// the schedule file is read every minute - changes apply without a restart.
+/// jobs which were missed while the scheduler was not running are caught up once.
+pub struct Scheduler {
+    path: PathBuf,
+    /// the pending reverts from 'switch on / off --for' are sent too
+    countdowns: PathBuf,
+    devices: Vec<Device>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    pub fn new(devices: Vec<Device>) -> Self {
+        Scheduler {
+            path: Schedule::default_path(),
+            countdowns: countdown::Countdowns::default_path(),
+            devices,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn schedule_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.path = path.into();
+        self
+    }
+
+    pub fn countdowns_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.countdowns = path.into();
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// runs until the process is stopped - a failed round (e.g. an unreadable file) is logged
+    pub fn run(&self) -> Result<()> {
+        loop {
+            let jobs = self.tick().unwrap_or_else(|err| {
+                eprintln!("unable to run the schedule: {}", err);
+                Vec::new()
+            });
+            for (job, res) in jobs {
+                match res {
+                    Ok(_) => println!("{}: done", job),
+                    Err(err) => eprintln!("{}: {}", job, err),
+                }
+            }
+            let reverts = self.revert().unwrap_or_else(|err| {
+                eprintln!("unable to send the reverts: {}", err);
+                Vec::new()
+            });
+            for (revert, res) in reverts {
+                match res {
+                    Ok(_) => println!("switched {} {}", revert.device_id, revert.state),
+                    Err(err) => eprintln!(
+                        "warning: unable to switch {} {} (attempt {}): {}",
+                        revert.device_id,
+                        revert.state,
+                        revert.attempts + 1,
+                        err
+                    ),
+                }
+            }
+            let now = self.clock.now();
+            self.clock
+                .sleep(Duration::from_secs((60 - now.rem_euclid(60)) as u64));
+        }
+    }
+
+    /// sends the due reverts from 'switch on / off --for'
+    pub fn revert(&self) -> Result<Vec<(countdown::Revert, Result<String>)>> {
+        countdown::deliver_due(&self.countdowns, &self.devices, self.clock.now())
+    }
+
+    /// executes the due jobs
+    ///
+    /// the progress is saved before the execution: a job which fails is not retried.
+    pub fn tick(&self) -> Result<Vec<(Job, Result<String>)>> {
+        let mut schedule = Schedule::load(&self.path)?;
+        let now = self.clock.now();
+        let minute = now - now.rem_euclid(60);
+        let due = schedule.due(self.clock.as_ref(), now);
+        schedule.last_run = Some(minute);
+        schedule.save()?;
+
+        Ok(due
+            .into_iter()
+            .map(|(job, ts)| {
+                if ts < minute {
+                    println!(
+                        "{}: catch up the run from {}",
+                        job,
+                        format_time(self.clock.as_ref(), ts)
+                    );
+                }
+                let res = self
+                    .devices
+                    .iter()
+                    .find(|d| d.id == job.device_id)
+                    .ok_or_else(|| Error::DeviceNotFound {
+                        device_id: job.device_id.clone(),
+                    })
+                    .and_then(|device| job.execute(device));
+                (job, res)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2020-03-02 00:00 utc (monday)
+    const MONDAY: i64 = 1_583_107_200;
+
+    fn schedule() -> Schedule {
+        let mut schedule = Schedule::default();
+        for (cron, action) in &[("0 7 * * 1-5", Action::On), ("30 22 * * *", Action::Off)] {
+            schedule
+                .add("1000000001", None, cron.parse().unwrap(), action.clone())
+                .unwrap();
+        }
+        schedule
+    }
+
+    #[test]
+    fn due_jobs() {
+        let clock = ManualClock::new(MONDAY);
+        let mut schedule = schedule();
+        let at = |h: i64, m: i64| MONDAY + h * 3600 + m * 60;
+
+        // the first run only checks the current minute
+        assert!(schedule.due(&clock, at(6, 59)).is_empty());
+        let due = schedule.due(&clock, at(7, 0) + 15);
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].0.id, due[0].1), (1, at(7, 0)));
+
+        schedule.last_run = Some(at(7, 0));
+        assert!(schedule.due(&clock, at(7, 1)).is_empty());
+    }
+
+    #[test]
+    fn catch_up_missed_jobs_once() {
+        let clock = ManualClock::new(MONDAY);
+        let mut schedule = schedule();
+        let day = 24 * 3600;
+
+        // down from monday 06:00 to tuesday 08:00
+        schedule.last_run = Some(MONDAY + 6 * 3600);
+        let due = schedule.due(&clock, MONDAY + day + 8 * 3600);
+        let due = due.iter().map(|(j, ts)| (j.id, *ts)).collect::<Vec<_>>();
+        assert_eq!(
+            due,
+            vec![(1, MONDAY + day + 7 * 3600), (2, MONDAY + 22 * 3600 + 1800)]
+        );
+
+        // older runs are not caught up
+        schedule.last_run = Some(MONDAY - 7 * day);
+        let now = MONDAY + 8 * 3600;
+        let due = schedule.due(&clock, now);
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|(_, ts)| *ts >= now - MAX_CATCH_UP));
+    }
+
+    #[test]
+    fn parse_triggers() {
+        for s in &[
+            "@sunset",
+            "@sunrise+30m",
+            "@dusk-1h",
+            "@dawn-90m",
+            "0 7 * * 1-5",
+        ] {
+            assert_eq!(Trigger::parse(s).unwrap().to_string(), *s);
+        }
+        assert_eq!(
+            Trigger::parse("@sunset-15").unwrap(),
+            Trigger::Solar {
+                event: SolarEvent::Sunset,
+                offset: -15 * 60
+            }
+        );
+        for s in &["@sunset+", "@sunset+xm", "@noon"] {
+            assert!(Trigger::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn solar_triggers() {
+        let clock = ManualClock::new(MONDAY);
+        let mut schedule = Schedule::default();
+        let trigger: Trigger = "@sunset+30m".parse().unwrap();
+        assert!(schedule
+            .add("1000000001", None, trigger.clone(), Action::On)
+            .is_err());
+        assert!(schedule.preview(&trigger, &clock, MONDAY, 3).is_err());
+
+        // berlin - sunset at 2020-03-02 16:48 utc
+        schedule
+            .set_location(Location {
+                latitude: 52.52,
+                longitude: 13.405,
+            })
+            .unwrap();
+        schedule
+            .add("1000000001", None, trigger.clone(), Action::On)
+            .unwrap();
+        let runs = schedule.preview(&trigger, &clock, MONDAY, 3).unwrap();
+        assert_eq!(runs.len(), 3);
+        assert!((runs[0] - (MONDAY + 17 * 3600 + 18 * 60)).abs() <= 120);
+        // the days get longer
+        assert!(runs[1] - runs[0] > 86_400 && runs[2] - runs[1] > 86_400);
+
+        let due = schedule.due(&clock, runs[0]);
+        assert_eq!(due.len(), 1);
+        assert!(schedule.due(&clock, runs[0] + 60).is_empty());
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut schedule = schedule();
+        let cron: Trigger = "@daily".parse().unwrap();
+        assert!(schedule
+            .add(
+                "1000000001",
+                Some(1),
+                cron.clone(),
+                Action::Pulse { width: 500 }
+            )
+            .is_err());
+        assert_eq!(
+            schedule
+                .add("1000000001", Some(1), cron, Action::Toggle)
+                .unwrap()
+                .id,
+            3
+        );
+        assert!(schedule.remove(2));
+        assert!(!schedule.remove(2));
+        assert_eq!(
+            schedule.jobs().iter().map(|j| j.id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+    }
+}
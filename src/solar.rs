@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// the julian day at the unix epoch
+const JULIAN_UNIX_EPOCH: f64 = 2_440_587.5;
+
+/// the julian day at 2000-01-01 12:00 (J2000)
+const JULIAN_2000: f64 = 2_451_545.0;
+
+/// the position for the solar events - in degrees, north and east are positive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SolarEvent {
+    Sunrise,
+    Sunset,
+    /// begin of the civil twilight - the sun is 6° below the horizon
+    Dawn,
+    /// end of the civil twilight
+    Dusk,
+}
+
+impl SolarEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            SolarEvent::Sunrise => "sunrise",
+            SolarEvent::Sunset => "sunset",
+            SolarEvent::Dawn => "dawn",
+            SolarEvent::Dusk => "dusk",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sunrise" => Some(SolarEvent::Sunrise),
+            "sunset" => Some(SolarEvent::Sunset),
+            "dawn" => Some(SolarEvent::Dawn),
+            "dusk" => Some(SolarEvent::Dusk),
+            _ => None,
+        }
+    }
+
+    /// the elevation of the sun's center at the event
+    fn elevation(self) -> f64 {
+        match self {
+            // refraction and the radius of the sun
+            SolarEvent::Sunrise | SolarEvent::Sunset => -0.833,
+            SolarEvent::Dawn | SolarEvent::Dusk => -6.0,
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        self == SolarEvent::Sunrise || self == SolarEvent::Dawn
+    }
+
+    /// the time of the event (seconds since the epoch) at the utc day of `day` (days since the epoch)
+    ///
+    /// `None` if the sun does not reach the elevation at this day (polar day / night).
+    /// uses the sunrise equation - accurate to about a minute.
+    pub fn at(self, location: &Location, day: i64) -> Option<i64> {
+        let rad = PI / 180.0;
+        // days since J2000 at the local mean solar noon
+        let n = (day as f64 + JULIAN_UNIX_EPOCH + 0.5 - JULIAN_2000 + 0.0008).round();
+        let mean_noon = n - location.longitude / 360.0;
+
+        let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0) * rad;
+        let center =
+            1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+        let ecliptic_longitude =
+            (anomaly / rad + center + 180.0 + 102.9372).rem_euclid(360.0) * rad;
+        let transit = JULIAN_2000 + mean_noon + 0.0053 * anomaly.sin()
+            - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+        let declination = (ecliptic_longitude.sin() * (23.4397 * rad).sin()).asin();
+        let latitude = location.latitude * rad;
+        let cos_hour_angle = ((self.elevation() * rad).sin() - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+
+        let hour_angle = cos_hour_angle.acos() / rad / 360.0;
+        let julian = if self.is_morning() {
+            transit - hour_angle
+        } else {
+            transit + hour_angle
+        };
+        Some(((julian - JULIAN_UNIX_EPOCH) * 86_400.0).round() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BERLIN: Location = Location {
+        latitude: 52.52,
+        longitude: 13.405,
+    };
+
+    /// 2020-06-21 in days since the epoch
+    const SOLSTICE: i64 = 18_434;
+
+    fn assert_about(ts: Option<i64>, expected: i64) {
+        let ts = ts.expect("no event");
+        assert!((ts - expected).abs() <= 120, "{} != {}", ts, expected);
+    }
+
+    #[test]
+    fn berlin_at_the_solstice() {
+        let midnight = SOLSTICE * 86_400;
+        // sunrise 04:43, sunset 21:33 (cest) - civil twilight 03:52 - 22:24
+        assert_about(
+            SolarEvent::Sunrise.at(&BERLIN, SOLSTICE),
+            midnight + 2 * 3600 + 43 * 60,
+        );
+        assert_about(
+            SolarEvent::Sunset.at(&BERLIN, SOLSTICE),
+            midnight + 19 * 3600 + 33 * 60,
+        );
+        assert_about(
+            SolarEvent::Dawn.at(&BERLIN, SOLSTICE),
+            midnight + 3600 + 52 * 60,
+        );
+        assert_about(
+            SolarEvent::Dusk.at(&BERLIN, SOLSTICE),
+            midnight + 20 * 3600 + 24 * 60,
+        );
+    }
+
+    #[test]
+    fn polar_day() {
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+        };
+        assert_eq!(SolarEvent::Sunset.at(&tromso, SOLSTICE), None);
+    }
+}
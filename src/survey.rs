@@ -0,0 +1,330 @@
+use crate::*;
+use chrono::DateTime;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Write,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// how long the scanner listens for announcements per round
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(5);
+
+pub const CSV_HEADER: &str = "timestamp,device_id,signal_strength,txt_rssi,error";
+
+/// one measurement of a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// seconds since the epoch
+    pub ts: i64,
+    pub device_id: String,
+    /// from '/zeroconf/signal_strength' - or 'info' for older firmware
+    pub signal_strength: Option<i32>,
+    /// the last rssi from the txt record since the previous sample
+    pub txt_rssi: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl Sample {
+    pub fn csv_row(&self) -> String {
+        let show = |v: Option<i32>| v.map(|v| v.to_string()).unwrap_or_default();
+        let ts = DateTime::from_timestamp(self.ts, 0).unwrap_or_default();
+        format!(
+            "{},{},{},{},{}",
+            ts.format("%Y-%m-%dT%H:%M:%SZ"),
+            csv_field(&self.device_id),
+            show(self.signal_strength),
+            show(self.txt_rssi),
+            csv_field(self.error.as_deref().unwrap_or(""))
+        )
+    }
+}
+
+/// quotes the field if necessary
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// samples the signal strength of the devices
+pub struct Survey {
+    devices: Vec<Device>,
+    parallel: usize,
+    txt_rssi: Arc<Mutex<HashMap<String, i32>>>,
+}
+
+impl Survey {
+    pub fn new(devices: Vec<Device>, parallel: usize) -> Self {
+        Survey {
+            devices,
+            parallel,
+            txt_rssi: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// records the rssi from the mdns announcements in the background
+    pub fn listen(&self, service_name: &str) {
+        let scanner = Scanner::new(service_name.to_string());
+        let txt_rssi = Arc::clone(&self.txt_rssi);
+        // the keys decrypt the txt records of encrypted devices
+        let keys = self
+            .devices
+            .iter()
+            .map(|d| (d.id.clone(), d.key.clone()))
+            .collect::<HashMap<_, _>>();
+        thread::spawn(move || loop {
+            match scanner.announcements(DISCOVERY_WINDOW) {
+                Ok(devices) => {
+                    for device in devices {
+                        let device_id = device.id.clone();
+                        if let Some(rssi) = announced_rssi(&keys, device) {
+                            txt_rssi.lock().unwrap().insert(device_id, rssi);
+                        }
+                    }
+                }
+                Err(err) => eprintln!("discovery failed: {}", err),
+            }
+        });
+    }
+
+    /// one sample per device - requested in parallel
+    pub fn sample(&self, ts: i64) -> Vec<Sample> {
+        let mut txt_rssi = std::mem::take(&mut *self.txt_rssi.lock().unwrap());
+        fanout::fan_out(self.devices.clone(), self.parallel, |device| {
+            match device.signal_strength() {
+                // older firmware without the endpoint
+                Err(Error::DeviceError { .. }) => {
+                    device
+                        .device_info()?
+                        .signal_strength
+                        .ok_or(Error::JSONLookupError {
+                            msg: "'signalStrength' in response not found".to_string(),
+                        })
+                }
+                res => res,
+            }
+        })
+        .into_iter()
+        .map(|(device, res)| Sample {
+            ts,
+            txt_rssi: txt_rssi.remove(&device.id),
+            device_id: device.id,
+            signal_strength: res.as_ref().ok().cloned(),
+            error: res.err().map(|err| err.to_string()),
+        })
+        .collect()
+    }
+
+    /// samples the devices every `interval` for `duration` - the rows are written to `csv`
+    pub fn run(
+        &self,
+        interval: Duration,
+        duration: Duration,
+        mut csv: Option<&mut dyn Write>,
+    ) -> Result<Summary> {
+        let mut summary = Summary::default();
+        if let Some(ref mut csv) = csv {
+            writeln!(csv, "{}", CSV_HEADER)?;
+        }
+        let end = countdown::now() + duration.as_secs() as i64;
+        loop {
+            let ts = countdown::now();
+            for sample in self.sample(ts) {
+                match (sample.signal_strength, &sample.error) {
+                    (Some(signal), _) => println!(
+                        "{}  {:4} dbm{}",
+                        sample.device_id,
+                        signal,
+                        sample
+                            .txt_rssi
+                            .map(|rssi| format!("  (txt: {} dbm)", rssi))
+                            .unwrap_or_default()
+                    ),
+                    (None, error) => println!(
+                        "{}  no response: {}",
+                        sample.device_id,
+                        error.as_deref().unwrap_or("-")
+                    ),
+                }
+                if let Some(ref mut csv) = csv {
+                    writeln!(csv, "{}", sample.csv_row())?;
+                }
+                summary.add(&sample);
+            }
+            if let Some(ref mut csv) = csv {
+                csv.flush()?;
+            }
+
+            let next = ts + interval.as_secs() as i64;
+            if next > end {
+                return Ok(summary);
+            }
+            thread::sleep(Duration::from_secs((next - countdown::now()).max(0) as u64));
+        }
+    }
+}
+
+/// the rssi from the announcement of a surveyed device
+fn announced_rssi(keys: &HashMap<String, Option<String>>, mut device: Device) -> Option<i32> {
+    let key = keys.get(&device.id)?;
+    device.key = device.key.or_else(|| key.clone());
+    let data = device.txt_data().ok()?;
+    data.get("rssi").and_then(|v| v.as_i64()).map(|v| v as i32)
+}
+
+/// min / avg / max of the values
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Range {
+    pub count: usize,
+    pub min: i32,
+    pub max: i32,
+    sum: i64,
+}
+
+impl Range {
+    fn add(&mut self, v: i32) {
+        if self.count == 0 {
+            self.min = v;
+            self.max = v;
+        }
+        self.count += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        self.sum += v as i64;
+    }
+
+    pub fn avg(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum as f64 / self.count as f64)
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.avg() {
+            Some(avg) => write!(f, "{} / {:.1} / {}", self.min, avg, self.max),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceSummary {
+    pub samples: usize,
+    /// samples without a response
+    pub lost: usize,
+    pub signal_strength: Range,
+    pub txt_rssi: Range,
+}
+
+/// the statistics per device
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary(pub BTreeMap<String, DeviceSummary>);
+
+impl Summary {
+    pub fn add(&mut self, sample: &Sample) {
+        let entry = self.0.entry(sample.device_id.clone()).or_default();
+        entry.samples += 1;
+        match sample.signal_strength {
+            Some(v) => entry.signal_strength.add(v),
+            None => entry.lost += 1,
+        }
+        if let Some(v) = sample.txt_rssi {
+            entry.txt_rssi.add(v);
+        }
+    }
+
+    pub fn print(&self) {
+        let id_width = self.0.keys().map(String::len).max().unwrap_or(0).max(2);
+        println!(
+            "{:id_width$}  {:>7}  {:>4}  {:22}  TXT RSSI MIN / AVG / MAX",
+            "ID",
+            "SAMPLES",
+            "LOST",
+            "SIGNAL MIN / AVG / MAX",
+            id_width = id_width
+        );
+        for (id, s) in &self.0 {
+            println!(
+                "{:id_width$}  {:>7}  {:>4}  {:22}  {}",
+                id,
+                s.samples,
+                s.lost,
+                s.signal_strength.to_string(),
+                s.txt_rssi,
+                id_width = id_width
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(device_id: &str, signal_strength: Option<i32>, txt_rssi: Option<i32>) -> Sample {
+        Sample {
+            ts: 1_583_107_200,
+            device_id: device_id.into(),
+            signal_strength,
+            txt_rssi,
+            error: signal_strength.map_or(Some("timeout, no response".into()), |_| None),
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let mut summary = Summary::default();
+        for s in &[
+            sample("a", Some(-60), Some(-61)),
+            sample("a", Some(-70), None),
+            sample("a", None, None),
+            sample("a", Some(-80), None),
+        ] {
+            summary.add(s);
+        }
+        let a = &summary.0["a"];
+        assert_eq!((a.samples, a.lost), (4, 1));
+        assert_eq!(a.signal_strength.to_string(), "-80 / -70.0 / -60");
+        assert_eq!(a.txt_rssi.to_string(), "-61 / -61.0 / -61");
+    }
+
+    #[cfg(feature = "emulator")]
+    #[test]
+    fn rssi_from_encrypted_announcements() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let emulator = Emulator::new(&localhost, 0, "1000000901")
+            .unwrap()
+            .key("0123456789abcdef0123456789abcdef");
+        let known = emulator.device();
+        let keys = vec![(known.id.clone(), known.key.clone())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        // the announcements carry no key
+        let mut announced = emulator.device();
+        announced.key = None;
+        assert_eq!(announced_rssi(&keys, announced.clone()), Some(-48));
+        assert_eq!(announced_rssi(&HashMap::new(), announced), None);
+    }
+
+    #[test]
+    fn csv_rows() {
+        assert_eq!(
+            sample("a", Some(-60), None).csv_row(),
+            "2020-03-02T00:00:00Z,a,-60,,"
+        );
+        assert_eq!(
+            sample("a", None, None).csv_row(),
+            "2020-03-02T00:00:00Z,a,,,\"timeout, no response\""
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use crate::*;
+use log::debug;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// mdns services announced by alternative firmwares
+const FIRMWARE_SERVICES: &[(&str, &str)] = &[
+    ("_esphomelib._tcp", "ESPHome"),
+    ("_http._tcp", "http service"),
+];
+
+/// markers in the web-interface of alternative firmwares
+const FIRMWARE_MARKERS: &[&str] = &["Tasmota", "ESPHome", "ESPEasy", "Espurna"];
+
+const DIY_SERVICE: &str = "_ewelink._tcp";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Verdict {
+    /// the new firmware is up
+    Success(String),
+    /// the device is still in diy mode - the flash was rejected
+    StillDiy,
+    /// neither the diy device nor a new firmware was found
+    Missing,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Success(firmware) => write!(f, "new firmware is up: {}", firmware),
+            Verdict::StillDiy => write!(f, "device is still in diy mode - flash rejected"),
+            Verdict::Missing => write!(f, "device is missing"),
+        }
+    }
+}
+
+/// watches for the device to drop off the diy service and for a new firmware
+/// at the same ip.
+pub fn verify(device: &Device, timeout: Duration) -> Result<Verdict> {
+    let start = Instant::now();
+    let mut dropped_off = false;
+    let mut still_diy = true;
+
+    while start.elapsed() < timeout {
+        if let Some(firmware) = probe_firmware(device)? {
+            return Ok(Verdict::Success(firmware));
+        }
+
+        let mut scanner = Scanner::new(DIY_SERVICE.into());
+        still_diy = scanner.find(&device.id, Duration::from_secs(3))?.is_some();
+        match (dropped_off, still_diy) {
+            (false, false) => {
+                debug!("{}: dropped off the diy service", device.id);
+                dropped_off = true;
+            }
+            (true, true) => {
+                debug!("{}: is back in diy mode", device.id);
+                return Ok(Verdict::StillDiy);
+            }
+            _ => (),
+        }
+    }
+
+    if still_diy {
+        Ok(Verdict::StillDiy)
+    } else {
+        Ok(Verdict::Missing)
+    }
+}
+
+/// looks for a new firmware at the ip of the device
+fn probe_firmware(device: &Device) -> Result<Option<String>> {
+    if let Some(firmware) = probe_http(device) {
+        return Ok(Some(firmware));
+    }
+
+    for (service, firmware) in FIRMWARE_SERVICES {
+        for (host, ip) in Scanner::announced_hosts(service, Duration::from_secs(2))? {
+            if ip == device.ip {
+                debug!("{}: found {} at {}", device.id, service, host);
+                return Ok(Some(format!("{} ({})", firmware, host)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn probe_http(device: &Device) -> Option<String> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .ok()?;
+    let body = client
+        .get(&format!("http://{}/", device.ip))
+        .send()
+        .and_then(|mut resp| resp.text())
+        .map_err(|err| debug!("{}: http probe failed: {}", device.id, err))
+        .ok()?;
+    FIRMWARE_MARKERS
+        .iter()
+        .find(|marker| body.contains(*marker))
+        .map(|marker| marker.to_string())
+}
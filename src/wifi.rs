@@ -0,0 +1,178 @@
+use crate::*;
+use log::debug;
+use std::{
+    fs,
+    io::{self, BufRead},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SERVICE_NAME: &str = "_ewelink._tcp";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// checks the ssid / password against the wpa2 limits - an empty password for open networks
+pub fn validate(ssid: &str, pwd: &str) -> Result<()> {
+    let invalid = |msg: String| Err(Error::InvalidWifiCredentials { msg });
+
+    if ssid.is_empty() || ssid.len() > 32 {
+        return invalid(format!(
+            "the ssid must have 1 - 32 bytes, got: {}",
+            ssid.len()
+        ));
+    }
+
+    // a 64 char password is the hex encoded pre-shared key
+    if pwd.is_empty() {
+        debug!("no password - open network");
+    } else if pwd.len() == 64 {
+        if !pwd.chars().all(|c| c.is_ascii_hexdigit()) {
+            return invalid("a password with 64 chars must be a hex encoded psk".into());
+        }
+    } else if pwd.len() < 8 || pwd.len() > 63 {
+        return invalid(format!(
+            "the password must have 8 - 63 chars (or none for an open network), got: {}",
+            pwd.len()
+        ));
+    }
+
+    if !pwd.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return invalid("the password must contain only printable ascii chars".into());
+    }
+    Ok(())
+}
+
+/// reads the wifi password from the selected source
+///
+/// without an explicit source, the password is looked up in the key store (if it exists)
+/// or asked for on the terminal.
+pub fn read_password(src: &PasswordSource, essid: &str) -> Result<String> {
+    if let Some(ref pwd) = src.pwd {
+        return Ok(pwd.clone());
+    }
+
+    if src.pwd_stdin {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        return Ok(trim_newline(line));
+    }
+
+    if let Some(ref var) = src.pwd_env {
+        return std::env::var(var).map_err(|_| Error::GenericError {
+            msg: format!("environment variable '{}' not set", var),
+        });
+    }
+
+    if let Some(ref path) = src.pwd_file {
+        let content = fs::read_to_string(path)?;
+        return Ok(trim_newline(
+            content.lines().next().unwrap_or("").to_string(),
+        ));
+    }
+
+    if KeyStore::default_path().exists() {
+        if let Some(pwd) = KeyStore::open()?.wifi_password(essid) {
+            return Ok(pwd.to_string());
+        }
+        debug!("no password for '{}' in the key store", essid);
+    }
+    keystore::prompt(&format!("wifi password for {}: ", essid))
+}
+
+fn trim_newline(mut s: String) -> String {
+    while s.ends_with('\n') || s.ends_with('\r') {
+        s.pop();
+    }
+    s
+}
+
+/// waits until the device is back in the new network
+///
+/// the device restarts the wifi after the response to the wifi request. it's back once its
+/// seq has changed - at the old address (it often keeps the dhcp lease) or in a new
+/// announcement. returns the device with the current address.
+pub fn await_reconnect(
+    device: &Device,
+    essid: &str,
+    wifi_response: &str,
+    timeout: Duration,
+) -> Result<Device> {
+    let seq = device::response_seq(&serde_json::from_str(wifi_response)?)?;
+    let scanner = Scanner::new(SERVICE_NAME.into());
+    let start = Instant::now();
+    let mut connected_to = None;
+    while start.elapsed() < timeout {
+        let poll = Instant::now();
+        if let Some(found) = reconnected(device, seq, &scanner)? {
+            match found.device_info().map(|info| info.ssid) {
+                Ok(Some(ref ssid)) if ssid == essid => return Ok(found),
+                Ok(ssid) => connected_to = ssid.or_else(|| Some("an unknown network".into())),
+                Err(err) => debug!("unable to read the ssid from {}: {}", found.id, err),
+            }
+        }
+        if let Some(pause) = POLL_INTERVAL.checked_sub(poll.elapsed()) {
+            thread::sleep(pause);
+        }
+    }
+
+    Err(Error::Timeout {
+        msg: match connected_to {
+            Some(ssid) => format!(
+                "device {} is connected to {} instead of {}",
+                device.id, ssid, essid
+            ),
+            None => format!("device {} not found in the new network", device.id),
+        },
+    })
+}
+
+/// the device with a new seq - at the old address or from an announcement
+fn reconnected(device: &Device, seq: u64, scanner: &Scanner) -> Result<Option<Device>> {
+    match device.seq() {
+        Ok(current) if current != seq => return Ok(Some(device.clone())),
+        Ok(_) => debug!("device {} has not restarted the wifi yet", device.id),
+        Err(err) => debug!(
+            "device {} not reachable at {}: {}",
+            device.id, device.ip, err
+        ),
+    }
+
+    let announced = scanner
+        .announcements(POLL_INTERVAL)?
+        .into_iter()
+        .find(|found| {
+            found.id == device.id
+                && found.txt_field("seq").and_then(|s| s.parse().ok()) != Some(seq)
+        });
+    Ok(announced.map(|mut found| {
+        found.tags = device.tags.clone();
+        found.key = device.key.clone();
+        found
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_ssid() {
+        assert!(validate("iot", "password").is_ok());
+        assert!(validate("", "password").is_err());
+        assert!(validate(&"x".repeat(32), "password").is_ok());
+        assert!(validate(&"x".repeat(33), "password").is_err());
+    }
+
+    #[test]
+    fn validate_password() {
+        assert!(validate("iot", "1234567").is_err());
+        // open network
+        assert!(validate("iot", "").is_ok());
+        assert!(validate("iot", &"x".repeat(63)).is_ok());
+        assert!(validate("iot", &"a".repeat(64)).is_ok());
+        assert!(validate("iot", &"x".repeat(64)).is_err());
+        assert!(validate("iot", &"x".repeat(65)).is_err());
+        assert!(validate("iot", "pass\tword").is_err());
+        assert!(validate("iot", "pässword").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+// the audit log is global - one test per binary
+#[test]
+fn switch_is_recorded_without_secrets() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000001001").unwrap().key(KEY);
+    let device = emulator.device();
+    emulator.start();
+
+    let path = std::env::temp_dir().join(format!("sonoff-diy-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    audit::enable(&path);
+
+    device.switch(SwitchState::On).unwrap();
+    device.device_info().unwrap();
+    device.signal_strength().unwrap();
+
+    let query = audit::Query {
+        device_id: None,
+        since: None,
+        until: None,
+    };
+    let records = query.run(&path).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].device_id, "1000001001");
+    assert_eq!(records[0].endpoint, "switch");
+    assert_eq!(records[0].payload["data"]["switch"], "on");
+    assert!(records[0].error.is_none());
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    assert!(!log.contains(KEY));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,252 @@
+use serde_json::{json, Value};
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{thread, time::Duration};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn daemon(ip: IpAddr, device: Device) -> Daemon {
+    Daemon::new(SocketAddr::new(ip, 0), DeviceCache::new(vec![device])).unwrap()
+}
+
+fn start(daemon: Daemon) -> String {
+    let url = format!("http://{}", daemon.local_addr());
+    daemon.start().unwrap();
+    url
+}
+
+fn start_daemon(device: Device) -> String {
+    start(daemon(LOCALHOST, device))
+}
+
+#[test]
+fn list_devices() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000401").unwrap();
+    let url = start_daemon(emulator.device());
+
+    let devices: Value = reqwest::get(&format!("{}/devices", url))
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(devices[0]["id"], "1000000401");
+    assert_eq!(devices[0]["state"]["switch"], "off");
+}
+
+#[test]
+fn switch_and_info() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000402").unwrap();
+    let state = emulator.state();
+    let url = start_daemon(emulator.device());
+    emulator.start();
+
+    let client = reqwest::Client::new();
+    let mut res = client
+        .post(&format!("{}/devices/1000000402/switch", url))
+        .json(&json!({ "state": "on" }))
+        .send()
+        .unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(res.json::<Value>().unwrap()["error"], 0);
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    client
+        .post(&format!("{}/devices/1000000402/pulse", url))
+        .json(&json!({ "state": "on", "width": 2000 }))
+        .send()
+        .unwrap();
+    client
+        .post(&format!("{}/devices/1000000402/startup", url))
+        .json(&json!({ "state": "stay" }))
+        .send()
+        .unwrap();
+
+    let info: Value = reqwest::get(&format!("{}/devices/1000000402/info", url))
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(info["switch"], "on");
+    assert_eq!(info["pulse"], "on");
+    assert_eq!(info["pulseWidth"], 2000);
+    assert_eq!(info["startup"], "stay");
+}
+
+#[test]
+fn errors() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000403").unwrap();
+    let url = start_daemon(emulator.device());
+    emulator.start();
+
+    let res = reqwest::get(&format!("{}/devices/unknown", url)).unwrap();
+    assert_eq!(res.status().as_u16(), 404);
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(&format!("{}/devices/1000000403/switch", url))
+        .json(&json!({ "state": "toggle" }))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 400);
+
+    let mut res = client
+        .post(&format!("{}/devices/1000000403/pulse", url))
+        .json(&json!({ "state": "on", "width": 700 }))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 400);
+    assert!(res.json::<Value>().unwrap()["error"].is_string());
+
+    // a width which is not a number isn't replaced with the default
+    let res = client
+        .post(&format!("{}/devices/1000000403/pulse", url))
+        .json(&json!({ "state": "on", "width": "2s" }))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 400);
+}
+
+#[test]
+fn metrics() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000404").unwrap();
+    let url = start_daemon(emulator.device());
+    emulator.start();
+
+    reqwest::Client::new()
+        .post(&format!("{}/devices/1000000404/switch", url))
+        .json(&json!({ "state": "on" }))
+        .send()
+        .unwrap();
+
+    let mut res = reqwest::get(&format!("{}/metrics", url)).unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(res.headers()["content-type"], "text/plain; version=0.0.4");
+    let metrics = res.text().unwrap();
+    assert!(metrics.contains("# TYPE sonoff_diy_relay_state gauge"));
+    assert!(metrics.lines().any(|l| l
+        .starts_with("sonoff_diy_relay_state{device_id=\"1000000404\"")
+        && l.ends_with("outlet=\"0\"} 1")));
+    assert!(metrics
+        .lines()
+        .any(|l| l.starts_with("sonoff_diy_request_duration_seconds_count{endpoint=\"switch\"}")));
+}
+
+#[test]
+fn non_loopback_requires_a_token() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000405").unwrap();
+    let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    assert!(daemon(unspecified, emulator.device()).start().is_err());
+
+    let daemon = daemon(unspecified, emulator.device()).token("secret".into());
+    let url = format!("http://127.0.0.1:{}", daemon.local_addr().port());
+    daemon.start().unwrap();
+
+    let client = reqwest::Client::new();
+    let res = client.get(&format!("{}/devices", url)).send().unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+    let res = client
+        .get(&format!("{}/devices", url))
+        .header("Authorization", "Bearer wrong")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 401);
+    let res = client
+        .get(&format!("{}/devices", url))
+        .header("Authorization", "Bearer secret")
+        .send()
+        .unwrap();
+    assert!(res.status().is_success());
+}
+
+#[test]
+fn cross_site_requests_are_rejected() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000407").unwrap();
+    let state = emulator.state();
+    let url = start_daemon(emulator.device());
+    emulator.start();
+
+    let client = reqwest::Client::new();
+    let switch = format!("{}/devices/1000000407/switch", url);
+    // a html form
+    let res = client
+        .post(&switch)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(r#"{"state": "on"}"#)
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 403);
+    let res = client
+        .post(&switch)
+        .header("Origin", "http://example.com")
+        .json(&json!({ "state": "on" }))
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 403);
+    // dns rebinding
+    let res = client
+        .get(&format!("{}/devices", url))
+        .header("Host", "example.com")
+        .send()
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 403);
+    assert_eq!(state.lock().unwrap().switch, "off");
+
+    let res = client
+        .post(&switch)
+        .header("Origin", "http://localhost:8080")
+        .json(&json!({ "state": "on" }))
+        .send()
+        .unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(state.lock().unwrap().switch, "on");
+}
+
+#[test]
+fn flash_from_the_flash_dir() {
+    let dir = std::env::temp_dir().join(format!("sonoff-diy-flash-dir-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut firmware = vec![0xE9];
+    firmware.resize(10 * 1024, 0);
+    std::fs::write(dir.join("firmware.bin"), &firmware).unwrap();
+
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000406").unwrap();
+    let state = emulator.state();
+    let device = emulator.device();
+    emulator.start();
+
+    let client = reqwest::Client::new();
+    let flash = |url: &str, bin: &str| {
+        client
+            .post(&format!("{}/devices/1000000406/flash", url))
+            .json(&json!({ "bin": bin }))
+            .send()
+            .unwrap()
+            .status()
+            .as_u16()
+    };
+
+    // flashing is disabled without a directory
+    let url = start_daemon(device.clone());
+    assert_eq!(flash(&url, "firmware.bin"), 400);
+
+    let url = start(daemon(LOCALHOST, device).flash_dir(&dir));
+    assert_eq!(flash(&url, "../firmware.bin"), 400);
+    assert_eq!(flash(&url, dir.join("firmware.bin").to_str().unwrap()), 400);
+    assert_eq!(flash(&url, "firmware.bin"), 202);
+    // one flash per device at a time
+    assert_eq!(flash(&url, "firmware.bin"), 409);
+
+    let mut status = Value::Null;
+    for _ in 0..100 {
+        let device: Value = reqwest::get(&format!("{}/devices/1000000406", url))
+            .unwrap()
+            .json()
+            .unwrap();
+        status = device["flash"]["status"].clone();
+        if status != "running" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(status, "done");
+    assert_eq!(state.lock().unwrap().firmware, Some(firmware));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,462 @@
+use sonoff_diy::emulator::OtaStatus;
+use sonoff_diy::scenario::Scenario;
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn start_emulator(device_id: &str) -> (Device, Emulator) {
+    let emulator = Emulator::new(&LOCALHOST, 0, device_id).unwrap();
+    let device = emulator.device();
+    (device, emulator)
+}
+
+#[test]
+fn switch_and_info() {
+    let (device, emulator) = start_emulator("1000000001");
+    let state = emulator.state();
+    emulator.start();
+
+    device.switch(SwitchState::On).unwrap();
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    let info = device.device_info().unwrap();
+    assert_eq!(info.switch, Some("on".into()));
+    assert_eq!(info.deviceid, Some("1000000001".into()));
+    assert_eq!(info.ota_unlock, Some(false));
+}
+
+#[test]
+fn probe_by_ip() {
+    let (device, emulator) = start_emulator("1000000002");
+    emulator.start();
+
+    let probed = Device::probe(device.ip, device.port).unwrap();
+    assert_eq!(probed.id, "1000000002");
+}
+
+#[test]
+fn flash_without_unlock_should_fail() {
+    let (device, emulator) = start_emulator("1000000003");
+    emulator.start();
+
+    match device.flash("http://127.0.0.1:1/sonoff.bin", "-") {
+        Err(Error::DeviceError { code: 403 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn unlock_and_flash() {
+    let (device, emulator) = start_emulator("1000000004");
+    let state = emulator.state();
+    emulator.start();
+
+    let bin = Binary::new("shell.nix").unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let transfers = httpd.transfers();
+    let (endpoint, _) = httpd.start();
+
+    let batch = BatchFlash::new(
+        endpoint,
+        bin.sha256sum(),
+        transfers,
+        Duration::from_secs(30),
+    );
+    assert_eq!(batch.flash(&device).unwrap(), "flashed");
+
+    let state = state.lock().unwrap();
+    assert!(state.ota_unlock);
+    assert_eq!(state.ota, OtaStatus::Done);
+    assert_eq!(state.firmware, Some(bin.slurp().unwrap()));
+}
+
+#[test]
+fn flash_with_wrong_sha256sum_should_fail() {
+    let (device, emulator) = start_emulator("1000000005");
+    emulator.state().lock().unwrap().ota_unlock = true;
+    emulator.start();
+
+    let bin = Binary::new("shell.nix").unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let transfers = httpd.transfers();
+    let (endpoint, _) = httpd.start();
+
+    let batch = BatchFlash::new(endpoint, "0".repeat(64), transfers, Duration::from_secs(30));
+    match batch.flash(&device) {
+        Err(Error::DeviceError { code: 471 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+fn start_emulator_with(device_id: &str, scenario: &str) -> (Device, Emulator) {
+    let scenario: Scenario = serde_json::from_str(scenario).unwrap();
+    let emulator = Emulator::new(&LOCALHOST, 0, device_id)
+        .unwrap()
+        .scenario(scenario);
+    let device = emulator.device();
+    (device, emulator)
+}
+
+fn flash(device: &Device, sha256sum: Option<String>) -> Result<String> {
+    let bin = Binary::new("shell.nix").unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let transfers = httpd.transfers();
+    let (endpoint, _) = httpd.start();
+
+    let sha256sum = sha256sum.unwrap_or_else(|| bin.sha256sum());
+    BatchFlash::new(endpoint, sha256sum, transfers, Duration::from_secs(30)).flash(device)
+}
+
+#[test]
+fn fault_error_code_after_unlock() {
+    let (device, emulator) = start_emulator_with(
+        "1000000101",
+        r#"{ "requests": [{ "endpoint": "ota_flash", "error": 403 }] }"#,
+    );
+    emulator.start();
+
+    device.unlock().unwrap();
+    match flash(&device, None) {
+        Err(Error::DeviceError { code: 403 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn device_error_fails_the_command() {
+    let (device, emulator) = start_emulator_with(
+        "1000000109",
+        r#"{ "requests": [
+            { "endpoint": "switch", "error": 400, "times": 1 },
+            { "endpoint": "startup", "error": 422, "times": 1 },
+            { "endpoint": "info", "error": 500, "times": 1 }
+        ] }"#,
+    );
+    let state = emulator.state();
+    emulator.start();
+
+    match device.switch(SwitchState::On) {
+        Err(Error::DeviceError { code: 400 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(state.lock().unwrap().switch, "off");
+    match device.startup(StartupState::Stay) {
+        Err(Error::DeviceError { code: 422 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match device.device_info() {
+        Err(Error::DeviceError { code: 500 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // 'error: 0' is a success
+    assert!(device.switch(SwitchState::On).is_ok());
+    assert!(device.startup(StartupState::Stay).is_ok());
+    assert_eq!(device.device_info().unwrap().switch, Some("on".into()));
+}
+
+#[test]
+fn fault_dropped_connection_is_retried_for_info() {
+    let (device, emulator) = start_emulator_with(
+        "1000000102",
+        r#"{ "requests": [{ "endpoint": "info", "drop_connection": true, "times": 1 }] }"#,
+    );
+    emulator.start();
+
+    assert!(device.device_info().is_ok());
+}
+
+#[test]
+fn fault_dropped_connection_is_not_retried_for_switch() {
+    let (device, emulator) = start_emulator_with(
+        "1000000103",
+        r#"{ "requests": [{ "endpoint": "switch", "drop_connection": true, "times": 1 }] }"#,
+    );
+    let state = emulator.state();
+    emulator.start();
+
+    match device.switch(SwitchState::On) {
+        Err(Error::ReqwestError { .. }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(state.lock().unwrap().switch, "off");
+}
+
+#[test]
+fn fault_stalled_and_repeated_chunks() {
+    let (device, emulator) = start_emulator_with(
+        "1000000104",
+        r#"{ "download": { "stall_at_chunk": 0, "stall_ms": 500, "repeat_chunk": 0 } }"#,
+    );
+    emulator.state().lock().unwrap().ota_unlock = true;
+    emulator.start();
+
+    assert_eq!(flash(&device, None).unwrap(), "flashed");
+}
+
+#[test]
+fn fault_aborted_download() {
+    let (device, emulator) =
+        start_emulator_with("1000000105", r#"{ "download": { "abort_at_chunk": 0 } }"#);
+    emulator.state().lock().unwrap().ota_unlock = true;
+    emulator.start();
+
+    match flash(&device, None) {
+        Err(Error::DeviceError { code: 424 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn fault_wrong_sha256_verdict() {
+    let (device, emulator) =
+        start_emulator_with("1000000106", r#"{ "download": { "wrong_sha256": true } }"#);
+    emulator.state().lock().unwrap().ota_unlock = true;
+    emulator.start();
+
+    match flash(&device, None) {
+        Err(Error::DeviceError { code: 471 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn fault_missing_txt_fields() {
+    let (device, _) = start_emulator_with("1000000107", r#"{ "missing_txt": ["id"] }"#);
+    assert!(!device.desc.iter().any(|txt| txt.starts_with("id=")));
+}
+
+#[test]
+#[cfg(target_os = "linux")] // the whole 127.0.0.0/8 net is only routed to lo on linux
+fn fault_ip_change_after_reboot() {
+    let (device, emulator) =
+        start_emulator_with("1000000108", r#"{ "ip_after_reboot": "127.0.0.2" }"#);
+    emulator.state().lock().unwrap().ota_unlock = true;
+    let state = emulator.state();
+    let addr = emulator.local_addr();
+    emulator.start();
+
+    assert_eq!(flash(&device, None).unwrap(), "flashed");
+    // the emulator changes the ip after the result was posted
+    while state.lock().unwrap().ota != OtaStatus::Done {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    std::thread::sleep(Duration::from_millis(500));
+
+    assert!(device.device_info().is_err());
+    let moved = Device::probe("127.0.0.2".parse().unwrap(), addr.port()).unwrap();
+    assert_eq!(moved.id, "1000000108");
+}
+
+#[test]
+fn encrypted_switch_and_info() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000201")
+        .unwrap()
+        .key("0123456789abcdef0123456789abcdef");
+    let device = emulator.device();
+    let state = emulator.state();
+    emulator.start();
+
+    assert!(device.encrypt);
+    assert_eq!(device.txt_data().unwrap()["switch"], "off");
+
+    device.switch(SwitchState::On).unwrap();
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    let info = device.device_info().unwrap();
+    assert_eq!(info.switch, Some("on".into()));
+    assert_eq!(info.deviceid, Some("1000000201".into()));
+}
+
+#[test]
+fn encrypted_device_without_key_should_fail() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000202")
+        .unwrap()
+        .key("0123456789abcdef0123456789abcdef");
+    let mut device = emulator.device();
+    emulator.start();
+
+    device.key = None;
+    match device.switch(SwitchState::On) {
+        Err(Error::DeviceKeyMissing { .. }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    device.encrypt = false;
+    match device.switch(SwitchState::On) {
+        Err(Error::DeviceError { code: 401 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+#[ignore] // needs multicast
+fn scan() {
+    let (_, emulator) = start_emulator("1000000006");
+    emulator.advertise().unwrap();
+    emulator.start();
+
+    let mut scanner = Scanner::new("_ewelink._tcp".into());
+    let device = scanner
+        .find("1000000006", Duration::from_secs(30))
+        .unwrap()
+        .expect("emulated device not found");
+    assert_eq!(device.id, "1000000006");
+}
+
+#[test]
+#[ignore] // needs multicast
+fn scan_ignores_announcements_without_id() {
+    let (_, faulty) = start_emulator_with("1000000110", r#"{ "missing_txt": ["id"] }"#);
+    faulty.advertise().unwrap();
+    faulty.start();
+    let (_, emulator) = start_emulator("1000000111");
+    emulator.advertise().unwrap();
+    emulator.start();
+
+    let mut scanner = Scanner::new("_ewelink._tcp".into());
+    let mut found = Vec::new();
+    while let Some(device) = scanner.scan_timeout(Duration::from_secs(10)).unwrap() {
+        found.push(device.name);
+    }
+    assert!(found.contains(&"eWeLink_1000000111".to_string()));
+    assert!(!found.contains(&"eWeLink_1000000110".to_string()));
+}
+
+#[test]
+fn wifi_migration_stops_at_the_first_failure() {
+    // the first device doesn't come back after the wifi change
+    let (first, first_emulator) = start_emulator_with(
+        "1000000301",
+        r#"{ "requests": [{ "endpoint": "info", "drop_connection": true }] }"#,
+    );
+    let (second, second_emulator) = start_emulator("1000000302");
+    let (first_state, second_state) = (first_emulator.state(), second_emulator.state());
+    first_emulator.start();
+    second_emulator.start();
+
+    let report = std::env::temp_dir().join(format!(
+        "sonoff-diy-wifi-migration-{}.json",
+        std::process::id()
+    ));
+    let cache = std::env::temp_dir().join(format!(
+        "sonoff-diy-wifi-migration-cache-{}.json",
+        std::process::id()
+    ));
+    let res = WifiMigration::new("iot", "new-password", Duration::from_secs(3))
+        .report_path(&report)
+        .run(
+            &[first, second],
+            &mut DeviceCache::default().path(&cache),
+            true,
+        );
+    match res {
+        Err(Error::MigrationFailed { ref device_id, .. }) if device_id == "1000000301" => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(first_state.lock().unwrap().ssid, "iot");
+    assert_eq!(second_state.lock().unwrap().ssid, "sonoffDiy");
+
+    let content = std::fs::read_to_string(&report).unwrap();
+    assert!(content.contains(r#""status": "failed""#));
+    assert!(!content.contains("new-password"));
+    std::fs::remove_file(&report).unwrap();
+    let _ = std::fs::remove_file(&cache);
+}
+
+#[test]
+fn wifi_migration_with_a_new_password_at_the_same_address() {
+    let (device, emulator) = start_emulator("1000000303");
+    let state = emulator.state();
+    emulator.start();
+
+    let report = std::env::temp_dir().join(format!(
+        "sonoff-diy-wifi-password-{}.json",
+        std::process::id()
+    ));
+    let cache = std::env::temp_dir().join(format!(
+        "sonoff-diy-wifi-password-cache-{}.json",
+        std::process::id()
+    ));
+    let (ip, mut device_cache) = (device.ip, DeviceCache::default().path(&cache));
+    // the emulator keeps its address and answers all the time
+    WifiMigration::new("sonoffDiy", "new-password", Duration::from_secs(10))
+        .report_path(&report)
+        .run(&[device], &mut device_cache, true)
+        .unwrap();
+    assert_eq!(state.lock().unwrap().password, "new-password");
+    assert!(!report.exists());
+    assert_eq!(device_cache.lookup("1000000303").unwrap().ip, ip);
+    std::fs::remove_file(&cache).unwrap();
+}
+
+#[test]
+fn backup_restore_to_a_replacement() {
+    let (device, emulator) = start_emulator("1000000801");
+    emulator.start();
+    let (replacement, emulator) = start_emulator("1000000802");
+    let state = emulator.state();
+    emulator.start();
+
+    device.startup(StartupState::Stay).unwrap();
+    device.pulse(PulseState::On { width: 1500 }).unwrap();
+    let (backup, results) = backup::backup(vec![device], 2, 1_583_107_200);
+    assert!(results[0].1.is_ok());
+    let saved = &backup.device("1000000801").unwrap().settings;
+    assert_eq!(saved.pulse_width, Some(1500));
+
+    let live = |d: &Device| backup::Settings::from(d.device_info().unwrap());
+    let diff = saved.diff(&live(&replacement));
+    assert_eq!(
+        diff.iter().map(|(name, _, _)| *name).collect::<Vec<_>>(),
+        vec!["startup", "pulse", "pulseWidth"]
+    );
+
+    let results = saved.restore(&replacement, false);
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|(_, res)| res.is_ok()));
+    assert!(saved.diff(&live(&replacement)).is_empty());
+    assert_eq!(state.lock().unwrap().startup, "stay");
+}
+
+#[test]
+fn firmware_report() {
+    let (device, emulator) = start_emulator("1000000811");
+    emulator.start();
+    // nothing listens at the port
+    let offline = Device::new("b", "1000000812", &[], LOCALHOST, 1);
+
+    let policy = report::Policy {
+        min_version: Some("3.4.0".parse().unwrap()),
+        ..report::Policy::default()
+    };
+    let entries = report::firmware(vec![device, offline], &policy, 2);
+    assert_eq!(entries[0].fw_version.as_deref(), Some("3.3.0"));
+    assert_eq!(entries[0].ota_unlock, Some(false));
+    assert_eq!(entries[0].status, report::FirmwareStatus::Outdated);
+    assert_eq!(entries[1].status, report::FirmwareStatus::Unreachable);
+}
+
+#[test]
+fn survey_signal_strength() {
+    let (device, emulator) = start_emulator("1000000821");
+    let state = emulator.state();
+    emulator.start();
+    let offline = Device::new("b", "1000000822", &[], LOCALHOST, 1);
+    assert_eq!(device.signal_strength().unwrap(), -48);
+
+    let survey = survey::Survey::new(vec![device, offline], 2);
+    let mut summary = survey::Summary::default();
+    for (ts, rssi) in &[(0, -60), (10, -85)] {
+        state.lock().unwrap().signal_strength = *rssi;
+        for sample in survey.sample(*ts) {
+            summary.add(&sample);
+        }
+    }
+    let online = &summary.0["1000000821"];
+    assert_eq!((online.samples, online.lost), (2, 0));
+    assert_eq!(online.signal_strength.to_string(), "-85 / -72.5 / -60");
+    assert_eq!(summary.0["1000000822"].lost, 2);
+}
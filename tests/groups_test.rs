@@ -0,0 +1,33 @@
+use sonoff_diy::groups::{self, SceneMember};
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+#[test]
+fn apply_scene_per_device() {
+    let a = Emulator::new(&LOCALHOST, 0, "1000000701").unwrap();
+    let b = Emulator::new(&LOCALHOST, 0, "1000000702").unwrap();
+    let (state_a, state_b) = (a.state(), b.state());
+    // nothing listens at the port
+    let offline = Device::new("c", "1000000703", &[], LOCALHOST, 1);
+    let devices = vec![a.device(), b.device(), offline];
+    a.start();
+    b.start();
+
+    let scene = ["1000000701=on", "1000000702=off", "1000000703=on"]
+        .iter()
+        .map(|s| s.parse::<SceneMember>().unwrap())
+        .collect::<Vec<_>>();
+    let results = groups::apply(&devices, &scene, 4).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].1.as_ref().unwrap(), "on");
+    assert_eq!(results[1].1.as_ref().unwrap(), "off");
+    assert!(results[2].1.is_err());
+    assert_eq!(state_a.lock().unwrap().switch, "on");
+    assert_eq!(state_b.lock().unwrap().switch, "off");
+
+    // unknown members fail before any request
+    let unknown = vec!["1000000799=off".parse().unwrap()];
+    assert!(groups::apply(&devices, &unknown, 4).is_err());
+}
@@ -1,6 +1,9 @@
 use reqwest::header::RANGE;
 use sonoff_diy::*;
-use std::net::{IpAddr, Ipv4Addr};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
 
 const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
@@ -12,7 +15,7 @@ fn download_range() {
     let bin_content = bin.slurp().unwrap();
     let bin_len = bin_content.len();
 
-    let httpd = Httpd::new(&LOCALHOST, 9876, &bin).unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap();
     let (endpoint, _) = httpd.start();
 
     let mut resp_content = Vec::new();
@@ -42,3 +45,150 @@ fn download_range() {
     // compare the response content with the original file content
     assert_eq!(bin_content, resp_content);
 }
+
+#[test]
+fn unsatisfiable_range() {
+    let bin = Binary::new("shell.nix").unwrap();
+    let bin_len = bin.slurp().unwrap().len();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let (endpoint, _) = httpd.start();
+
+    let client = reqwest::Client::new();
+    for range in &[
+        format!("bytes={}-{}", bin_len, bin_len + 99),
+        "bytes=100-99".to_string(),
+    ] {
+        let resp = client.get(&endpoint).header(RANGE, range).send().unwrap();
+        assert_eq!(resp.status().as_u16(), 416);
+        assert_eq!(
+            resp.headers().get("content-range").unwrap(),
+            &format!("bytes */{}", bin_len)
+        );
+    }
+
+    // the server is still up
+    let resp = client
+        .get(&endpoint)
+        .header(RANGE, "bytes=0-9")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 206);
+}
+
+#[test]
+fn download_range_concurrent() {
+    let bin = Binary::new("shell.nix").unwrap();
+    let bin_content = bin.slurp().unwrap();
+    let bin_len = bin_content.len();
+
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().workers(4);
+    let (endpoint, _) = httpd.start();
+
+    // every client keeps its connection alive while the others download
+    let clients = (0..4)
+        .map(|_| {
+            let endpoint = endpoint.clone();
+            thread::spawn(move || {
+                let client = reqwest::Client::new();
+                let mut resp_content = Vec::new();
+                for from in (0..bin_len).step_by(10) {
+                    let mut resp = client
+                        .get(&endpoint)
+                        .header(RANGE, format!("bytes={}-{}", from, from + 9))
+                        .send()
+                        .unwrap();
+                    resp.copy_to(&mut resp_content).unwrap();
+                }
+                resp_content
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for client in clients {
+        assert_eq!(bin_content, client.join().unwrap());
+    }
+}
+
+#[test]
+fn transfers_per_device_from_the_same_ip() {
+    let bin = Binary::new("shell.nix").unwrap();
+    let bin_len = bin.slurp().unwrap().len();
+
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let transfers = httpd.transfers();
+    let (endpoint, _) = httpd.start();
+    let batch = BatchFlash::new(
+        endpoint,
+        bin.sha256sum(),
+        transfers.clone(),
+        Duration::from_secs(1),
+    );
+
+    let client = reqwest::Client::new();
+    let device = |id: &str| Device::new(id, id, &[], LOCALHOST, 8081);
+    for (id, to) in &[("a", 9), ("b", bin_len - 1)] {
+        client
+            .get(&batch.download_url(&device(id)))
+            .header(RANGE, format!("bytes=0-{}", to))
+            .send()
+            .unwrap();
+    }
+    client
+        .post(&batch.download_url(&device("b")))
+        .body(r#"{"error":0}"#)
+        .send()
+        .unwrap();
+
+    let a = transfers.get("a").unwrap();
+    assert_eq!((a.served, a.result), (10, None));
+    let b = transfers.get("b").unwrap();
+    assert_eq!(
+        (b.served, b.result),
+        (bin_len, Some(r#"{"error":0}"#.into()))
+    );
+    assert_eq!(batch.await_completion(&device("b")).unwrap(), "flashed");
+    assert!(transfers.get(&LOCALHOST.to_string()).is_none());
+}
+
+#[test]
+fn oversized_request_closes_the_connection() {
+    let bin = Binary::new("shell.nix").unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let addr = httpd.local_addr();
+    httpd.start();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let header = format!("GET /{} HTTP/1.1\r\nX-Padding: ", bin.basename());
+    stream.write_all(header.as_bytes()).unwrap();
+    // the server may close the connection while the padding is sent
+    let _ = stream.write_all(&[b'x'; 128 * 1024]);
+    let mut resp = Vec::new();
+    let _ = stream.read_to_end(&mut resp);
+    assert!(resp.is_empty());
+}
+
+#[test]
+fn shutdown_frees_the_port() {
+    let bin = Binary::new("shell.nix").unwrap();
+    let httpd = Httpd::new(&LOCALHOST, 0, &bin).unwrap().verbose(false);
+    let addr = httpd.local_addr();
+    let shutdown = httpd.shutdown_handle();
+    let (_, hndl) = httpd.start();
+
+    shutdown.shutdown();
+    hndl.join().unwrap();
+    assert!(Httpd::new(&LOCALHOST, addr.port(), &bin).is_ok());
+}
+
+#[test]
+fn bind_to_a_used_port_should_fail() {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let bin = Binary::new("shell.nix").unwrap();
+    match Httpd::new(&LOCALHOST, port, &bin) {
+        Err(Error::BindError { addr, .. }) => assert_eq!(addr.port(), port),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("bind to a used port should fail"),
+    }
+}
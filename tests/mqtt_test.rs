@@ -0,0 +1,189 @@
+use sonoff_diy::mqtt::{apply_command, device_id, device_state, MqttBridge, MqttOpts};
+use sonoff_diy::*;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::{thread, time::Duration};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+#[test]
+fn device_id_from_topic() {
+    assert_eq!(
+        device_id("sonoff-diy", "sonoff-diy/1000000001/set"),
+        Some("1000000001")
+    );
+    assert_eq!(device_id("sonoff-diy", "sonoff-diy/1000000001/state"), None);
+    assert_eq!(device_id("sonoff-diy", "sonoff-diy//set"), None);
+    assert_eq!(device_id("sonoff-diy", "other/1000000001/set"), None);
+}
+
+#[test]
+fn commands() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000501").unwrap();
+    let device = emulator.device();
+    let state = emulator.state();
+    emulator.start();
+
+    apply_command(&device, b"on").unwrap();
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    apply_command(
+        &device,
+        br#"{"startup": "stay", "pulse": "on", "pulseWidth": 1500}"#,
+    )
+    .unwrap();
+    {
+        let state = state.lock().unwrap();
+        assert_eq!(state.startup, "stay");
+        assert_eq!(state.pulse, "on");
+        assert_eq!(state.pulse_width, 1500);
+    }
+
+    assert!(apply_command(&device, b"toggle").is_err());
+    assert!(apply_command(&device, br#"{"switch": "toggle"}"#).is_err());
+    assert!(apply_command(&device, br#"{"unknown": "on"}"#).is_err());
+    assert!(apply_command(&device, br#"{"pulse": "on", "pulseWidth": "2s"}"#).is_err());
+    assert_eq!(state.lock().unwrap().pulse_width, 1500);
+}
+
+/// reads a mqtt packet - returns the first header byte and the variable part
+fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut byte = [0_u8];
+    stream.read_exact(&mut byte).ok()?;
+    let header = byte[0];
+    let (mut len, mut shift) = (0_usize, 0);
+    loop {
+        stream.read_exact(&mut byte).ok()?;
+        len += ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).ok()?;
+    Some((header, body))
+}
+
+/// a minimal mqtt broker which sends the commands after the subscription
+fn start_broker(commands: Vec<(&'static str, &'static str)>) -> u16 {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        while let Some((header, body)) = read_packet(&mut stream) {
+            let response = match header >> 4 {
+                // connect
+                1 => vec![0x20, 0x02, 0x00, 0x00],
+                // publish - ack the retained state with qos 1
+                3 if header & 0x06 != 0 => {
+                    let pkid_at = 2 + ((body[0] as usize) << 8 | body[1] as usize);
+                    vec![0x40, 0x02, body[pkid_at], body[pkid_at + 1]]
+                }
+                // subscribe
+                8 => {
+                    let mut response = vec![0x90, 0x03, body[0], body[1], 0x01];
+                    for (topic, payload) in &commands {
+                        let len = 2 + topic.len() + payload.len();
+                        response.extend_from_slice(&[0x30, len as u8, 0, topic.len() as u8]);
+                        response.extend_from_slice(topic.as_bytes());
+                        response.extend_from_slice(payload.as_bytes());
+                    }
+                    response
+                }
+                // ping
+                12 => vec![0xd0, 0x00],
+                _ => continue,
+            };
+            if stream.write_all(&response).is_err() {
+                break;
+            }
+        }
+    });
+    port
+}
+
+#[test]
+fn commands_from_the_broker() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000504").unwrap();
+    let device = emulator.device();
+    let state = emulator.state();
+    emulator.start();
+
+    let port = start_broker(vec![
+        ("sonoff-diy/unknown/set", "on"),
+        ("sonoff-diy/1000000504/set", "toggle"),
+        ("sonoff-diy/1000000504/set", r#"{"startup": "stay"}"#),
+        ("sonoff-diy/1000000504/set", "on"),
+    ]);
+    let opts = MqttOpts {
+        host: LOCALHOST.to_string(),
+        port,
+        client_id: "sonoff-diy-test".into(),
+        credentials: None,
+        prefix: "sonoff-diy".into(),
+        service_name: "_ewelink._tcp".into(),
+        interval: Duration::from_secs(1),
+        offline_after: Duration::from_secs(60),
+        homeassistant: None,
+    };
+    thread::spawn(move || MqttBridge::new(opts, vec![device]).run());
+
+    for _ in 0..100 {
+        if state.lock().unwrap().switch == "on" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let state = state.lock().unwrap();
+    assert_eq!(state.switch, "on");
+    assert_eq!(state.startup, "stay");
+}
+
+#[test]
+fn state_from_txt_and_info() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000502").unwrap();
+    let device = emulator.device();
+    emulator.start();
+
+    let state = device_state(&device).unwrap();
+    assert_eq!(state["switch"], "off");
+    // from the txt record
+    assert_eq!(state["rssi"], -48);
+    // from the device info
+    assert_eq!(state["fwVersion"], "3.3.0");
+    assert_eq!(state["ssid"], "sonoffDiy");
+}
+
+#[test]
+fn homeassistant_discovery_configs() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000503").unwrap();
+    let device = emulator.device();
+    emulator.start();
+
+    let state = device_state(&device).unwrap();
+    let configs = homeassistant::discovery_configs("homeassistant", "sonoff-diy", &device, &state);
+    let topics = configs.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        topics,
+        vec![
+            "homeassistant/switch/1000000503/config",
+            "homeassistant/sensor/1000000503/rssi/config",
+            "homeassistant/sensor/1000000503/fw_version/config",
+        ]
+    );
+
+    let switch = &configs[0].1;
+    assert_eq!(switch["command_topic"], "sonoff-diy/1000000503/set");
+    assert_eq!(switch["state_topic"], "sonoff-diy/1000000503/state");
+    assert_eq!(switch["unique_id"], "sonoff_diy_1000000503");
+    assert_eq!(switch["device"]["model"], "diy_plug");
+    assert_eq!(switch["device"]["sw_version"], "3.3.0");
+    // the bssid is the mac of the access point - not of the device
+    assert!(switch["device"].get("connections").is_none());
+    assert_eq!(
+        switch["availability"][1]["topic"],
+        "sonoff-diy/1000000503/availability"
+    );
+    assert_eq!(configs[1].1["unit_of_measurement"], "dBm");
+}
@@ -0,0 +1,81 @@
+use sonoff_diy::rules::DeviceState;
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+#[test]
+fn switch_and_mirror() {
+    let source = Emulator::new(&LOCALHOST, 0, "1000000601").unwrap();
+    let target = Emulator::new(&LOCALHOST, 0, "1000000602").unwrap();
+    let state = target.state();
+    let devices = vec![source.device(), target.device()];
+    target.start();
+
+    let rules = Rules::parse(
+        r#"{"rules": [
+            {"name": "follow", "when": {"deviceId": "1000000601", "switch": "changed"},
+             "delay": 5, "then": [{"action": "mirror", "deviceId": "1000000602"}]}
+        ]}"#,
+    )
+    .unwrap();
+
+    let on = DeviceState {
+        switch: Some(SwitchState::On),
+        rssi: None,
+    };
+    let off = DeviceState {
+        switch: Some(SwitchState::Off),
+        rssi: None,
+    };
+
+    // dry-run does not touch the target
+    let mut automation = Automation::new(rules.clone(), devices.clone()).dry_run(true);
+    automation.update("1000000601", &off, 0);
+    automation.update("1000000601", &on, 10);
+    let results = automation.tick(15);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.as_ref().unwrap().starts_with("dry-run"));
+    assert_eq!(state.lock().unwrap().switch, "off");
+
+    let mut automation = Automation::new(rules, devices);
+    automation.update("1000000601", &off, 0);
+    assert!(automation.update("1000000601", &on, 10).is_empty());
+    let results = automation.tick(15);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+    assert_eq!(state.lock().unwrap().switch, "on");
+}
+
+#[test]
+fn announcements_from_an_encrypted_device() {
+    let source = Emulator::new(&LOCALHOST, 0, "1000000603")
+        .unwrap()
+        .key("0123456789abcdef0123456789abcdef");
+    let target = Emulator::new(&LOCALHOST, 0, "1000000604").unwrap();
+    let (source_state, target_state) = (source.state(), target.state());
+    let devices = vec![source.device(), target.device()];
+    target.start();
+
+    let rules = Rules::parse(
+        r#"{"rules": [
+            {"name": "follow", "when": {"deviceId": "1000000603", "switch": "changed"},
+             "then": [{"action": "mirror", "deviceId": "1000000604"}]}
+        ]}"#,
+    )
+    .unwrap();
+    let mut automation = Automation::new(rules, devices);
+
+    // the announcements carry no key - the txt record is encrypted
+    let announced = || {
+        let mut device = source.device();
+        device.key = None;
+        device
+    };
+    assert!(automation.announced(announced(), 0).is_empty());
+    source_state.lock().unwrap().switch = "on".into();
+    let results = automation.announced(announced(), 10);
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+    assert_eq!(target_state.lock().unwrap().switch, "on");
+}
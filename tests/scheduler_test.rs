@@ -0,0 +1,101 @@
+use sonoff_diy::countdown::{Countdowns, Revert};
+use sonoff_diy::scheduler::{Action, ManualClock, Schedule};
+use sonoff_diy::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+/// 2020-03-02 00:00 utc (monday)
+const MONDAY: i64 = 1_583_107_200;
+
+#[test]
+fn run_and_catch_up_jobs() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000501").unwrap();
+    let state = emulator.state();
+    let device = emulator.device();
+    emulator.start();
+
+    let path =
+        std::env::temp_dir().join(format!("sonoff-diy-schedule-{}.json", std::process::id()));
+    let mut schedule = Schedule::load(&path).unwrap();
+    for (cron, action) in &[
+        ("0 7 * * *", Action::Toggle),
+        ("0 8 * * *", Action::Pulse { width: 2000 }),
+    ] {
+        schedule
+            .add(&device.id, None, cron.parse().unwrap(), action.clone())
+            .unwrap();
+    }
+    schedule.save().unwrap();
+
+    let clock = Arc::new(ManualClock::new(MONDAY + 7 * 3600));
+    let scheduler = Scheduler::new(vec![device])
+        .schedule_path(&path)
+        .clock(clock.clone());
+
+    let results = scheduler.tick().unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    // the same minute runs only once
+    assert!(scheduler.tick().unwrap().is_empty());
+
+    // the host was down from 07:01 to 09:30
+    clock.set(MONDAY + 9 * 3600 + 1800);
+    let results = scheduler.tick().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0.id, 2);
+    {
+        let state = state.lock().unwrap();
+        assert_eq!(state.pulse, "on");
+        assert_eq!(state.pulse_width, 2000);
+    }
+    // the previous pulse setting is restored after the pulse
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while state.lock().unwrap().pulse != "off" && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(state.lock().unwrap().pulse, "off");
+
+    // the progress is persisted
+    clock.set(MONDAY + 9 * 3600 + 1860);
+    let scheduler = Scheduler::new(Vec::new()).schedule_path(&path).clock(clock);
+    assert!(scheduler.tick().unwrap().is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn send_due_reverts() {
+    let emulator = Emulator::new(&LOCALHOST, 0, "1000000502").unwrap();
+    let state = emulator.state();
+    let device = emulator.device();
+    emulator.start();
+    device.switch(SwitchState::On).unwrap();
+
+    let path = std::env::temp_dir().join(format!(
+        "sonoff-diy-countdowns-test-{}.json",
+        std::process::id()
+    ));
+    let mut countdowns = Countdowns::load(&path).unwrap();
+    let revert = Revert::new(&device.id, SwitchState::Off, Duration::from_secs(900));
+    countdowns.add(revert.clone());
+    countdowns.save().unwrap();
+
+    let clock = Arc::new(ManualClock::new(revert.due - 60));
+    let scheduler = Scheduler::new(vec![device])
+        .countdowns_path(&path)
+        .clock(clock.clone());
+    assert!(scheduler.revert().unwrap().is_empty());
+    assert_eq!(state.lock().unwrap().switch, "on");
+
+    clock.set(revert.due);
+    let results = scheduler.revert().unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].1.is_ok());
+    assert_eq!(state.lock().unwrap().switch, "off");
+    assert!(Countdowns::load(&path).unwrap().reverts().is_empty());
+    std::fs::remove_file(&path).unwrap();
+}